@@ -1,47 +1,476 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::num::NonZeroU64;
+
 use iced::widget::Column;
-use crate::{frontend::{message::Message, widget::packet_widget::PacketWidget}, networking::packet::Packet};
+use iced::Element;
+use iroh::EndpointId;
+use crate::{backend::history::Record, error::{Res, StorageError}, frontend::{message::Message, widget::packet_widget::{Annotations, PacketWidget}}, networking::{group::{GroupId, Membership}, packet::{MessageId, Packet, PacketType, Stamp}}, util::clock};
 
 #[derive(Copy, Debug, Clone, PartialEq, Eq)]
 pub enum PacketState {
     Unknown,
     Failed,
-    Verified
+    Verified,
+    Discarded,
+    Read            // Verified, and since viewed by the recipient.
+}
+
+impl PacketState {
+    pub fn from_byte(byte: u8) -> Res<PacketState> {
+        Ok(match byte {
+            0 => PacketState::Unknown,
+            1 => PacketState::Failed,
+            2 => PacketState::Verified,
+            3 => PacketState::Discarded,
+            4 => PacketState::Read,
+            _ => return Err(StorageError::CorruptHistory.into())
+        })
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            PacketState::Unknown => 0,
+            PacketState::Failed => 1,
+            PacketState::Verified => 2,
+            PacketState::Discarded => 3,
+            PacketState::Read => 4
+        }
+    }
+}
+
+// The number of characters of a message quoted by a reply.
+const PREVIEW_LENGTH: usize = 80;
+
+// How far ahead of the conversation's clock a packet may be stamped. A sender only moves its clock past ours by the packets
+// we have not yet seen, so a stamp further ahead is a peer trying to pin the clock, and is treated as the next tick instead.
+const MAXIMUM_CLOCK_JUMP: u64 = 1 << 20;
+
+/// Who a reaction is from: whether it is our own, and which member sent it in a group.
+type Reactor = (bool, Option<EndpointId>);
+
+/// How a packet has been amended by its author since it was sent.
+#[derive(Copy, Debug, Clone, PartialEq, Eq)]
+pub enum Revision<'a> {
+    Original,
+    Edited(&'a str),
+    Deleted
+}
+
+/// A conversation, either with a single peer or with the members of a group.
+#[derive(Copy, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ChatId {
+    Direct(EndpointId),
+    Group(GroupId)
+}
+
+impl ChatId {
+
+    /// The conversation a packet exchanged with a peer belongs to.
+    pub fn of(peer: EndpointId, packet: &Packet) -> ChatId {
+        match packet.group {
+            Some(group) => ChatId::Group(group),
+            None => ChatId::Direct(peer)
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Chat {
     foreign_username: Option<String>,
-    packets: Vec<(bool, Packet, PacketState)>
+    packets: Vec<(bool, Packet, PacketState)>,      // In the order they were recorded, which indices refer to.
+    order: BTreeSet<(Stamp, MessageId, usize)>,     // The index of every packet, in causal order.
+    ids: HashMap<MessageId, usize>,                 // The index of the first packet recorded with each message id.
+    pending: HashMap<MessageId, Vec<usize>>,        // Amendments that arrived before the packet they refer to, by its id.
+    clock: u64,                                     // The highest logical clock of any packet in the conversation.
+
+    // Group chats only
+    membership: Option<Membership>,
+    left: bool,
+    authors: HashMap<usize, EndpointId>,                            // The member who sent each foreign packet.
+    deliveries: HashMap<usize, HashMap<EndpointId, PacketState>>,   // The state of each local packet for every member it was sent to.
+
+    // The latest edit of each edited message with its text, and every deleted packet. Each Edit and Delete packet is kept in the history.
+    edits: HashMap<usize, (usize, String)>,
+    deleted: HashSet<usize>,
+
+    // Who has reacted to each packet with each emoji, and the causally latest reaction of each reactor with each emoji to each packet.
+    reactions: HashMap<usize, BTreeMap<String, HashSet<Reactor>>>,
+    latest_reactions: HashMap<(usize, String, Reactor), (Option<Stamp>, MessageId)>
 }
 
 impl Chat {
     pub fn new() -> Chat {
         Chat {
             foreign_username: None,
-            packets: Vec::default()
+            packets: Vec::default(),
+            order: BTreeSet::new(),
+            ids: HashMap::new(),
+            pending: HashMap::new(),
+            clock: 0,
+            membership: None,
+            left: false,
+            authors: HashMap::new(),
+            deliveries: HashMap::new(),
+            edits: HashMap::new(),
+            deleted: HashSet::new(),
+            reactions: HashMap::new(),
+            latest_reactions: HashMap::new()
         }
     }
 
-    pub fn view(&self, local: String) -> Column<'_, Message> {
-        let mut previous: Option<bool> = None;
+    /// Render the conversation. Foreign packets in a group are attributed using the given names of its members.
+    /// The emoji palette is shown beneath the packet being reacted to, if any.
+    /// Each new day is headed by a separator. Packets from verified peers are marked as such.
+    pub fn view<'a>(&'a self, local: String, chat: ChatId, names: &HashMap<EndpointId, String>, verified: &HashSet<EndpointId>, transfers: &HashMap<MessageId, u64>, reacting: Option<usize>) -> Column<'a, Message> {
+        let mut previous: Option<(bool, Option<EndpointId>)> = None;
+        let mut day = None;
+
         Column::from_iter(
-            self.packets.iter().map(|(is_local, packet, state)| {
-                let headerless = if let Some(previous) = previous {
-                    previous == *is_local
-                } else { false };
-                previous = Some(*is_local);
-                let username = if *is_local { &local } else { &self.foreign_username.clone().unwrap_or(String::from("FOREIGN")) };
-                PacketWidget::parse(username.clone(), packet, *state, headerless).into()
+            self.visible().flat_map(|(index, entry)| {
+                let (is_local, packet, _) = entry;
+                let sent = packet.stamp.and_then(|stamp| stamp.sent).map(NonZeroU64::get);
+
+                let separator = match sent.map(clock::day) {
+                    Some(sent_day) if day != Some(sent_day) => {
+                        day = Some(sent_day);
+                        previous = None;
+                        Some(PacketWidget::separator(clock::day_label(sent_day)).into())
+                    }
+                    _ => None
+                };
+
+                let author = if *is_local { None } else { self.authors.get(&index).copied() };
+                let headerless = previous == Some((*is_local, author));
+                previous = Some((*is_local, author));
+
+                let verified = match (is_local, chat) {
+                    (true, _) => false,
+                    (false, ChatId::Direct(peer)) => verified.contains(&peer),
+                    (false, ChatId::Group(_)) => author.is_some_and(|author| verified.contains(&author))
+                };
+
+                let deliveries = self.deliveries.get(&index).map(|deliveries| deliveries.iter()
+                    .map(|(member, state)| (Self::name(member, names), *state))
+                    .collect());
+
+                let reply = packet.in_reply_to.and_then(|parent| self.find(parent))
+                    .map(|parent| (self.author_name(parent, &local, names), self.preview(parent), parent));
+
+                let widget: Element<'a, Message> = PacketWidget::parse(self.author_name(index, &local, names), chat, index, entry, Annotations {
+                    revision: self.revision(index),
+                    time: sent.map(clock::time_of_day),
+                    progress: transfers.get(&packet.id).copied(),
+                    deliveries,
+                    reply,
+                    reactions: self.reactions(index),
+                    palette: reacting == Some(index),
+                    headerless,
+                    verified
+                }).into();
+
+                separator.into_iter().chain([widget])
             })
         ).padding(10).spacing(10)
     }
 
+    /// Every packet shown in the conversation in causal order, with its index.
+    /// Edits, deletions and reactions are shown on the packets they refer to rather than on their own.
+    fn visible(&self) -> impl Iterator<Item = (usize, &(bool, Packet, PacketState))> {
+        self.order.iter().map(|(_, _, index)| (*index, &self.packets[*index])).filter(|(_, (_, packet, _))| packet.target().is_none())
+    }
+
+    /// The logical clock to stamp the next local packet with.
+    pub fn next_clock(&self) -> NonZeroU64 {
+        NonZeroU64::MIN.saturating_add(self.clock)
+    }
+
+    /// Place a newly recorded packet in causal order: by logical clock, then by the time it was sent, then by id.
+    /// A packet recorded before stamps existed is given the next tick of the clock, so keeps its place in the order it arrived,
+    /// as is one stamped implausibly far ahead.
+    /// Amendments that arrived before the packet they refer to are applied once it arrives.
+    /// Only the causally latest amendment of each kind counts, so the order they are applied in makes no difference.
+    fn place(&mut self, index: usize) {
+        let next = self.next_clock();
+        let stamp = self.packets[index].1.stamp.get_or_insert(Stamp { clock: next, sent: None });
+        if stamp.clock.get().saturating_sub(self.clock) > MAXIMUM_CLOCK_JUMP {
+            stamp.clock = next;
+        }
+
+        let stamp = *stamp;
+        self.clock = self.clock.max(stamp.clock.get());
+
+        let id = self.packets[index].1.id;
+        self.order.insert((stamp, id, index));
+        self.ids.entry(id).or_insert(index);
+
+        match self.packets[index].1.target() {
+            Some(target) if self.find(target).is_none() => self.pending.entry(target).or_default().push(index),
+            Some(_) => self.amend(index),
+            None => self.pending.remove(&id).unwrap_or_default().into_iter().for_each(|amendment| self.amend(amendment))
+        }
+    }
+
+    fn name(peer: &EndpointId, names: &HashMap<EndpointId, String>) -> String {
+        names.get(peer).cloned().unwrap_or_else(|| peer.fmt_short().to_string())
+    }
+
+    /// The name to attribute the packet at the given index to.
+    pub fn author_name(&self, index: usize, local: &str, names: &HashMap<EndpointId, String>) -> String {
+        match (self.packets.get(index).map(|(is_local, _, _)| *is_local), self.authors.get(&index)) {
+            (Some(true), _) => local.to_string(),
+            (_, Some(author)) => Self::name(author, names),
+            _ => self.foreign_username.clone().unwrap_or(String::from("FOREIGN"))
+        }
+    }
+
+    /// A short description of a packet, quoted by the replies to it.
+    pub fn preview(&self, index: usize) -> String {
+        match (self.packet(index), self.revision(index)) {
+            (_, Revision::Deleted) => String::from("Deleted message"),
+            (Some(packet), _) if packet.kind == PacketType::Image => String::from("Image"),
+            (Some(packet), _) if packet.kind == PacketType::File => packet.file_header().map(|header| header.name).unwrap_or_default(),
+            _ => {
+                let text = self.text(index).unwrap_or_default();
+                match text.chars().count() > PREVIEW_LENGTH {
+                    true => format!("{}…", text.chars().take(PREVIEW_LENGTH).collect::<String>()),
+                    false => text
+                }
+            }
+        }
+    }
+
+    /// The position of a packet within the conversation, as a scroll offset from the bottom.
+    /// Packets are assumed to all be the same height, so this is only approximate.
+    pub fn offset_of(&self, index: usize) -> Option<f32> {
+        let positions: Vec<usize> = self.visible().map(|(index, _)| index).collect();
+        let position = positions.iter().position(|visible| *visible == index)?;
+        Some(match positions.len() {
+            1 => 0f32,
+            length => 1f32 - position as f32 / (length - 1) as f32
+        })
+    }
+
     pub fn set_foreign_username(&mut self, username: String) {
         self.foreign_username = Some(username);
     }
 
-    pub fn add_packet(&mut self, local: bool, packet: Packet) {
-        self.packets.push((local, packet, if local { PacketState::Unknown } else { PacketState::Verified }));
+    pub fn foreign_username(&self) -> Option<&String> {
+        self.foreign_username.as_ref()
+    }
+
+    pub fn membership(&self) -> Option<&Membership> {
+        self.membership.as_ref()
+    }
+
+    /// Whether we have left this group, after which it is kept only as history.
+    pub fn has_left(&self) -> bool {
+        self.left
+    }
+
+    /// Apply a history record, as replayed from disk.
+    pub fn apply(&mut self, record: Record) {
+        match record {
+            Record::Packet { local, packet, state } => {
+
+                // A local packet in a group is sent to every member at the time.
+                if let (true, Some(membership)) = (local, self.membership.as_ref()) {
+                    self.deliveries.insert(self.packets.len(), membership.members.iter().map(|member| (*member, state)).collect());
+                }
+
+                self.packets.push((local, packet, state));
+                self.place(self.packets.len() - 1);
+            },
+            Record::State { index, state } => self.update_state(index, state),
+            Record::Username(username) => self.set_foreign_username(username),
+            Record::Member { author, packet, state } => {
+                self.authors.insert(self.packets.len(), author);
+                self.packets.push((false, packet, state));
+                self.place(self.packets.len() - 1);
+            },
+            Record::Delivery { index, member, state } => {
+                if let Some(deliveries) = self.deliveries.get_mut(&index) {
+                    deliveries.insert(member, state);
+                    let aggregate = Self::aggregate(deliveries);
+                    self.update_state(index, aggregate);
+                }
+            },
+            Record::Membership(membership) => self.membership = Some(membership),
+            Record::Left => self.left = true
+        }
+    }
+
+    /// Apply the Edit, Delete or React packet at the given index to the packet it refers to.
+    /// Anyone may react, but only the original author may edit or delete a packet. Anything else is kept in the history but otherwise ignored.
+    fn amend(&mut self, index: usize) {
+        let (local, packet, _) = &self.packets[index];
+        let target = match packet.target().and_then(|id| self.find(id)) {
+            Some(target) => target,
+            None => return
+        };
+
+        if self.deleted.contains(&target) { return; }
+        let reactor = (*local, self.authors.get(&index).copied());
+        let order = (packet.stamp, packet.id);
+
+        // Reactions toggle, so a reaction older than the latest from the same reactor with the same emoji is ignored.
+        if let Some((emoji, add)) = packet.reaction() {
+            let key = (target, emoji.clone(), reactor);
+            if self.latest_reactions.get(&key).is_some_and(|latest| *latest > order) { return; }
+            self.latest_reactions.insert(key, order);
+
+            let emojis = self.reactions.entry(target).or_default();
+            match add {
+                true => { emojis.entry(emoji).or_default().insert(reactor); },
+                false => if let Some(reactors) = emojis.get_mut(&emoji) {
+                    reactors.remove(&reactor);
+                    if reactors.is_empty() { emojis.remove(&emoji); }
+                }
+            }
+            return;
+        }
+
+        let (target_local, target_packet, _) = &self.packets[target];
+        if reactor != (*target_local, self.authors.get(&target).copied()) { return; }
+
+        match (packet.kind, target_packet.kind) {
+            // Edits may arrive out of order, so only the causally latest is kept.
+            (PacketType::Edit, PacketType::Message) if self.edits.get(&target).is_none_or(|(edit, _)| (self.packets[*edit].1.stamp, self.packets[*edit].1.id) <= order) => {
+                self.edits.insert(target, (index, packet.edited_text().unwrap_or_default()));
+            },
+            (PacketType::Delete, PacketType::Message | PacketType::Image | PacketType::File) => { self.deleted.insert(target); },
+            _ => ()
+        }
+    }
+
+    /// Each emoji reacted to a packet with, how many have reacted with it, and whether we are one of them.
+    pub fn reactions(&self, index: usize) -> Vec<(String, usize, bool)> {
+        self.reactions.get(&index).map(|emojis| emojis.iter()
+            .map(|(emoji, reactors)| (emoji.clone(), reactors.len(), reactors.iter().any(|(local, _)| *local)))
+            .collect()
+        ).unwrap_or_default()
+    }
+
+    /// Whether we have reacted to a packet with the given emoji.
+    pub fn reacted(&self, index: usize, emoji: &str) -> bool {
+        self.reactions.get(&index).and_then(|emojis| emojis.get(emoji)).is_some_and(|reactors| reactors.iter().any(|(local, _)| *local))
+    }
+
+    /// How the packet at the given index has been amended by its author.
+    pub fn revision(&self, index: usize) -> Revision<'_> {
+        match (self.deleted.contains(&index), self.edits.get(&index)) {
+            (true, _) => Revision::Deleted,
+            (false, Some((_, text))) => Revision::Edited(text),
+            (false, None) => Revision::Original
+        }
+    }
+
+    /// The current text of a message, taking any edit into account.
+    pub fn text(&self, index: usize) -> Option<String> {
+        match (self.packet(index)?, self.revision(index)) {
+            (_, Revision::Edited(text)) => Some(text.to_string()),
+            (packet, Revision::Original) if packet.kind == PacketType::Message => Some(String::from_utf8_lossy(&packet.data).to_string()),
+            _ => None
+        }
+    }
+
+    /// The overall state of a packet sent to a group: failed if any member failed, verified once every member has it,
+    /// and read once every member has read it.
+    fn aggregate(deliveries: &HashMap<EndpointId, PacketState>) -> PacketState {
+        let states: Vec<PacketState> = deliveries.values().copied().collect();
+        if states.contains(&PacketState::Failed) { PacketState::Failed }
+        else if states.contains(&PacketState::Unknown) { PacketState::Unknown }
+        else if states.contains(&PacketState::Verified) { PacketState::Verified }
+        else if states.contains(&PacketState::Read) { PacketState::Read }
+        else { PacketState::Discarded }
+    }
+
+    /// The state of a packet for one of its recipients. In a direct chat, this is the state of the packet itself.
+    pub fn delivery(&self, index: usize, member: EndpointId) -> Option<PacketState> {
+        match self.deliveries.get(&index) {
+            Some(deliveries) => deliveries.get(&member).copied(),
+            None => self.state(index)
+        }
+    }
+
+    /// Every foreign packet that has been received but not yet viewed, with its index.
+    pub fn unread(&self) -> Vec<(usize, MessageId)> {
+        self.visible()
+            .filter(|(_, (local, _, state))| !*local && *state == PacketState::Verified)
+            .map(|(index, (_, packet, _))| (index, packet.id))
+            .collect()
+    }
+
+    /// The members of a group a local packet failed to reach.
+    pub fn failed_members(&self, index: usize) -> Vec<EndpointId> {
+        self.deliveries.get(&index).map(|deliveries| deliveries.iter()
+            .filter(|(_, state)| **state == PacketState::Failed)
+            .map(|(member, _)| *member)
+            .collect()
+        ).unwrap_or_default()
+    }
+
+    /// Every local packet in a group that failed to reach a member, paired with that member, in the order they were sent.
+    pub fn failed_deliveries(&self) -> Vec<(EndpointId, usize, Packet)> {
+        self.packets.iter().enumerate()
+            .flat_map(|(index, (_, packet, _))| self.failed_members(index).into_iter().map(move |member| (member, index, packet.clone())))
+            .collect()
+    }
+
+    pub fn packet(&self, index: usize) -> Option<&Packet> {
+        self.packets.get(index).map(|(_, packet, _)| packet)
+    }
+
+    pub fn is_local(&self, index: usize) -> bool {
+        self.packets.get(index).is_some_and(|(local, _, _)| *local)
+    }
+
+    pub fn state(&self, index: usize) -> Option<PacketState> {
+        self.packets.get(index).map(|(_, _, state)| *state)
+    }
+
+    /// The message ids of every incoming file from the given peer that was not received in full.
+    pub fn interrupted_incoming(&self, peer: EndpointId) -> Vec<MessageId> {
+        self.packets.iter().enumerate()
+            .filter(|(_, (local, packet, state))| !*local && packet.kind == PacketType::File && *state == PacketState::Failed)
+            .filter(|(index, _)| self.authors.get(index).is_none_or(|author| *author == peer))
+            .map(|(_, (_, packet, _))| packet.id)
+            .collect()
+    }
+
+    /// The index of the packet with the given message id.
+    pub fn find(&self, id: MessageId) -> Option<usize> {
+        self.ids.get(&id).copied()
+    }
+
+    /// The index of the file with the given message id, if it is one we sent, or one the given peer sent, as asked.
+    /// Message ids of files are chosen by whoever sent them, so a peer naming any other packet is ignored.
+    pub fn find_file(&self, id: MessageId, local: bool, peer: EndpointId) -> Option<usize> {
+        self.find(id)
+            .filter(|index| self.packets[*index].0 == local && self.packets[*index].1.kind == PacketType::File)
+            .filter(|index| local || self.authors.get(index).is_none_or(|author| *author == peer))
+    }
+
+    /// Every local packet that failed to send and has not been discarded, in the order they were sent.
+    pub fn failed_local(&self) -> Vec<(usize, Packet)> {
+        self.packets.iter().enumerate()
+            .filter(|(_, (local, _, state))| *local && *state == PacketState::Failed)
+            .map(|(index, (_, packet, _))| (index, packet.clone()))
+            .collect()
+    }
+
+    /// Mark every packet still awaiting confirmation as failed, along with any incoming file that was never completed.
+    pub fn fail_unconfirmed(&mut self) {
+        for (_, _, state) in &mut self.packets {
+            if *state == PacketState::Unknown {
+                *state = PacketState::Failed;
+            }
+        }
+
+        for state in self.deliveries.values_mut().flat_map(|deliveries| deliveries.values_mut()) {
+            if *state == PacketState::Unknown {
+                *state = PacketState::Failed;
+            }
+        }
     }
 
     pub fn get_unique_id(&self) -> usize {
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use iroh::EndpointId;
+
+use crate::error::{Res, StorageError};
+use crate::networking::identity::SafetyNumber;
+use crate::util::storage::{data_dir, read_sealed, write_sealed};
+
+const CONTACTS_FILE: &str = "contacts";
+
+/// A peer we have chosen to remember. The petname is ours to choose, unlike the username, which the peer asserts about itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Contact {
+    pub petname: String,
+    pub notes: String,
+    pub username: Option<String>,           // The last username the peer reported.
+    pub verified: Option<SafetyNumber>      // The safety number we confirmed with the peer, if we have.
+}
+
+impl Contact {
+
+    /// Whether we confirmed the given safety number with the peer. Should either key change, so does the number,
+    /// and the contact is no longer verified.
+    pub fn is_verified(&self, number: &SafetyNumber) -> bool {
+        self.verified.as_ref() == Some(number)
+    }
+}
+
+/// Every contact, keyed by the EndpointId that identifies them. The whole book is rewritten on every change.
+#[derive(Debug, Clone, Default)]
+pub struct ContactBook {
+    contacts: HashMap<EndpointId, Contact>
+}
+
+impl ContactBook {
+
+    /// Load the stored contacts, or an empty book if none have been saved.
+    pub fn load() -> Res<ContactBook> {
+        let path = data_dir()?.join(CONTACTS_FILE);
+        if !path.exists() { return Ok(ContactBook::default()); }
+        Self::from_bytes(&read_sealed(&path)?)
+    }
+
+    pub fn save(&self) -> Res<()> {
+        write_sealed(&data_dir()?.join(CONTACTS_FILE), self.to_bytes())
+    }
+
+    pub fn get(&self, peer: &EndpointId) -> Option<&Contact> {
+        self.contacts.get(peer)
+    }
+
+    pub fn petname(&self, peer: &EndpointId) -> Option<&String> {
+        self.contacts.get(peer).map(|contact| &contact.petname)
+    }
+
+    pub fn all(&self) -> HashMap<EndpointId, Contact> {
+        self.contacts.clone()
+    }
+
+    /// Every contact, ordered by petname.
+    pub fn sorted(&self) -> Vec<(&EndpointId, &Contact)> {
+        let mut contacts: Vec<(&EndpointId, &Contact)> = self.contacts.iter().collect();
+        contacts.sort_by_key(|(_, contact)| contact.petname.to_lowercase());
+        contacts
+    }
+
+    /// Add a contact, or change the petname and notes of an existing one while keeping its last known username.
+    pub fn set(&mut self, peer: EndpointId, petname: String, notes: String) -> Res<()> {
+        let contact = self.contacts.entry(peer).or_default();
+        contact.petname = petname;
+        contact.notes = notes;
+        self.save()
+    }
+
+    /// Move a contact to a new EndpointId, as when the peer replaced its identity.
+    /// What we knew of the old key, its username and verification, does not carry over to the new one.
+    pub fn rekey(&mut self, old: &EndpointId, new: EndpointId) -> Res<()> {
+        let contact = match self.contacts.remove(old) {
+            Some(contact) => contact,
+            None => return Ok(())
+        };
+
+        self.contacts.insert(new, Contact { username: None, verified: None, ..contact });
+        self.save()
+    }
+
+    /// Record the safety number we confirmed with a contact, or clear it.
+    pub fn verify(&mut self, peer: &EndpointId, number: Option<SafetyNumber>) -> Res<()> {
+        match self.contacts.get_mut(peer) {
+            Some(contact) => {
+                contact.verified = number;
+                self.save()
+            }
+            None => Ok(())
+        }
+    }
+
+    pub fn remove(&mut self, peer: &EndpointId) -> Res<()> {
+        if self.contacts.remove(peer).is_none() { return Ok(()); }
+        self.save()
+    }
+
+    /// Remember the username a contact reported. Peers that are not contacts are ignored.
+    pub fn observe(&mut self, peer: &EndpointId, username: String) -> Res<()> {
+        match self.contacts.get_mut(peer) {
+            Some(contact) if contact.username.as_ref() != Some(&username) => {
+                contact.username = Some(username);
+                self.save()
+            }
+            _ => Ok(())
+        }
+    }
+
+    /*
+        Encoding
+        Each contact is [peer: 32 bytes][petname length: u32][petname][notes length: u32][notes][username length: u32][username][verified: u8][safety number: 32 bytes, if verified].
+        An empty username means the contact has never reported one.
+    */
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.contacts.iter().flat_map(|(peer, contact)| {
+            vec![
+                peer.as_bytes().to_vec(),
+                encode_string(&contact.petname),
+                encode_string(&contact.notes),
+                encode_string(contact.username.as_deref().unwrap_or_default()),
+                match contact.verified {
+                    Some(number) => [vec![1], number.as_bytes().to_vec()].concat(),
+                    None => vec![0]
+                }
+            ].into_iter().flatten()
+        }).collect()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Res<ContactBook> {
+        let mut contacts = HashMap::new();
+        let mut remaining = bytes;
+
+        while !remaining.is_empty() {
+            let (peer, rest) = remaining.split_first_chunk::<32>().ok_or(StorageError::CorruptContacts)?;
+            let (petname, rest) = decode_string(rest)?;
+            let (notes, rest) = decode_string(rest)?;
+            let (username, rest) = decode_string(rest)?;
+            let (verified, rest) = match rest.split_first() {
+                Some((0, rest)) => (None, rest),
+                Some((1, rest)) => match rest.split_first_chunk::<32>() {
+                    Some((number, rest)) => (Some(SafetyNumber::from_bytes(*number)), rest),
+                    None => return Err(StorageError::CorruptContacts.into())
+                },
+                _ => return Err(StorageError::CorruptContacts.into())
+            };
+
+            contacts.insert(
+                EndpointId::from_bytes(peer).map_err(|_| StorageError::CorruptContacts)?,
+                Contact { petname, notes, username: Some(username).filter(|username| !username.is_empty()), verified }
+            );
+            remaining = rest;
+        }
+
+        Ok(ContactBook { contacts })
+    }
+}
+
+fn encode_string(string: &str) -> Vec<u8> {
+    [(string.len() as u32).to_be_bytes().to_vec(), string.as_bytes().to_vec()].concat()
+}
+
+fn decode_string(bytes: &[u8]) -> Res<(String, &[u8])> {
+    let (length, rest) = bytes.split_first_chunk::<4>().ok_or(StorageError::CorruptContacts)?;
+    let length = u32::from_be_bytes(*length) as usize;
+    if rest.len() < length { return Err(StorageError::CorruptContacts.into()); }
+
+    let (string, rest) = rest.split_at(length);
+    Ok((String::from_utf8_lossy(string).to_string(), rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::SecretKey;
+
+    use super::*;
+
+    fn book() -> ContactBook {
+        let contacts = [
+            (1, Contact { petname: String::from("Alice"), notes: String::from("From work"), username: Some(String::from("alice")), verified: Some(SafetyNumber::from_bytes([9; 32])) }),
+            (2, Contact { petname: String::from("Bob"), ..Contact::default() })
+        ];
+        ContactBook { contacts: contacts.into_iter().map(|(seed, contact)| (SecretKey::from_bytes(&[seed; 32]).public(), contact)).collect() }
+    }
+
+    #[test]
+    fn round_trip() {
+        let book = book();
+        assert_eq!(ContactBook::from_bytes(&book.to_bytes()).unwrap().contacts, book.contacts);
+        assert!(ContactBook::from_bytes(&[]).unwrap().contacts.is_empty());
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = ContactBook { contacts: book().contacts.into_iter().filter(|(_, contact)| contact.verified.is_some()).collect() }.to_bytes();
+        for length in 1..bytes.len() {
+            assert!(ContactBook::from_bytes(&bytes[..length]).is_err(), "accepted {length} of {} bytes", bytes.len());
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        let mut bytes = book().to_bytes();
+        bytes.extend([0; 32]);
+        assert!(ContactBook::from_bytes(&bytes).is_err());
+
+        let bytes = [[1; 32].as_slice(), &[0xff; 4]].concat();
+        assert!(ContactBook::from_bytes(&bytes).is_err());
+    }
+}
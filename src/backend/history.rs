@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+use std::fs::{OpenOptions, read, read_dir};
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use iroh::EndpointId;
+
+use crate::backend::chat::{Chat, ChatId, PacketState};
+use crate::error::{Error, Res, StorageError};
+use crate::networking::group::Membership;
+use crate::networking::packet::Packet;
+use crate::util::storage::{create_private_dir, data_dir};
+use crate::util::vault;
+
+const HISTORY_DIRECTORY: &str = "history";
+const EXTENSION: &str = "log";
+const GROUP_EXTENSION: &str = "group";
+
+/// The extensions of logs, which are sealed record by record rather than as a whole.
+pub const FRAMED_EXTENSIONS: [&str; 2] = [EXTENSION, GROUP_EXTENSION];
+
+/// The chats whose logs could only be read in part, each with why.
+pub type Damaged = Vec<(ChatId, Error)>;
+
+/// A single entry in a conversation's append-only log.
+/// Replaying every record of a peer in order reconstructs its Chat.
+#[derive(Debug, Clone)]
+pub enum Record {
+    Packet { local: bool, packet: Packet, state: PacketState },
+    State { index: usize, state: PacketState },
+    Username(String),
+
+    // Group chats only
+    Member { author: EndpointId, packet: Packet, state: PacketState },     // A packet received from a member.
+    Delivery { index: usize, member: EndpointId, state: PacketState },     // The state of a local packet for one member.
+    Membership(Membership),
+    Left
+}
+
+impl Record {
+    /*
+        Encoding
+        The first byte identifies the record type.
+        Packet:   [local: u8][state: u8][stored packet bytes]
+        State:    [index: u64][state: u8]
+        Username: [utf8 bytes]
+        Member:   [author: 32 bytes][state: u8][stored packet bytes]
+        Delivery: [index: u64][member: 32 bytes][state: u8]
+        Membership, as encoded by Membership::to_bytes
+        Left:     []
+    */
+
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Record::Packet { local, packet, state } => vec![
+                vec![3, *local as u8, state.to_byte()],
+                packet.clone().to_stored_bytes()
+            ].into_iter().flatten().collect(),
+            Record::State { index, state } => vec![
+                vec![1],
+                (*index as u64).to_be_bytes().to_vec(),
+                vec![state.to_byte()]
+            ].into_iter().flatten().collect(),
+            Record::Username(username) => vec![
+                vec![2],
+                username.as_bytes().to_vec()
+            ].into_iter().flatten().collect(),
+            Record::Member { author, packet, state } => vec![
+                vec![4],
+                author.as_bytes().to_vec(),
+                vec![state.to_byte()],
+                packet.clone().to_stored_bytes()
+            ].into_iter().flatten().collect(),
+            Record::Delivery { index, member, state } => vec![
+                vec![5],
+                (*index as u64).to_be_bytes().to_vec(),
+                member.as_bytes().to_vec(),
+                vec![state.to_byte()]
+            ].into_iter().flatten().collect(),
+            Record::Membership(membership) => vec![
+                vec![6],
+                membership.to_bytes()
+            ].into_iter().flatten().collect(),
+            Record::Left => vec![7]
+        }
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Res<Record> {
+        let (&kind, rest) = bytes.split_first().ok_or(StorageError::CorruptHistory)?;
+        Ok(match kind {
+            3 => match rest {
+                [local, state, packet @ ..] => Record::Packet {
+                    local: *local != 0,
+                    packet: Packet::from_stored_bytes(packet.to_vec())?,
+                    state: PacketState::from_byte(*state)?
+                },
+                _ => return Err(StorageError::CorruptHistory.into())
+            },
+            1 => match rest {
+                [index @ .., state] => Record::State {
+                    index: u64::from_be_bytes(index.try_into().map_err(|_| StorageError::CorruptHistory)?) as usize,
+                    state: PacketState::from_byte(*state)?
+                },
+                _ => return Err(StorageError::CorruptHistory.into())
+            },
+            2 => Record::Username(String::from_utf8_lossy(rest).to_string()),
+            4 => match rest.split_first_chunk::<32>() {
+                Some((author, [state, packet @ ..])) => Record::Member {
+                    author: EndpointId::from_bytes(author).map_err(|_| StorageError::CorruptHistory)?,
+                    packet: Packet::from_stored_bytes(packet.to_vec())?,
+                    state: PacketState::from_byte(*state)?
+                },
+                _ => return Err(StorageError::CorruptHistory.into())
+            },
+            5 => match rest.split_first_chunk::<8>().and_then(|(index, rest)| rest.split_first_chunk::<32>().map(|(member, rest)| (index, member, rest))) {
+                Some((index, member, [state])) => Record::Delivery {
+                    index: u64::from_be_bytes(*index) as usize,
+                    member: EndpointId::from_bytes(member).map_err(|_| StorageError::CorruptHistory)?,
+                    state: PacketState::from_byte(*state)?
+                },
+                _ => return Err(StorageError::CorruptHistory.into())
+            },
+            6 => Record::Membership(Membership::from_bytes(rest).map_err(|_| StorageError::CorruptHistory)?),
+            7 => Record::Left,
+            _ => return Err(StorageError::CorruptHistory.into())
+        })
+    }
+}
+
+/// Persistent chat history, stored as one append-only log per peer, and one per group.
+/// Each record is framed by a 4 byte big-endian length, and sealed under the passphrase if one is set.
+#[derive(Debug, Clone)]
+pub struct History {
+    directory: PathBuf
+}
+
+impl History {
+
+    pub fn open() -> Res<History> {
+        let directory = data_dir()?.join(HISTORY_DIRECTORY);
+        create_private_dir(&directory)?;
+        Ok(History { directory })
+    }
+
+    /// Append a record to the end of a chat's log.
+    pub fn append(&self, chat: ChatId, record: &Record) -> Res<()> {
+        let key = vault::key();
+        let frame = frame(&key.seal(record.to_bytes())?);
+
+        let mut options = OpenOptions::new();
+        options.append(true).create(true);
+
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(self.path(chat))?;
+        Ok(file.write_all(&frame)?)
+    }
+
+    /// Replay every stored log into a Chat, along with every chat whose log could only be read in part, and why.
+    /// Each log is replayed up to its first unreadable record, so that one damaged log does not hide the others.
+    /// An incomplete record at the end of a log, left by an interrupted append, is cut off so that records appended later can be read.
+    pub fn load(&self) -> Res<(HashMap<ChatId, Chat>, Damaged)> {
+        let mut chats = HashMap::new();
+        let mut damaged = Vec::new();
+
+        for entry in read_dir(&self.directory)? {
+            let path = entry?.path();
+            let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+
+            let id = match path.extension().and_then(|extension| extension.to_str()) {
+                Some(EXTENSION) => match EndpointId::from_str(stem) {
+                    Ok(peer) => ChatId::Direct(peer),
+                    Err(_) => continue
+                },
+                Some(GROUP_EXTENSION) => match u128::from_str_radix(stem, 16) {
+                    Ok(group) => ChatId::Group(group),
+                    Err(_) => continue
+                },
+                _ => continue
+            };
+
+            let bytes = match read(&path) {
+                Ok(bytes) => bytes,
+                Err(error) => {
+                    damaged.push((id, error.into()));
+                    continue;
+                }
+            };
+
+            let (records, mut error) = Self::read_records(&bytes);
+            let intact: usize = frames(&bytes).iter().map(|record| size_of::<u32>() + record.len()).sum();
+            if intact < bytes.len() {
+                let truncated = OpenOptions::new().write(true).open(&path).and_then(|file| file.set_len(intact as u64));
+                error = error.or(Some(truncated.map_or_else(Error::from, |()| StorageError::CorruptHistory.into())));
+            }
+            if let Some(error) = error {
+                damaged.push((id, error));
+            }
+
+            let mut chat = Chat::new();
+            for record in records {
+                chat.apply(record);
+            }
+
+            // Anything still awaiting confirmation when the app closed will never be confirmed.
+            chat.fail_unconfirmed();
+            chats.insert(id, chat);
+        }
+
+        Ok((chats, damaged))
+    }
+
+    /// Every record in a log up to the first that cannot be read, along with why it could not be.
+    fn read_records(bytes: &[u8]) -> (Vec<Record>, Option<Error>) {
+        let key = vault::key();
+        let mut records = Vec::new();
+
+        for record in frames(bytes) {
+            match key.unseal(record.to_vec()).and_then(Record::from_bytes) {
+                Ok(record) => records.push(record),
+                Err(error) => return (records, Some(error))
+            }
+        }
+
+        (records, None)
+    }
+
+    fn path(&self, chat: ChatId) -> PathBuf {
+        match chat {
+            ChatId::Direct(peer) => self.directory.join(format!("{peer}.{EXTENSION}")),
+            ChatId::Group(group) => self.directory.join(format!("{group:032x}.{GROUP_EXTENSION}"))
+        }
+    }
+}
+
+/// Frame a record with its length, ready to be appended to a log.
+pub fn frame(record: &[u8]) -> Vec<u8> {
+    [(record.len() as u32).to_be_bytes().as_slice(), record].concat()
+}
+
+/// Every record framed within a log, in order.
+pub fn frames(bytes: &[u8]) -> Vec<&[u8]> {
+    let mut records = Vec::new();
+    let mut remaining = bytes;
+
+    while let Some((length, rest)) = remaining.split_first_chunk::<4>() {
+        let length = u32::from_be_bytes(*length) as usize;
+
+        // A truncated trailing record is the result of an interrupted write, everything before it is intact.
+        if rest.len() < length { break; }
+
+        let (record, rest) = rest.split_at(length);
+        records.push(record);
+        remaining = rest;
+    }
+
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::SecretKey;
+
+    use super::*;
+
+    fn member() -> EndpointId {
+        SecretKey::from_bytes(&[3; 32]).public()
+    }
+
+    fn records() -> Vec<Record> {
+        let packet = Packet::message(String::from("hello")).replying_to(9);
+        vec![
+            Record::Packet { local: true, packet: packet.clone(), state: PacketState::Verified },
+            Record::State { index: 12, state: PacketState::Read },
+            Record::Username(String::from("alice")),
+            Record::Member { author: member(), packet, state: PacketState::Unknown },
+            Record::Delivery { index: 4, member: member(), state: PacketState::Failed },
+            Record::Membership(Membership { name: String::from("friends"), members: vec![member()] }),
+            Record::Left
+        ]
+    }
+
+    fn assert_same(decoded: &Record, record: &Record) {
+        match (decoded, record) {
+            (Record::Packet { local, packet, state }, Record::Packet { local: l, packet: p, state: s }) => {
+                assert_eq!((local, state, packet.id, &packet.data, packet.in_reply_to), (l, s, p.id, &p.data, p.in_reply_to));
+            }
+            (Record::State { index, state }, Record::State { index: i, state: s }) => assert_eq!((index, state), (i, s)),
+            (Record::Username(username), Record::Username(u)) => assert_eq!(username, u),
+            (Record::Member { author, packet, state }, Record::Member { author: a, packet: p, state: s }) => {
+                assert_eq!((author, state, packet.id, &packet.data), (a, s, p.id, &p.data));
+            }
+            (Record::Delivery { index, member, state }, Record::Delivery { index: i, member: m, state: s }) => {
+                assert_eq!((index, member, state), (i, m, s));
+            }
+            (Record::Membership(membership), Record::Membership(m)) => assert_eq!((&membership.name, &membership.members), (&m.name, &m.members)),
+            (Record::Left, Record::Left) => (),
+            _ => panic!("decoded {decoded:?} from {record:?}")
+        }
+    }
+
+    #[test]
+    fn record_round_trip() {
+        for record in records() {
+            assert_same(&Record::from_bytes(record.to_bytes()).unwrap(), &record);
+        }
+    }
+
+    #[test]
+    fn record_truncated() {
+        // Usernames, memberships and left records have no fixed length, so only the others can be detected as truncated.
+        for record in records().into_iter().filter(|record| matches!(record, Record::Packet { .. } | Record::State { .. } | Record::Member { .. } | Record::Delivery { .. })) {
+            let bytes = record.to_bytes();
+            for length in 0..bytes.len() {
+                assert!(Record::from_bytes(bytes[..length].to_vec()).is_err(), "accepted {length} of {} bytes of {record:?}", bytes.len());
+            }
+        }
+    }
+
+    #[test]
+    fn record_malformed() {
+        assert!(Record::from_bytes(vec![0, 1, 2]).is_err());
+        assert!(Record::from_bytes(vec![8]).is_err());
+        assert!(Record::from_bytes([vec![1], 0u64.to_be_bytes().to_vec(), vec![200]].concat()).is_err());
+        assert!(Record::from_bytes([vec![1], 0u64.to_be_bytes().to_vec(), vec![0, 0]].concat()).is_err());
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let records: Vec<Vec<u8>> = records().iter().map(Record::to_bytes).collect();
+        let log: Vec<u8> = records.iter().flat_map(|record| frame(record)).collect();
+        assert_eq!(frames(&log), records);
+        assert!(frames(&[]).is_empty());
+    }
+
+    #[test]
+    fn frames_drop_a_torn_tail() {
+        let records: Vec<Vec<u8>> = records().iter().map(Record::to_bytes).collect();
+        let log: Vec<u8> = records.iter().flat_map(|record| frame(record)).collect();
+        let last = frame(&records[records.len() - 1]).len();
+
+        for torn in 1..last {
+            assert_eq!(frames(&log[..log.len() - torn]), records[..records.len() - 1]);
+        }
+    }
+}
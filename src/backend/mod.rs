@@ -1 +1,5 @@
 pub mod chat;
+pub mod contacts;
+pub mod history;
+pub mod outbox;
+pub mod settings;
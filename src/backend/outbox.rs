@@ -0,0 +1,157 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{read_dir, remove_file};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use iroh::EndpointId;
+
+use crate::error::{Res, StorageError};
+use crate::networking::packet::{MessageId, Packet};
+use crate::util::storage::{create_private_dir, data_dir, read_sealed, write_sealed};
+
+const OUTBOX_DIRECTORY: &str = "outbox";
+const EXTENSION: &str = "queue";
+
+/// Undelivered verified packets, queued per peer in the order they were sent.
+/// Each entry is identified by the packet's message id, and remembers the packet's index within its chat.
+/// A packet sent to a group is queued separately for each member it failed to reach.
+/// Every change rewrites the peer's queue file so that the outbox survives restarts.
+#[derive(Debug)]
+pub struct Outbox {
+    directory: PathBuf,
+    queues: HashMap<EndpointId, VecDeque<(usize, Packet)>>
+}
+
+impl Outbox {
+
+    pub fn open() -> Res<Outbox> {
+        let directory = data_dir()?.join(OUTBOX_DIRECTORY);
+        create_private_dir(&directory)?;
+
+        let mut queues = HashMap::new();
+        for entry in read_dir(&directory)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != EXTENSION) { continue; }
+
+            let peer = match path.file_stem().and_then(|stem| stem.to_str()).map(EndpointId::from_str) {
+                Some(Ok(peer)) => peer,
+                _ => continue
+            };
+
+            // A queue that cannot be read is discarded, since its packets are marked as failed in the history
+            // and are queued again when it is loaded.
+            match Self::decode(read_sealed(&path)?) {
+                Ok(queue) => { queues.insert(peer, queue); },
+                Err(_) => remove_file(&path)?
+            }
+        }
+
+        Ok(Outbox { directory, queues })
+    }
+
+    /// Queue a packet for a peer. Packets that are already queued keep their place.
+    pub fn enqueue(&mut self, peer: EndpointId, index: usize, packet: Packet) -> Res<()> {
+        if !packet.kind.verify() || self.get(peer, packet.id).is_some() { return Ok(()); }
+        self.queues.entry(peer).or_default().push_back((index, packet));
+        self.persist(peer)
+    }
+
+    /// Remove a packet from a peer's queue, returning whether it was queued.
+    pub fn remove(&mut self, peer: EndpointId, id: MessageId) -> Res<bool> {
+        let queue = match self.queues.get_mut(&peer) {
+            Some(queue) => queue,
+            None => return Ok(false)
+        };
+
+        let length = queue.len();
+        queue.retain(|(_, packet)| packet.id != id);
+        if queue.len() == length { return Ok(false); }
+
+        self.persist(peer)?;
+        Ok(true)
+    }
+
+    /// The oldest undelivered packet for a peer.
+    pub fn head(&self, peer: EndpointId) -> Option<(usize, Packet)> {
+        self.queues.get(&peer).and_then(|queue| queue.front()).cloned()
+    }
+
+    pub fn get(&self, peer: EndpointId, id: MessageId) -> Option<(usize, Packet)> {
+        self.queues.get(&peer)?.iter().find(|(_, packet)| packet.id == id).cloned()
+    }
+
+    fn persist(&mut self, peer: EndpointId) -> Res<()> {
+        let path = self.directory.join(format!("{peer}.{EXTENSION}"));
+        match self.queues.get(&peer) {
+            Some(queue) if !queue.is_empty() => write_sealed(&path, Self::encode(queue)),
+            _ => {
+                self.queues.remove(&peer);
+                if path.exists() { remove_file(&path)?; }
+                Ok(())
+            }
+        }
+    }
+
+    /*
+        Encoding
+        Each entry is [index: u64][length: u32][packet bytes].
+    */
+
+    fn encode(queue: &VecDeque<(usize, Packet)>) -> Vec<u8> {
+        queue.iter().flat_map(|(index, packet)| {
+            let bytes = packet.clone().to_stored_bytes();
+            vec![
+                (*index as u64).to_be_bytes().to_vec(),
+                (bytes.len() as u32).to_be_bytes().to_vec(),
+                bytes
+            ].into_iter().flatten()
+        }).collect()
+    }
+
+    fn decode(bytes: Vec<u8>) -> Res<VecDeque<(usize, Packet)>> {
+        let mut queue = VecDeque::new();
+        let mut remaining = bytes.as_slice();
+
+        while !remaining.is_empty() {
+            let (index, rest) = remaining.split_first_chunk::<8>().ok_or(StorageError::CorruptOutbox)?;
+            let (length, rest) = rest.split_first_chunk::<4>().ok_or(StorageError::CorruptOutbox)?;
+            let length = u32::from_be_bytes(*length) as usize;
+            if rest.len() < length { return Err(StorageError::CorruptOutbox.into()); }
+
+            let (packet, rest) = rest.split_at(length);
+            queue.push_back((u64::from_be_bytes(*index) as usize, Packet::from_stored_bytes(packet.to_vec())?));
+            remaining = rest;
+        }
+
+        Ok(queue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue() -> VecDeque<(usize, Packet)> {
+        VecDeque::from([(3, Packet::message(String::from("first"))), (8, Packet::message(String::from("second")).replying_to(1))])
+    }
+
+    #[test]
+    fn round_trip() {
+        let queue = queue();
+        let decoded = Outbox::decode(Outbox::encode(&queue)).unwrap();
+        assert_eq!(decoded.len(), queue.len());
+        for ((index, packet), (i, p)) in decoded.iter().zip(&queue) {
+            assert_eq!((index, packet.id, &packet.data, packet.in_reply_to), (i, p.id, &p.data, p.in_reply_to));
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        // Cutting the queue between entries leaves a shorter, valid one, so that boundary is not checked.
+        let bytes = Outbox::encode(&queue());
+        let first = Outbox::encode(&queue().into_iter().take(1).collect()).len();
+        for length in (1..bytes.len()).filter(|length| *length != first) {
+            assert!(Outbox::decode(bytes[..length].to_vec()).is_err(), "accepted {length} of {} bytes", bytes.len());
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use crate::error::{Res, StorageError};
+use crate::networking::access::AccessPolicy;
+use crate::util::storage::{data_dir, read_sealed, write_sealed};
+
+const SETTINGS_FILE: &str = "settings";
+
+// The choices of how many idle minutes pass before Rift locks itself, where none means never.
+const AUTO_LOCK_CHOICES: [u16; 4] = [0, 5, 15, 60];
+
+/// Preferences that persist across restarts.
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    pub read_receipts: bool,    // Whether peers are told when we have read their packets.
+    pub access: AccessPolicy,   // Who may connect to us.
+    pub auto_lock: u16          // Idle minutes before the window locks while a passphrase is set, where 0 means never.
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings { read_receipts: true, access: AccessPolicy::default(), auto_lock: 0 }
+    }
+}
+
+impl Settings {
+
+    /// Load the stored settings, or the defaults if none have been saved.
+    pub fn load() -> Res<Settings> {
+        let path = data_dir()?.join(SETTINGS_FILE);
+        if !path.exists() { return Ok(Settings::default()); }
+        Self::from_bytes(&read_sealed(&path)?)
+    }
+
+    pub fn save(&self) -> Res<()> {
+        write_sealed(&data_dir()?.join(SETTINGS_FILE), self.to_bytes())
+    }
+
+    /// Move to the next auto-lock timeout, from never to the longest and back.
+    pub fn cycle_auto_lock(&mut self) {
+        let position = AUTO_LOCK_CHOICES.iter().position(|choice| *choice == self.auto_lock).unwrap_or_default();
+        self.auto_lock = AUTO_LOCK_CHOICES[(position + 1) % AUTO_LOCK_CHOICES.len()];
+    }
+
+    /*
+        Encoding
+        [read receipts: u8][access policy: u8][auto lock minutes: u16]
+        Settings saved before the access policy existed end after the first byte, and take the default policy.
+        Those saved before the auto-lock timeout existed end after the second, and never lock.
+        Any trailing bytes are reserved for settings added later.
+    */
+
+    fn to_bytes(self) -> Vec<u8> {
+        [vec![self.read_receipts as u8, self.access.to_byte()], self.auto_lock.to_be_bytes().to_vec()].concat()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Res<Settings> {
+        let read_receipts = bytes.first().ok_or(StorageError::CorruptSettings)?;
+        let access = match bytes.get(1) {
+            Some(byte) => AccessPolicy::from_byte(*byte)?,
+            None => AccessPolicy::default()
+        };
+        let auto_lock = match bytes.get(2..4) {
+            Some(minutes) => u16::from_be_bytes(minutes.try_into().map_err(|_| StorageError::CorruptSettings)?),
+            None => 0
+        };
+        Ok(Settings { read_receipts: *read_receipts != 0, access, auto_lock })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let settings = Settings { read_receipts: false, access: AccessPolicy::ContactsOnly, auto_lock: 15 };
+        let decoded = Settings::from_bytes(&settings.to_bytes()).unwrap();
+        assert_eq!((decoded.read_receipts, decoded.access, decoded.auto_lock), (false, AccessPolicy::ContactsOnly, 15));
+    }
+
+    #[test]
+    fn older_settings_take_defaults() {
+        let settings = Settings { read_receipts: false, access: AccessPolicy::Open, auto_lock: 60 };
+        let bytes = settings.to_bytes();
+
+        let decoded = Settings::from_bytes(&bytes[..2]).unwrap();
+        assert_eq!((decoded.read_receipts, decoded.access, decoded.auto_lock), (false, AccessPolicy::Open, 0));
+
+        let decoded = Settings::from_bytes(&bytes[..1]).unwrap();
+        assert_eq!((decoded.read_receipts, decoded.access, decoded.auto_lock), (false, AccessPolicy::default(), 0));
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(Settings::from_bytes(&[]).is_err());
+        assert!(Settings::from_bytes(&[1, 9]).is_err());
+    }
+}
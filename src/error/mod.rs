@@ -1,7 +1,8 @@
+use std::fmt::{Display, Formatter};
 use std::sync::Arc;
 use async_channel::{RecvError, SendError, TryRecvError};
 use image::ImageError;
-use iroh::endpoint::{BindError, ClosedStream, ConnectError, ConnectingError, ConnectionError, ReadToEndError, WriteError};
+use iroh::endpoint::{BindError, ClosedStream, ConnectError, ConnectWithOptsError, ConnectingError, ConnectionError, ReadError, ReadExactError, ReadToEndError, WriteError};
 use tokio::task::JoinError;
 
 use crate::networking::error::NetworkError;
@@ -17,6 +18,7 @@ macro_rules! error_enum {
     ) => {
         $(#[$meta])*
         #[derive(Debug, Clone)]
+        #[allow(clippy::enum_variant_names)]     // Variants are named after the errors they wrap, most of which end in Error.
         $vis enum $name {
             $(
                 $variant(Arc<$variant>),
@@ -30,15 +32,44 @@ macro_rules! error_enum {
                 }
             }
         )*
+
+        impl std::error::Error for $name {
+            fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+                match self {
+                    $(
+                        $name::$variant(e) => Some(e.as_ref()),
+                    )*
+                }
+            }
+        }
     };
 }
 
 #[derive(Clone, Debug)]
 pub enum ChatError {
     NoChatOpen,
-    InvalidCode,
+    UnconfirmedDelivery,
     NetworkingBackendFailedToInitialise,
-    NoFileSelected
+    NoFileSelected,
+    LeftGroup,
+    InvalidMember
+}
+
+#[derive(Debug, Clone)]
+pub enum StorageError {
+    NoDataDirectory,
+    NoDownloadDirectory,
+    MalformedKey,
+    CorruptHistory,
+    CorruptOutbox,
+    CorruptSettings,
+    CorruptContacts,
+    CorruptBlocklist,
+    CorruptVault,
+    Locked,
+    IncorrectPassphrase,
+    EncryptionFailed,
+    Undecryptable
 }
 
 #[derive(Debug, Clone)]
@@ -47,6 +78,52 @@ pub enum ChannelError {
     ChannelEmpty
 }
 
+impl Display for ChatError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ChatError::NoChatOpen => "No chat is open",
+            ChatError::UnconfirmedDelivery => "The peer did not confirm this message",
+            ChatError::NetworkingBackendFailedToInitialise => "Networking failed to start",
+            ChatError::NoFileSelected => "No file was selected",
+            ChatError::LeftGroup => "You have left this group",
+            ChatError::InvalidMember => "That is not a valid member"
+        })
+    }
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            StorageError::NoDataDirectory => "No data directory could be found",
+            StorageError::NoDownloadDirectory => "No folder could be found to save received files to",
+            StorageError::MalformedKey => "The stored identity is malformed",
+            StorageError::CorruptHistory => "The chat history is corrupt",
+            StorageError::CorruptOutbox => "The outbox is corrupt",
+            StorageError::CorruptSettings => "The settings are corrupt",
+            StorageError::CorruptContacts => "The contact book is corrupt",
+            StorageError::CorruptBlocklist => "The blocklist is corrupt",
+            StorageError::CorruptVault => "The vault file is corrupt",
+            StorageError::Locked => "Rift is locked",
+            StorageError::IncorrectPassphrase => "The passphrase is incorrect",
+            StorageError::EncryptionFailed => "Data could not be encrypted",
+            StorageError::Undecryptable => "Stored data could not be decrypted"
+        })
+    }
+}
+
+impl Display for ChannelError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ChannelError::ChannelDead => "The connection stopped responding",
+            ChannelError::ChannelEmpty => "There was nothing to receive"
+        })
+    }
+}
+
+impl std::error::Error for ChatError {}
+impl std::error::Error for StorageError {}
+impl std::error::Error for ChannelError {}
+
 impl<T> From<SendError<T>> for ChannelError {
     fn from(_: SendError<T>) -> ChannelError {
         ChannelError::ChannelDead
@@ -74,6 +151,7 @@ error_enum! {
     pub enum Error {
         BindError,
         ConnectError,
+        ConnectWithOptsError,
         ConnectingError,
         ChannelError,
         ConnectionError,
@@ -81,9 +159,77 @@ error_enum! {
         ClosedStream,
         NetworkError,
         ChatError,
+        StorageError,
         StdIoError,
         ReadToEndError,
+        ReadExactError,
+        ReadError,
         ImageError,
         JoinError,
     }
 }
+
+/// A short description of what went wrong, fit for the heading of a notification.
+/// The errors of the libraries we depend on are summarised, with their own descriptions left to Error::source.
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::BindError(_) => f.write_str("Networking could not start"),
+            Error::ConnectError(_) | Error::ConnectWithOptsError(_) | Error::ConnectingError(_) => f.write_str("The peer could not be reached"),
+            Error::ConnectionError(_) => f.write_str("The connection was lost"),
+            Error::WriteError(_) | Error::ClosedStream(_) => f.write_str("A packet could not be sent"),
+            Error::ReadToEndError(_) | Error::ReadExactError(_) | Error::ReadError(_) => f.write_str("A packet could not be received"),
+            Error::StdIoError(_) => f.write_str("A file could not be read or written"),
+            Error::ImageError(_) => f.write_str("The image could not be opened"),
+            Error::JoinError(_) => f.write_str("A background task failed"),
+            Error::ChannelError(error) => error.fmt(f),
+            Error::NetworkError(error) => error.fmt(f),
+            Error::ChatError(error) => error.fmt(f),
+            Error::StorageError(error) => error.fmt(f)
+        }
+    }
+}
+
+impl Error {
+
+    /// What, if anything, can be done about the error.
+    pub fn advice(&self) -> Option<&'static str> {
+        match self {
+            Error::BindError(_) => Some("Check that you are connected to a network, then restart Rift."),
+            Error::ConnectError(_) | Error::ConnectWithOptsError(_) | Error::ConnectingError(_) => Some("Peer unreachable — check their node ID or your network."),
+            Error::ConnectionError(_) | Error::ChannelError(_) => Some("The peer may have gone offline. Rift reconnects to peers you added, and undelivered messages can be retried."),
+            Error::WriteError(_) | Error::ClosedStream(_) => Some("The connection was interrupted. Failed messages can be retried from the chat."),
+            Error::ReadToEndError(_) | Error::ReadExactError(_) | Error::ReadError(_) => Some("The connection was interrupted. The peer can send it again."),
+            Error::StdIoError(_) => Some("Check that the file exists and that Rift is allowed to access it."),
+            Error::ImageError(_) => Some("Check that the file is an image in a supported format."),
+            Error::JoinError(_) => None,
+            Error::NetworkError(error) => match **error {
+                NetworkError::PeerOffline => Some("Messages are kept in the outbox and sent once the peer reconnects."),
+                NetworkError::IncompatibleVersion | NetworkError::Unsupported => Some("Ask the peer to update Rift."),
+                NetworkError::TransferInterrupted => Some("The transfer resumes where it left off when the file is retried."),
+                NetworkError::MalformedTicket => Some("Check that the whole invite was copied."),
+                NetworkError::ExpiredTicket => Some("Ask the peer for a new invite."),
+                NetworkError::InvalidPacket | NetworkError::MalformedCode | NetworkError::InvalidHandshake => None
+            },
+            Error::ChatError(error) => match **error {
+                ChatError::NoChatOpen => Some("Choose a chat from the sidebar first."),
+                ChatError::UnconfirmedDelivery => Some("It is kept in the outbox and retried when the peer reconnects, or can be retried from the chat now."),
+                ChatError::NetworkingBackendFailedToInitialise => Some("Check that you are connected to a network, then restart Rift."),
+                ChatError::LeftGroup => Some("Ask a member to add you again to rejoin."),
+                ChatError::InvalidMember => Some("Check their node ID and try again."),
+                ChatError::NoFileSelected => None
+            },
+            Error::StorageError(error) => match **error {
+                StorageError::NoDataDirectory => Some("Rift could not find somewhere to keep its data. Check your home directory is set."),
+                StorageError::NoDownloadDirectory => Some("Check your home directory is set, then ask the peer to send the file again."),
+                StorageError::MalformedKey => Some("Import a backup of your identity, or rotate it to start afresh."),
+                StorageError::CorruptSettings => Some("The default settings are being used instead."),
+                StorageError::CorruptBlocklist => Some("Anyone who dials you is asked about first. Restore the blocklist from a backup and restart Rift, or reset it from the contacts page."),
+                StorageError::Locked => Some("Unlock Rift with your passphrase first."),
+                StorageError::IncorrectPassphrase => Some("Check the passphrase and try again."),
+                StorageError::Undecryptable => Some("The file may have been altered, or sealed under a passphrase that has since changed."),
+                StorageError::CorruptHistory | StorageError::CorruptOutbox | StorageError::CorruptContacts | StorageError::CorruptVault | StorageError::EncryptionFailed => None
+            }
+        }
+    }
+}
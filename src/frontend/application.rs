@@ -1,17 +1,53 @@
+use std::path::PathBuf;
 use std::sync::Arc;
-use iced::{Background, Border, Length, Shadow, Task, widget::{Column, Container, Row, Scrollable, button, text, text_input}};
-use crate::{error::ChatError, frontend::{message::{Global, Message}, pages::{Pages, add_chat_page::AddChatPage, chat_page::{ChatMessage, ChatPage}}, widget::Colour}, networking::{connection_manager::ConnectionManagerMessage, packet::{Packet, TrackedPacket}, server::Local}, util::relay::Relay};
+use std::time::{Duration, Instant};
+use iroh::EndpointId;
+use iced::{Background, Border, Event, Length, Shadow, Subscription, Task, keyboard, mouse, window, alignment::{Horizontal, Vertical}, widget::{Column, Container, Row, Scrollable, Stack, button, svg, text, text_input}};
+use qrcode::{EcLevel, QrCode};
+use crate::{backend::{chat::ChatId, settings::Settings}, error::{ChatError, Error}, frontend::{message::{Global, Message}, pages::{Pages, add_chat_page::AddChatPage, chat_page::{ChatMessage, ChatPage}, contacts_page::{ContactMessage, ContactsPage}, vault_page::{VaultMessage, VaultPage}}, widget::{Colour, button_style, toast_widget::ToastWidget}}, networking::{access::{AccessControl, AccessPolicy}, connection_manager::ConnectionManagerMessage, group::GroupId, identity::Identity, packet::{Packet, TrackedPacket}, server::Local, ticket::Ticket}, util::{clock, relay::Relay, vault}};
 use crate::frontend::notification::Notification;
 
+// How many notifications are on screen at once, and how many past notifications are remembered.
+const MAXIMUM_TOASTS: usize = 5;
+const HISTORY_LENGTH: usize = 100;
+
+// How often the time since the user was last active is checked against the auto-lock timeout.
+const AUTO_LOCK_INTERVAL: Duration = Duration::from_secs(15);
+
+const INVITE_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
 pub struct Application {
     networking: Option<Arc<Local>>,
     active_page: Pages,
     chat_page: Option<ChatPage>,
     add_chat_page: Option<Box<dyn Page>>,
-    notification_stack: Vec<Notification>,
-    active_chats: Vec<(usize, String, usize)>,
+    contacts_page: Option<ContactsPage>,
+    vault_page: Option<VaultPage>,
+    locked_page: Pages,                     // The page to return to once unlocked.
+    started: bool,                          // Whether history and networking have been loaded, which waits for the first unlock.
+    last_activity: Instant,
+    focused: bool,                          // Whether the window has focus, without which the active chat is not being read.
+    notification_stack: Vec<(u64, Notification, usize, bool)>,  // The notifications on screen, each with the id it is dismissed by, how many times it was raised and whether its details are shown.
+    notification_history: Vec<Notification>,                // Every notification raised, most recent first.
+    next_notification: u64,
+    show_notification_history: bool,
+    active_chats: Vec<(EndpointId, Option<String>, usize, PeerStatus)>,    // Each peer with the last username it reported, its unread count and status.
+    groups: Vec<(GroupId, String, usize)>,
     username_input: String,
-    username: Option<String>
+    username: Option<String>,
+    settings: Settings,
+    access: AccessControl,                 // Shared with networking, and kept across restarts of it.
+    connection_requests: Vec<EndpointId>,  // Peers waiting for us to approve their connection, oldest first.
+    identity_qr: Option<svg::Handle>,      // Our node ID as a QR code, drawn once networking is up.
+    show_identity: bool
+}
+
+/// Whether a chat currently has a live connection to its peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerStatus {
+    Online,
+    Offline,
+    Reconnecting(u32)
 }
 
 pub trait Page {
@@ -21,15 +57,31 @@ pub trait Page {
 
 impl Default for Application {
     fn default() -> Application {
+        let access = AccessControl::default();
         Application {
             networking: None,
             active_page: Pages::AddChat,
             chat_page: Some(ChatPage::default()),
             add_chat_page: Some(Box::new(AddChatPage::default())),
+            contacts_page: Some(ContactsPage::new(access.clone())),
+            vault_page: Some(VaultPage::default()),
+            locked_page: Pages::AddChat,
+            started: false,
+            last_activity: Instant::now(),
+            focused: true,
             notification_stack: vec![],
+            notification_history: vec![],
+            next_notification: 0,
+            show_notification_history: false,
             active_chats: vec![],
+            groups: vec![],
             username_input: String::new(),
-            username: None
+            username: None,
+            settings: Settings::default(),
+            access,
+            connection_requests: vec![],
+            identity_qr: None,
+            show_identity: false
         }
     }
 }
@@ -37,12 +89,30 @@ impl Default for Application {
 impl Page for Application {
     fn view(&self) -> Container<'_, Message> {
 
+        // Nothing else is drawn while locked, not even notifications, which may name peers.
+        if let Pages::Unlock = self.active_page {
+            return match self.vault_page.as_ref() {
+                Some(page) => page.unlock_view(),
+                None => Container::new(text("Error"))
+            };
+        }
+
         let contents = match &self.active_page {
             Pages::Chat(_) => if let Some(page) = self.chat_page.as_ref() { page.view() } else { Container::new(text("Error")) }
             Pages::AddChat => if let Some(page) = self.add_chat_page.as_ref() { page.view() } else { Container::new(text("Error")) }
+            Pages::Contacts => if let Some(page) = self.contacts_page.as_ref() { page.view() } else { Container::new(text("Error")) }
+            Pages::Security | Pages::Unlock => if let Some(page) = self.vault_page.as_ref() { page.view() } else { Container::new(text("Error")) }
         };
 
-        Container::new(
+        // Toasts are layered over the bottom right of the window, beneath the history of past notifications when it is open.
+        let toasts = Container::new(
+            Column::new().spacing(10)
+                .push(if self.show_notification_history { Some(ToastWidget::history(&self.notification_history)) } else { None })
+                .extend(self.notification_stack.iter().rev().take(MAXIMUM_TOASTS).rev()
+                    .map(|(id, notification, count, expanded)| ToastWidget::parse(*id, notification, *count, *expanded).into()))
+        ).padding(20).width(Length::Fill).height(Length::Fill).align_x(Horizontal::Right).align_y(Vertical::Bottom);
+
+        Container::new(Stack::new().push(Container::new(
             Row::new()
                 .push(
                     Column::new().spacing(10).padding(10)
@@ -84,6 +154,26 @@ impl Page for Application {
                                         ).width(Length::Fill)
 
                                 )
+                        ).push(
+                            Row::new().spacing(5)
+                                .push(button(text(if self.show_identity { "HIDE ID" } else { "SHOW ID" }).size(12)).on_press(Global::ToggleIdentity.into()).style(button_style).width(Length::Fill))
+                                .push(button(text("ROTATE ID").size(12)).on_press(Global::RotateIdentity.into()).style(button_style).width(Length::Fill))
+                                .push(button(text("IMPORT ID").size(12)).on_press(Global::PickIdentityImport.into()).style(button_style).width(Length::Fill))
+                                .push(button(text("EXPORT ID").size(12)).on_press(Global::PickIdentityExport.into()).style(button_style).width(Length::Fill))
+                        ).push(self.identity_panel()
+                        ).push(
+                            Row::new().spacing(5)
+                                .push(button(text(match self.settings.read_receipts {
+                                    true => "READ RECEIPTS: ON",
+                                    false => "READ RECEIPTS: OFF"
+                                }).size(12)).on_press(Global::ToggleReadReceipts.into()).style(button_style).width(Length::Fill))
+                                .push(button(text("NOTIFICATIONS").size(12)).on_press(Global::ToggleNotificationHistory.into()).style(button_style).width(Length::Fill))
+                                .push(button(text(match self.settings.access {
+                                    AccessPolicy::ContactsOnly => "ACCESS: CONTACTS",
+                                    AccessPolicy::Ask => "ACCESS: ASK",
+                                    AccessPolicy::Open => "ACCESS: OPEN"
+                                }).size(12)).on_press(Global::CycleAccessPolicy.into()).style(button_style).width(Length::Fill))
+                        ).push(self.requests_panel()
                         ).push(
                             button("ADD CHAT").on_press_with(|| Global::SwitchTo(Pages::AddChat).into())
                                 .style(
@@ -100,29 +190,34 @@ impl Page for Application {
                                         snap: false
                                     }
                                 ).width(Length::Fill)
+                        ).push(
+                            button("CONTACTS").on_press_with(|| Global::SwitchTo(Pages::Contacts).into()).style(button_style).width(Length::Fill)
+                        ).push(
+                            Row::new().spacing(5)
+                                .push(button("SECURITY").on_press_with(|| Global::SwitchTo(Pages::Security).into()).style(button_style).width(Length::Fill))
+                                .push(match vault::is_enabled() {
+                                    true => Some(button("LOCK").on_press(Global::Lock.into()).style(button_style).width(Length::Fill)),
+                                    false => None
+                                })
                         ).push(
                             Container::new(
                                 Scrollable::new(Column::from_iter(self.active_chats.iter().map(
-                                    |(id, chat, notifications)| button(
-                                        Row::new().spacing(10).push(text(chat).size(15))
-                                            .push(match notifications {
-                                                0 => None,
-                                                other => Some(
-                                                    Container::new(text(other).size(20f32).color(Colour::text()))
-                                                        .padding(5)
-                                                        .style(|_|
-                                                            iced::widget::container::Style {
-                                                                background: Some(Background::Color(Colour::error())),
-                                                                text_color: None,
-                                                                border: Border::default().rounded(5),
-                                                                shadow: Shadow::default(),
-                                                                snap: false
-                                                            }
-                                                        )
-                                                )
+                                    |(id, username, notifications, status)| button(
+                                        Row::new().spacing(10)
+                                            .push(text("●").size(15).color(match status {
+                                                PeerStatus::Online => Colour::success(),
+                                                PeerStatus::Offline => Colour::loading(),
+                                                PeerStatus::Reconnecting(_) => Colour::warning()
+                                            }))
+                                            .push(text(self.chat_name(id, username.as_ref())).size(15))
+                                            .push(self.petname(id).and(username.as_ref()).map(|username| text(format!("~{username}")).size(12).color(Colour::loading())))
+                                            .push(match status {
+                                                PeerStatus::Reconnecting(attempt) => Some(text(format!("reconnecting… ({attempt})")).size(12).color(Colour::loading())),
+                                                _ => None
                                             })
+                                            .push(notification_badge(*notifications))
                                         )
-                                        .on_press_with(|| Global::SwitchTo(Pages::Chat(*id)).into())
+                                        .on_press_with(|| Global::SwitchTo(Pages::Chat(ChatId::Direct(*id))).into())
                                         .style(
                                             |_, status|
                                             iced::widget::button::Style {
@@ -136,9 +231,20 @@ impl Page for Application {
                                                 shadow: Shadow::default(),
                                                 snap: false
                                             }
-                                        ).into())).spacing(10)
+                                        ).into()))
+                                        .extend(self.groups.iter().map(
+                                            |(group, name, notifications)| button(
+                                                Row::new().spacing(10)
+                                                    .push(text("#").size(15).color(Colour::accent()))
+                                                    .push(text(name).size(15))
+                                                    .push(notification_badge(*notifications))
+                                            )
+                                            .on_press_with(|| Global::SwitchTo(Pages::Chat(ChatId::Group(*group))).into())
+                                            .style(button_style)
+                                            .into()
+                                        )).spacing(10)
                                         .push(
-                                            match self.active_chats.is_empty() {
+                                            match self.active_chats.is_empty() && self.groups.is_empty() {
                                                 true => Some(text("You don't seem to have any chats yet...").color(Colour::loading())),
                                                 false => None
                                             }
@@ -163,14 +269,21 @@ impl Page for Application {
                 shadow: Shadow::default(),
                 snap: false
             }
-        ).height(Length::Fill).width(Length::Fill)
+        ).height(Length::Fill).width(Length::Fill)).push(toasts))
 
     }
 
     fn update(&mut self, message: Message) -> Task<Message> {
         match message {
             Message::Global(global) => match global {
-                Global::LoadNetworking => Task::future(Local::establish())
+
+                // Nothing stored can be read until Rift is unlocked, so loading waits for the passphrase if one is set.
+                Global::Start => match vault::is_locked() {
+                    true => (self.active_page = Pages::Unlock).into(),
+                    false => self.start()
+                },
+
+                Global::LoadNetworking => Task::future(Local::establish(self.access.clone()))
                     .map(|res| {
                         match res {
                             Ok(local) => Global::LoadSuccess(Arc::new(local)),
@@ -178,23 +291,75 @@ impl Page for Application {
                          }.into()
                     }),
 
+                // Settings and the blocklist are loaded alongside the history. Unreadable settings fall back to the defaults,
+                // while an unreadable blocklist is kept, and every peer that dials us is asked about until it is reset.
+                Global::LoadHistory => {
+                    let loaded = match Settings::load() {
+                        Ok(settings) => {
+                            self.settings = settings;
+                            Task::none()
+                        }
+                        Err(error) => Task::done(Global::Error(error).into())
+                    };
+                    self.access.set_policy(self.settings.access);
+
+                    let blocklist = match self.access.load_blocklist() {
+                        Ok(()) => Task::none(),
+                        Err(error) => Task::done(Global::Error(error).into())
+                    };
+
+                    Task::batch(vec![
+                        loaded,
+                        blocklist,
+                        Task::done(ChatMessage::SetReadReceipts(self.settings.read_receipts).into()),
+                        Task::done(VaultMessage::SetAutoLock(self.settings.auto_lock).into()),
+                        Task::done(ContactMessage::Load.into()),
+                        Task::done(ChatMessage::LoadHistory.into())
+                    ])
+                }
+
+                Global::ToggleReadReceipts => {
+                    self.settings.read_receipts = !self.settings.read_receipts;
+                    let saved = match self.settings.save() {
+                        Ok(()) => Task::none(),
+                        Err(error) => Task::done(Global::Error(error).into())
+                    };
+
+                    let status = match self.settings.read_receipts {
+                        true => "Read receipts turned on.",
+                        false => "Read receipts turned off. Peers will no longer see when you have read their messages."
+                    };
+
+                    Task::batch(vec![
+                        saved,
+                        Task::done(Global::Notify(Notification::info(String::from(status))).into()),
+                        Task::done(ChatMessage::SetReadReceipts(self.settings.read_receipts).into())
+                    ])
+                }
+
                 Global::Error(error) => {
                     Task::done(Global::Notify(error.into()).into())
                 }
 
                 Global::SwitchTo(page) => {
 
-                    let task = if let Pages::Chat(stable_id) = page {
-                        Task::done(ChatMessage::SetActiveChat(stable_id).into())
+                    let task = if let Pages::Chat(peer) = page {
+                        Task::done(ChatMessage::SetActiveChat(peer).into())
                     } else { Task::none() };
 
-                    self.active_page = page;
+                    // A page opened while locked is shown once unlocked.
+                    match self.active_page {
+                        Pages::Unlock => self.locked_page = page,
+                        _ => self.active_page = page
+                    }
 
-                    task
+                    Task::batch(vec![task, self.on_screen()])
                 }
 
+                Global::Connect(node_id) => Task::done(Global::ConnectTo(node_id.into()).into()),
+
                 // Initiate a connection. Upon success, backend will inform frontend of the connection.
-                Global::Connect(node_id) => match self.networking.as_ref() {
+                Global::ConnectTo(addr) => match self.networking.as_ref() {
 
                     Some(local) => {
                         // Spawn a future that will attempt to connect with a client.
@@ -203,10 +368,12 @@ impl Page for Application {
                             local.ep(),
                             local.cs(),
                             local.ps(),
-                            node_id.into()
+                            local.ts(),
+                            local.rw(),
+                            addr
                         )).map(|res| match res {
                             // Upon success, counterintuitively do not track the new ID. Rather, rely on the backend to process the connection and relay it back.
-                            Ok(id) => Global::Notify(Notification::success(format!("Connection success! ID: {id}"))),
+                            Ok(id) => Global::Notify(Notification::success(format!("Connection success! ID: {}", id.fmt_short()))),
                             Err(error) => Global::Notify(error.into()),
                         }.into())
                     }
@@ -219,19 +386,31 @@ impl Page for Application {
                     // Establish receiver clones for backend outputs.
                     let output_receiver = local.yield_output();
                     let packet_receiver = local.yield_packet_output();
+                    let transfer_receiver = local.yield_transfer_output();
+                    let local_id = local.ep().id();
                     self.networking = Some(local);
+                    self.identity_qr = QrCode::with_error_correction_level(local_id.to_string(), EcLevel::M).ok()
+                        .map(|code| svg::Handle::from_memory(code.render::<qrcode::render::svg::Color>().quiet_zone(true).build().into_bytes()));
                     
                     // Generate a relay converting new connections / errors into frontend messages.
                     // This will occur for foreign and locally initiated connections.
                     let new_connection_stream = Task::stream(Relay::consume_receiver(output_receiver, |message| match message {
-                        ConnectionManagerMessage::SuccessfulConnection(stable_id) => Some(Global::ChatConnected(stable_id).into()),
+                        ConnectionManagerMessage::SuccessfulConnection(peer) => Some(Global::ChatConnected(peer).into()),
+                        ConnectionManagerMessage::Disconnected(peer, reason) => Some(Global::ChatDisconnected(peer, reason).into()),
+                        ConnectionManagerMessage::Reconnecting(peer, attempt) => Some(Global::ChatReconnecting(peer, attempt).into()),
+                        ConnectionManagerMessage::Request(peer) => Some(Global::ConnectionRequested(peer).into()),
+                        ConnectionManagerMessage::Withdrawn(peer) => Some(Global::ConnectionWithdrawn(peer).into()),
+                        ConnectionManagerMessage::Awaiting(peer) => Some(Global::Notify(Notification::info(format!("Waiting for {} to accept your connection", peer.fmt_short()))).into()),
                         ConnectionManagerMessage::Error(error) => Some(Global::Error(error).into()),
                         _ => Some(Message::Global(Global::None))
                     }));
 
                     // Generate a relay converting incoming packets into frontend messages.
                     let new_packet_stream = Task::stream(Relay::consume_receiver(packet_receiver, |(author, packet)| Some(Global::Packet(author, packet).into())));
-                    Task::batch(vec![new_connection_stream, new_packet_stream])
+
+                    // Generate a relay converting file transfer progress into frontend messages.
+                    let new_transfer_stream = Task::stream(Relay::consume_receiver(transfer_receiver, |event| Some(ChatMessage::Transfer(event).into())));
+                    Task::batch(vec![new_connection_stream, new_packet_stream, new_transfer_stream, Task::done(ChatMessage::SetLocalId(local_id).into())])
                 }
 
                 // Originating point of incoming packets from the relay above.
@@ -252,13 +431,39 @@ impl Page for Application {
                     }.into())
                 }
 
+                // A notification already on screen is counted again rather than shown twice.
                 Global::Notify(notification) => {
-                    println!("NOTIFICATION: {notification:?}");
-                    self.notification_stack.push(notification);
+                    if let Some((_, _, count, _)) = self.notification_stack.iter_mut().find(|(_, shown, _, _)| *shown == notification) {
+                        *count += 1;
+                        return Task::none();
+                    }
+
+                    let id = self.next_notification;
+                    self.next_notification += 1;
+
+                    self.notification_history.insert(0, notification.clone());
+                    self.notification_history.truncate(HISTORY_LENGTH);
+
+                    let expiry = notification.expiry();
+                    self.notification_stack.push((id, notification, 1, false));
+                    match expiry {
+                        Some(expiry) => Task::perform(tokio::time::sleep(expiry), move |_| Global::DismissNotification(id).into()),
+                        None => Task::none()
+                    }
+                }
+
+                Global::DismissNotification(id) => (self.notification_stack.retain(|(shown, _, _, _)| *shown != id)).into(),
+
+                Global::ToggleNotificationDetails(id) => {
+                    if let Some((_, _, _, expanded)) = self.notification_stack.iter_mut().find(|(shown, _, _, _)| *shown == id) {
+                        *expanded = !*expanded;
+                    }
                     Task::none()
                 }
 
-                Global::LoadImage(chat_stable_id, result) => {
+                Global::ToggleNotificationHistory => (self.show_notification_history = !self.show_notification_history).into(),
+
+                Global::LoadImage(peer, result) => {
                     let option = match result {
                         Ok(option) => option,
                         Err(e) => return Task::done(Global::Error(e).into())
@@ -269,18 +474,52 @@ impl Page for Application {
                         None => return Task::done(Global::Error(ChatError::NoFileSelected.into()).into())
                     };
 
-                    Task::done(ChatMessage::ImagePicked(chat_stable_id, path).into())
+                    Task::done(ChatMessage::ImagePicked(peer, path).into())
                 }
 
-                Global::ChatConnected(stable_id) => {
-                    self.active_chats.push((stable_id, stable_id.to_string(), 1));
+                // A peer (re)connected. Chats are keyed by peer, so a returning peer resumes its existing chat.
+                Global::ChatConnected(peer) => {
+                    self.access.know(peer);
+                    match self.active_chats.iter_mut().find(|chat| chat.0 == peer) {
+                        Some(chat) => chat.3 = PeerStatus::Online,
+                        None => self.active_chats.push((peer, None, 1, PeerStatus::Online))
+                    }
                     if let Some(chat_page) = self.chat_page.as_mut() {
-                        chat_page.make_empty(stable_id);
+                        chat_page.make_empty(peer);
                     }
-                    match self.username.as_ref() {
-                        Some(username) => Task::done(Global::Send(TrackedPacket::new(stable_id, Packet::username(username.to_string())).0).into()),
+                    let username = match self.username.as_ref() {
+                        Some(username) => Task::done(Global::Send(TrackedPacket::new(peer, Packet::username(username.to_string())).0).into()),
                         None => Task::none()
+                    };
+                    // Groups are synchronised before the outbox is flushed, so that the peer knows of any group its queued packets belong to.
+                    Task::batch(vec![
+                        username,
+                        Task::done(ChatMessage::SyncGroups(peer).into()),
+                        Task::done(ChatMessage::FlushOutbox(peer).into()),
+                        Task::done(ChatMessage::ResumeTransfers(peer).into())
+                    ])
+                }
+
+                // The last connection to a peer closed. Its chat remains, but is marked offline.
+                Global::ChatDisconnected(peer, reason) => {
+                    let mut username = None;
+                    for chat in &mut self.active_chats {
+                        if chat.0 == peer {
+                            chat.3 = PeerStatus::Offline;
+                            username = chat.1.clone();
+                        }
                     }
+                    let name = self.chat_name(&peer, username.as_ref());
+                    Task::done(Global::Notify(Notification::warning(format!("{name} disconnected"), Some(reason))).into())
+                }
+
+                Global::ChatReconnecting(peer, attempt) => {
+                    for chat in &mut self.active_chats {
+                        if chat.0 == peer {
+                            chat.3 = PeerStatus::Reconnecting(attempt);
+                        }
+                    }
+                    Task::none()
                 }
 
                 Global::UsernameInput(value) => {
@@ -292,35 +531,303 @@ impl Page for Application {
                     if self.username_input.is_empty() { return Task::none(); }
                     let new_username = std::mem::take(&mut self.username_input);
                     self.username = Some(new_username.clone());
-                    Task::done(ChatMessage::UsernameUpdate(new_username).into())
+
+                    // Only online peers can be told, the rest learn the new username when they reconnect.
+                    let packet = Packet::username(new_username.clone());
+                    let mut dispatch: Vec<Task<Message>> = self.active_chats.iter()
+                        .filter(|chat| chat.3 == PeerStatus::Online)
+                        .map(|chat| Task::done(Global::Send(TrackedPacket::new(chat.0, packet.clone()).0).into()))
+                        .collect();
+
+                    dispatch.push(Task::done(ChatMessage::UsernameUpdate(new_username).into()));
+                    Task::batch(dispatch)
+                }
+
+                Global::ChatRestored(peer, username) => {
+                    self.access.know(peer);
+                    if !self.active_chats.iter().any(|chat| chat.0 == peer) {
+                        self.active_chats.push((peer, username, 0, PeerStatus::Offline));
+                    }
+                    Task::none()
                 }
 
-                Global::BindUsernameToId(stable_id, username) => {
+                // Connect to any member we are not already connected to, so that the group's packets can reach them.
+                // Members are not dialled before networking is up, as with chats restored from history.
+                Global::GroupJoined(group, name, members) => {
+                    match self.groups.iter_mut().find(|entry| entry.0 == group) {
+                        Some(entry) => entry.1 = name,
+                        None => self.groups.push((group, name, 0))
+                    }
+
+                    if self.networking.is_none() { return Task::none(); }
+                    Task::batch(members.into_iter()
+                        .filter(|member| !self.active_chats.iter().any(|chat| chat.0 == *member && chat.3 == PeerStatus::Online))
+                        .map(|member| Task::done(Global::Connect(member).into()))
+                        .collect::<Vec<Task<Message>>>())
+                }
+
+                // Usernames are chosen by the peers themselves, so a change is pointed out in case someone else has taken the name.
+                Global::BindUsernameToId(peer, username) => {
+                    let mut previous = self.contacts_page.as_ref().and_then(|page| page.book().get(&peer)).and_then(|contact| contact.username.clone());
                     for chat in &mut self.active_chats {
-                        if chat.0 == stable_id {
-                            chat.1 = username.clone();
+                        if chat.0 == peer {
+                            previous = chat.1.replace(username.clone()).or(previous);
                         }
                     }
 
-                    Task::none()
+                    let warning = match previous.filter(|previous| *previous != username) {
+                        Some(previous) => Task::done(Global::Notify(Notification::warning(
+                            format!("{} changed their username", self.petname(&peer).unwrap_or(&previous)),
+                            Some(format!("Previously {previous}, now {username}. Check this is who you expect before trusting the new name."))
+                        )).into()),
+                        None => Task::none()
+                    };
+
+                    Task::batch(vec![warning, Task::done(ContactMessage::Observe(peer, username).into())])
+                }
+
+                // Chats with peers we have not yet connected to appear once the connection succeeds.
+                Global::OpenChat(peer) => {
+                    let connect = match self.active_chats.iter().any(|chat| chat.0 == peer && chat.3 == PeerStatus::Online) {
+                        true => Task::none(),
+                        false => Task::done(Global::Connect(peer).into())
+                    };
+
+                    match self.active_chats.iter().any(|chat| chat.0 == peer) {
+                        true => Task::batch(vec![connect, Task::done(Global::SwitchTo(Pages::Chat(ChatId::Direct(peer))).into())]),
+                        false => connect
+                    }
                 }
 
                 Global::AddNotification(id) => {
-                    for chat in &mut self.active_chats {
-                        if chat.0 == id {
-                            chat.2 += 1;
-                        }
+                    match id {
+                        ChatId::Direct(peer) => self.active_chats.iter_mut().filter(|chat| chat.0 == peer).for_each(|chat| chat.2 += 1),
+                        ChatId::Group(group) => self.groups.iter_mut().filter(|entry| entry.0 == group).for_each(|entry| entry.2 += 1)
                     }
                     Task::none()
                 }
 
                 Global::ClearNotifications(id) => {
+                    match id {
+                        ChatId::Direct(peer) => self.active_chats.iter_mut().filter(|chat| chat.0 == peer).for_each(|chat| chat.2 = 0),
+                        ChatId::Group(group) => self.groups.iter_mut().filter(|entry| entry.0 == group).for_each(|entry| entry.2 = 0)
+                    }
+                    Task::none()
+                }
+
+                Global::ToggleIdentity => (self.show_identity = !self.show_identity).into(),
+
+                Global::CopyIdentity => match self.networking.as_ref() {
+                    Some(local) => Task::batch(vec![
+                        iced::clipboard::write(local.ep().id().to_string()),
+                        Task::done(Global::Notify(Notification::success(String::from("Node ID copied to the clipboard."))).into())
+                    ]),
+                    None => Task::done(Global::Notify(Notification::error(String::from("Networking not initialised."))).into())
+                },
+
+                // Invites name us by our username, and last a week.
+                Global::CopyInvite => match self.networking.as_ref() {
+                    Some(local) => {
+                        let ticket = Ticket::new(local.ep().addr(), self.username.clone(), Some(clock::now() + INVITE_LIFETIME.as_millis() as u64));
+                        Task::batch(vec![
+                            iced::clipboard::write(ticket.to_string()),
+                            Task::done(Global::Notify(Notification::success(String::from("Invite copied to the clipboard. It expires in 7 days."))).into())
+                        ])
+                    }
+                    None => Task::done(Global::Notify(Notification::error(String::from("Networking not initialised."))).into())
+                },
+
+                Global::ConnectionRequested(peer) => {
+                    if self.connection_requests.contains(&peer) { return Task::none(); }
+                    self.connection_requests.push(peer);
+                    Task::done(Global::Notify(Notification::info(format!("{} wants to connect", peer.fmt_short()))).into())
+                }
+
+                Global::ConnectionWithdrawn(peer) => (self.connection_requests.retain(|request| *request != peer)).into(),
+
+                Global::CycleAccessPolicy => {
+                    self.settings.access = self.settings.access.next();
+                    self.access.set_policy(self.settings.access);
+                    let saved = match self.settings.save() {
+                        Ok(()) => Task::none(),
+                        Err(error) => Task::done(Global::Error(error).into())
+                    };
+
+                    let status = match self.settings.access {
+                        AccessPolicy::ContactsOnly => "Only contacts can connect to you.",
+                        AccessPolicy::Ask => "Peers you have not chatted with must ask before connecting.",
+                        AccessPolicy::Open => "Anyone with your node ID can connect to you."
+                    };
+
+                    Task::batch(vec![saved, Task::done(Global::Notify(Notification::info(String::from(status))).into())])
+                }
+
+                Global::AcceptConnection(peer) => {
+                    self.connection_requests.retain(|request| *request != peer);
+                    match self.networking.as_ref() {
+                        Some(local) => Task::future(Local::approve(local.cs(), local.ps(), local.ts(), local.rw(), self.access.clone(), peer)).map(|res| match res {
+                            Ok(()) => Global::None,
+                            Err(error) => Global::Error(error)
+                        }.into()),
+                        None => Task::done(Global::Notify(Notification::error(String::from("Networking not initialised."))).into())
+                    }
+                }
+
+                Global::RejectConnection(peer) => {
+                    self.connection_requests.retain(|request| *request != peer);
+                    self.access.reject(peer);
+                    Task::none()
+                }
+
+                // A blocked peer that is connected is disconnected, and is not redialed.
+                // It stays blocked until restart even if the blocklist could not be saved.
+                Global::Block(peer) => {
+                    self.connection_requests.retain(|request| *request != peer);
+                    let saved = match self.access.block(peer) {
+                        Ok(()) => Task::done(Global::Notify(Notification::success(format!("Blocked {}.", self.chat_name(&peer, None)))).into()),
+                        Err(error) => Task::done(Global::Error(error).into())
+                    };
+
+                    let disconnect = match self.networking.as_ref() {
+                        Some(local) => Task::future(Local::disconnect(local.cs(), peer)).map(|res| match res {
+                            Ok(()) => Global::None,
+                            Err(error) => Global::Error(error)
+                        }.into()),
+                        None => Task::none()
+                    };
+
+                    Task::batch(vec![disconnect, saved])
+                }
+
+                Global::Unblock(peer) => match self.access.unblock(&peer) {
+                    Ok(()) => Task::done(Global::Notify(Notification::success(format!("Unblocked {}.", self.chat_name(&peer, None)))).into()),
+                    Err(error) => Task::done(Global::Error(error).into())
+                },
+
+                Global::ResetBlocklist => match self.access.reset_blocklist() {
+                    Ok(()) => Task::done(Global::Notify(Notification::warning(String::from("Blocklist reset. Peers blocked before were forgotten."), None)).into()),
+                    Err(error) => Task::done(Global::Error(error).into())
+                },
+
+                // Replace our identity. Every existing connection is bound to the old key, so networking is restarted.
+                Global::RotateIdentity => match Identity::rotate() {
+                    Ok((_, retired)) => Task::batch(vec![
+                        Task::done(Global::Notify(Notification::success(format!("Identity rotated. Contacts will need your new node ID.{}", Self::retired(retired)))).into()),
+                        Task::done(Global::ReloadNetworking.into())
+                    ]),
+                    Err(error) => Task::done(Global::Error(error).into())
+                }
+
+                Global::PickIdentityImport => Task::perform(
+                    tokio::task::spawn_blocking(|| rfd::FileDialog::new().set_title("Import identity").pick_file()),
+                    |res| Global::ImportIdentity(res.map_err(Error::from)).into()
+                ),
+
+                Global::PickIdentityExport => Task::perform(
+                    tokio::task::spawn_blocking(|| rfd::FileDialog::new().set_title("Export identity").set_file_name("rift.key").save_file()),
+                    |res| Global::ExportIdentity(res.map_err(Error::from)).into()
+                ),
+
+                Global::ImportIdentity(result) => match result {
+                    Ok(Some(path)) => match Identity::import(&path) {
+                        Ok((_, retired)) => Task::batch(vec![
+                            Task::done(Global::Notify(Notification::success(format!("Identity imported.{}", Self::retired(retired)))).into()),
+                            Task::done(Global::ReloadNetworking.into())
+                        ]),
+                        Err(error) => Task::done(Global::Error(error).into())
+                    },
+                    Ok(None) => Task::done(Global::Error(ChatError::NoFileSelected.into()).into()),
+                    Err(error) => Task::done(Global::Error(error).into())
+                }
+
+                Global::ExportIdentity(result) => match result {
+                    Ok(Some(path)) => match Identity::export(&path) {
+                        Ok(()) => Task::done(Global::Notify(Notification::success(format!("Identity exported to {}", path.display()))).into()),
+                        Err(error) => Task::done(Global::Error(error).into())
+                    },
+                    Ok(None) => Task::done(Global::Error(ChatError::NoFileSelected.into()).into()),
+                    Err(error) => Task::done(Global::Error(error).into())
+                }
+
+                // Tear down the current endpoint and establish a new one from the stored identity.
+                // Chats are keyed by peer rather than connection, so they survive the restart.
+                Global::ReloadNetworking => {
                     for chat in &mut self.active_chats {
-                        if chat.0 == id {
-                            chat.2 = 0;
+                        chat.3 = PeerStatus::Offline;
+                    }
+                    self.connection_requests.clear();
+
+                    let shutdown = match self.networking.take() {
+                        Some(local) => Task::future(async move { local.shutdown().await }).discard(),
+                        None => Task::none()
+                    };
+
+                    shutdown.chain(Task::done(Global::LoadNetworking.into()))
+                }
+
+                // The page shown when locked is opened again, which for a chat also makes it the active chat once more.
+                Global::Unlocked => {
+                    self.last_activity = Instant::now();
+                    let shown = match self.active_page {
+                        Pages::Unlock => {
+                            self.active_page = Pages::AddChat;
+                            Task::done(Global::SwitchTo(self.locked_page).into())
                         }
+                        _ => Task::none()
+                    };
+                    let started = match self.started {
+                        true => self.on_screen(),
+                        false => self.start()
+                    };
+                    Task::batch(vec![shown, started])
+                }
+
+                // Locking clears the key from memory, along with everything that was read with it, and stops networking,
+                // since nothing received could be stored. Peers keep what they send meanwhile in their outboxes.
+                // Only the settings and our username are kept, and everything else is loaded again once unlocked.
+                Global::Lock => {
+                    if !vault::is_enabled() { return Task::none(); }
+                    if let Pages::Unlock = self.active_page { return Task::none(); }
+
+                    let shutdown = match self.networking.take() {
+                        Some(local) => Task::future(async move { local.shutdown().await }).discard(),
+                        None => Task::none()
+                    };
+                    vault::lock();
+
+                    let (locked_page, focused, username, settings) = (self.active_page, self.focused, self.username.take(), std::mem::take(&mut self.settings));
+                    let restored = match username.clone() {
+                        Some(username) => Task::done(ChatMessage::UsernameUpdate(username).into()),
+                        None => Task::none()
+                    };
+                    *self = Application { active_page: Pages::Unlock, locked_page, focused, username, settings, ..Application::default() };
+
+                    Task::batch(vec![shutdown, restored, self.on_screen()])
+                }
+
+                Global::Activity => (self.last_activity = Instant::now()).into(),
+
+                Global::Focus(focused) => {
+                    self.focused = focused;
+                    self.on_screen()
+                }
+
+                Global::CheckAutoLock => match self.settings.auto_lock {
+                    0 => Task::none(),
+                    minutes => match self.last_activity.elapsed() >= Duration::from_secs(minutes as u64 * 60) {
+                        true => Task::done(Global::Lock.into()),
+                        false => Task::none()
                     }
-                    Task::none()
+                },
+
+                Global::CycleAutoLock => {
+                    self.settings.cycle_auto_lock();
+                    self.last_activity = Instant::now();
+                    let saved = match self.settings.save() {
+                        Ok(()) => Task::none(),
+                        Err(error) => Task::done(Global::Error(error).into())
+                    };
+                    Task::batch(vec![saved, Task::done(VaultMessage::SetAutoLock(self.settings.auto_lock).into())])
                 }
 
                 Global::None => Task::none()
@@ -339,6 +846,146 @@ impl Page for Application {
                     None => Task::none()
                 }
             }
+
+            Message::ContactMessage(msg) => {
+                match self.contacts_page.as_mut() {
+                    Some(page) => page.update(Message::ContactMessage(msg)),
+                    None => Task::none()
+                }
+            }
+
+            Message::VaultMessage(msg) => {
+                match self.vault_page.as_mut() {
+                    Some(page) => page.update(Message::VaultMessage(msg)),
+                    None => Task::none()
+                }
+            }
         }
     }
 }
+
+impl Application {
+
+    /// Key presses and clicks postpone the auto-lock, which is checked periodically while a passphrase is set and a timeout chosen.
+    pub fn subscription(&self) -> Subscription<Message> {
+        let focus = iced::event::listen_with(|event, _, _| match event {
+            Event::Window(window::Event::Focused) => Some(Global::Focus(true).into()),
+            Event::Window(window::Event::Unfocused) => Some(Global::Focus(false).into()),
+            _ => None
+        });
+        if self.settings.auto_lock == 0 || !vault::is_enabled() { return focus; }
+
+        Subscription::batch(vec![
+            focus,
+            iced::event::listen_with(|event, _, _| match event {
+                Event::Keyboard(keyboard::Event::KeyPressed { .. })
+                | Event::Mouse(mouse::Event::ButtonPressed(_) | mouse::Event::WheelScrolled { .. }) => Some(Global::Activity.into()),
+                _ => None
+            }),
+            iced::time::every(AUTO_LOCK_INTERVAL).map(|_| Global::CheckAutoLock.into())
+        ])
+    }
+
+    /// Tell the chat page whether its active chat is on screen, which it is only while shown in a focused window.
+    /// While locked, the unlock page is shown in its place.
+    fn on_screen(&self) -> Task<Message> {
+        Task::done(ChatMessage::SetOnScreen(self.focused && matches!(self.active_page, Pages::Chat(_))).into())
+    }
+
+    /// Where the identity replaced by a rotation or an import was kept, to be told to the user.
+    fn retired(retired: Option<PathBuf>) -> String {
+        retired.map(|path| format!(" Your previous identity was kept in {}.", path.display())).unwrap_or_default()
+    }
+
+    /// Load the history and start networking, once storage can be read.
+    fn start(&mut self) -> Task<Message> {
+        self.started = true;
+        Task::batch(vec![Task::done(Global::LoadHistory.into()), Task::done(Global::LoadNetworking.into())])
+    }
+
+    /// Peers waiting for us to approve their connection, each with buttons to accept, reject or block them.
+    fn requests_panel(&self) -> Option<Container<'_, Message>> {
+        if self.connection_requests.is_empty() { return None; }
+
+        Some(Container::new(
+            Column::new().spacing(10)
+                .extend(self.connection_requests.iter().map(|peer|
+                    Column::new().spacing(5)
+                        .push(text(format!("{} wants to connect", peer.fmt_short())).size(12).color(Colour::text()))
+                        .push(
+                            Row::new().spacing(5)
+                                .push(button(text("ACCEPT").size(12)).on_press(Global::AcceptConnection(*peer).into()).style(button_style).width(Length::Fill))
+                                .push(button(text("REJECT").size(12)).on_press(Global::RejectConnection(*peer).into()).style(button_style).width(Length::Fill))
+                                .push(button(text("BLOCK").size(12)).on_press(Global::Block(*peer).into()).style(button_style).width(Length::Fill))
+                        )
+                        .into()
+                ))
+        ).padding(10).style(|_|
+            iced::widget::container::Style {
+                background: Some(Background::Color(Colour::foreground())),
+                text_color: None,
+                border: Border::default().rounded(10),
+                shadow: Shadow::default(),
+                snap: false
+            }
+        ))
+    }
+
+    fn petname(&self, peer: &EndpointId) -> Option<&String> {
+        self.contacts_page.as_ref().and_then(|page| page.book().petname(peer))
+    }
+
+    /// A chat is named by the peer's petname if it is a contact, then by its username, then by its abbreviated EndpointId.
+    fn chat_name(&self, peer: &EndpointId, username: Option<&String>) -> String {
+        self.petname(peer).or(username).cloned().unwrap_or_else(|| peer.fmt_short().to_string())
+    }
+
+    /// Our node ID, with buttons to copy it or an invite carrying our addresses, and a QR code for a peer to scan, shown while the identity panel is open.
+    fn identity_panel(&self) -> Option<Container<'_, Message>> {
+        if !self.show_identity { return None; }
+
+        let local_id = match self.networking.as_ref() {
+            Some(local) => local.ep().id().to_string(),
+            None => return Some(Container::new(text("Networking not initialised.").size(12).color(Colour::loading())))
+        };
+
+        Some(Container::new(
+            Column::new().spacing(10)
+                .push(text(local_id).size(12).color(Colour::text()))
+                .push(
+                    Row::new().spacing(5)
+                        .push(button(text("COPY ID").size(12)).on_press(Global::CopyIdentity.into()).style(button_style).width(Length::Fill))
+                        .push(button(text("COPY INVITE").size(12)).on_press(Global::CopyInvite.into()).style(button_style).width(Length::Fill))
+                )
+                .push(self.identity_qr.clone().map(|handle| svg(handle).width(Length::Fill)))
+        ).padding(10).style(|_|
+            iced::widget::container::Style {
+                background: Some(Background::Color(Colour::foreground())),
+                text_color: None,
+                border: Border::default().rounded(10),
+                shadow: Shadow::default(),
+                snap: false
+            }
+        ))
+    }
+}
+
+/// The count of unread packets shown beside a chat in the sidebar, hidden when there are none.
+fn notification_badge<'a>(notifications: usize) -> Option<Container<'a, Message>> {
+    match notifications {
+        0 => None,
+        other => Some(
+            Container::new(text(other).size(20f32).color(Colour::text()))
+                .padding(5)
+                .style(|_|
+                    iced::widget::container::Style {
+                        background: Some(Background::Color(Colour::error())),
+                        text_color: None,
+                        border: Border::default().rounded(5),
+                        shadow: Shadow::default(),
+                        snap: false
+                    }
+                )
+        )
+    }
+}
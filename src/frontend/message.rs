@@ -1,8 +1,8 @@
 use std::{path::PathBuf, sync::Arc};
 
-use iroh::EndpointId;
+use iroh::{EndpointAddr, EndpointId};
 
-use crate::{error::{Error, Res}, frontend::{notification::Notification, pages::{Pages, add_chat_page::AddChatMessage, chat_page::ChatMessage}}, networking::{packet::{Packet, TrackedPacket}, server::Local}};
+use crate::{backend::chat::ChatId, error::{Error, Res}, frontend::{notification::Notification, pages::{Pages, add_chat_page::AddChatMessage, chat_page::ChatMessage, contacts_page::ContactMessage, vault_page::VaultMessage}}, networking::{group::GroupId, packet::{Packet, TrackedPacket}, server::Local}};
 
 macro_rules! message_enum {
     (
@@ -39,28 +39,77 @@ pub enum Global {
         None,
 
         // Load
+        Start,                                     // History and networking are loaded once Rift is unlocked, straight away if no passphrase is set.
         LoadNetworking,
+        LoadHistory,
         LoadSuccess(Arc<Local>),
-        LoadImage(usize, Res<Option<PathBuf>>),
+        LoadImage(ChatId, Res<Option<PathBuf>>),
 
         // Interface with backend
-        Send(TrackedPacket),                       // Send a packet to the given stable_id, requires a Connection to the foreign node to exist already.
-        Packet(usize, Packet),                     // When a new packet is received, this is the first message prior to it being relayed to page specific needs.
+        Send(TrackedPacket),                       // Send a packet to the given peer, requires a Connection to the foreign node to exist already.
+        Packet(EndpointId, Packet),                // When a new packet is received, this is the first message prior to it being relayed to page specific needs.
         Connect(EndpointId),
-        ChatConnected(usize),
+        ConnectTo(EndpointAddr),                   // Dial a peer at the addresses it gave, as in an invite, rather than finding them through discovery.
+        ChatConnected(EndpointId),
+        ChatDisconnected(EndpointId, String),
+        ChatReconnecting(EndpointId, u32),
+        ChatRestored(EndpointId, Option<String>),  // A chat was loaded from history, prior to any connection with the peer.
+        GroupJoined(GroupId, String, Vec<EndpointId>),  // A group was created, joined, restored or changed, with its current members.
+        ConnectionRequested(EndpointId),           // A peer dialed us and is waiting for our approval.
+        ConnectionWithdrawn(EndpointId),
         NewUsername,
         
         // Frontend
         UsernameInput(String),
-        BindUsernameToId(usize, String),
-        AddNotification(usize),
-        ClearNotifications(usize),
+        BindUsernameToId(EndpointId, String),
+        AddNotification(ChatId),
+        ClearNotifications(ChatId),
+        DismissNotification(u64),
+        ToggleNotificationDetails(u64),
+        ToggleNotificationHistory,
+
+        // Contacts
+        OpenChat(EndpointId),                      // Switch to the chat with a peer, connecting to it if it is not online.
+
+        // Identity
+        ToggleIdentity,
+        CopyIdentity,
+        CopyInvite,
+        RotateIdentity,
+        PickIdentityImport,
+        PickIdentityExport,
+        ImportIdentity(Res<Option<PathBuf>>),
+        ExportIdentity(Res<Option<PathBuf>>),
+        ReloadNetworking,
+
+        // Settings
+        ToggleReadReceipts,
+
+        // Access
+        CycleAccessPolicy,
+        AcceptConnection(EndpointId),
+        RejectConnection(EndpointId),
+        Block(EndpointId),
+        Unblock(EndpointId),
+        ResetBlocklist,                            // Replace a blocklist that could not be read, forgetting whoever it held.
+
+        // Vault
+        Unlocked,
+        Lock,
+        Activity,                                  // The user pressed a key or a mouse button, which postpones the auto-lock.
+        CheckAutoLock,
+        CycleAutoLock,
+
+        // Window
+        Focus(bool),                               // The window gained or lost focus, without which no chat is being read.
 }
 
 message_enum! {
     pub enum Message {
         Global,
         AddChatMessage,
-        ChatMessage
+        ChatMessage,
+        ContactMessage,
+        VaultMessage
     }
 }
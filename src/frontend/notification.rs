@@ -1,6 +1,13 @@
-use crate::error::Error;
+use std::time::Duration;
 
-#[derive(Debug, Clone, Copy)]
+use iced::Color;
+
+use crate::{error::{ChatError, Error}, frontend::widget::Colour, networking::error::NetworkError};
+
+// How long a notification that needs no action stays on screen.
+const EXPIRY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NotificationType {
     Error,
     Warning,
@@ -8,11 +15,12 @@ pub enum NotificationType {
     Success
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Notification {
     kind: NotificationType,
     heading: String,
-    body: Option<String>
+    body: Option<String>,
+    details: Option<String>     // Technical detail for diagnosing the problem, shown only on request.
 }
 
 impl Notification {
@@ -20,7 +28,26 @@ impl Notification {
         Notification {
             kind: NotificationType::Success,
             heading,
-            body: None
+            body: None,
+            details: None
+        }
+    }
+
+    pub fn info(heading: String) -> Notification {
+        Notification {
+            kind: NotificationType::Info,
+            heading,
+            body: None,
+            details: None
+        }
+    }
+
+    pub fn warning(heading: String, body: Option<String>) -> Notification {
+        Notification {
+            kind: NotificationType::Warning,
+            heading,
+            body,
+            details: None
         }
     }
 
@@ -28,18 +55,65 @@ impl Notification {
         Notification {
             kind: NotificationType::Error,
             heading,
-            body: None
+            body: None,
+            details: None
+        }
+    }
+
+    pub fn heading(&self) -> &str {
+        &self.heading
+    }
+
+    pub fn body(&self) -> Option<&str> {
+        self.body.as_deref()
+    }
+
+    pub fn details(&self) -> Option<&str> {
+        self.details.as_deref()
+    }
+
+    pub fn colour(&self) -> Color {
+        match self.kind {
+            NotificationType::Error => Colour::error(),
+            NotificationType::Warning => Colour::warning(),
+            NotificationType::Info => Colour::text(),
+            NotificationType::Success => Colour::success()
+        }
+    }
+
+    /// How long the notification is shown before it dismisses itself. Errors and warnings stay until dismissed.
+    pub fn expiry(&self) -> Option<Duration> {
+        match self.kind {
+            NotificationType::Info | NotificationType::Success => Some(EXPIRY),
+            NotificationType::Error | NotificationType::Warning => None
         }
     }
 }
 
 impl From<Error> for Notification {
     fn from(error: Error) -> Notification {
+        let details = match std::error::Error::source(&error) {
+            Some(source) => format!("{source}\n{error:?}"),
+            None => format!("{error:?}")
+        };
+
         Notification {
-            kind: NotificationType::Error,
-            heading: format!("{error:?}"),
-            body: None
+            kind: severity(&error),
+            heading: error.to_string(),
+            body: error.advice().map(String::from),
+            details: Some(details)
         }
     }
 }
 
+/// Problems that sort themselves out, or that only need the user to try again, are not shown as errors.
+fn severity(error: &Error) -> NotificationType {
+    match error {
+        Error::ConnectionError(_) | Error::ChannelError(_) | Error::WriteError(_) | Error::ClosedStream(_)
+            | Error::ReadToEndError(_) | Error::ReadExactError(_) | Error::ReadError(_) => NotificationType::Warning,
+        Error::NetworkError(error) if matches!(**error, NetworkError::PeerOffline | NetworkError::TransferInterrupted) => NotificationType::Warning,
+        Error::ChatError(error) if matches!(**error, ChatError::NoFileSelected) => NotificationType::Info,
+        _ => NotificationType::Error
+    }
+}
+
@@ -3,17 +3,20 @@ use std::str::FromStr;
 use iced::{Background, Border, Shadow, Task, widget::{Column, Container, button, text_input}};
 use iroh::EndpointId;
 
-use crate::frontend::{application::Page, message::{Global, Message}, notification::Notification, widget::Colour};
+use crate::{frontend::{application::Page, message::{Global, Message}, notification::Notification, pages::chat_page::ChatMessage, widget::{Colour, button_style}}, networking::{error::NetworkError, ticket::Ticket}};
 
 #[derive(Default)]
 pub struct AddChatPage {
-    input: String
+    input: String,
+    group_input: String
 }
 
 #[derive(Clone, Debug)]
 pub enum AddChatMessage {
     Input(String),
-    Submit
+    Submit,
+    GroupInput(String),
+    CreateGroup
 }
 
 impl Page for AddChatPage {
@@ -21,7 +24,7 @@ impl Page for AddChatPage {
         Container::new(
             Column::new().padding(10).spacing(10)
                 .push(
-                    text_input("Enter NODE ID.", &self.input)
+                    text_input("Enter NODE ID or INVITE.", &self.input)
                         .on_submit(AddChatMessage::Submit.into())
                         .on_input_maybe(Some(|new_content| AddChatMessage::Input(new_content).into()))
                         .style(|_,_| iced::widget::text_input::Style {
@@ -51,6 +54,24 @@ impl Page for AddChatPage {
                                 }
                             )
                 )
+                .push(
+                    text_input("Enter GROUP NAME.", &self.group_input)
+                        .on_submit(AddChatMessage::CreateGroup.into())
+                        .on_input(|new_content| AddChatMessage::GroupInput(new_content).into())
+                        .style(|_,_| iced::widget::text_input::Style {
+                            background: Background::Color(Colour::foreground()),
+                            border: Border::default().rounded(10),
+                            icon: Colour::accent(),
+                            placeholder: Colour::loading(),
+                            value: Colour::text(),
+                            selection: Colour::accent()
+                        })
+                )
+                .push(
+                    button("Create group")
+                        .on_press(AddChatMessage::CreateGroup.into())
+                        .style(button_style)
+                )
         )
     }
 
@@ -61,12 +82,27 @@ impl Page for AddChatPage {
                     self.input = new_content;
                     Task::none()
                 }
+                // Either a bare node ID, which is found through discovery, or an invite carrying the peer's addresses.
                 AddChatMessage::Submit => {
-                    match EndpointId::from_str(&std::mem::take(&mut self.input)) {
-                        Ok(valid_id) => Task::done(Global::Connect(valid_id).into()),
-                        Err(_) => Task::done(Global::Notify(Notification::error(String::from("Invalid ID"))).into())
+                    let input = std::mem::take(&mut self.input);
+                    if let Ok(valid_id) = EndpointId::from_str(input.trim()) {
+                        return Task::done(Global::Connect(valid_id).into());
                     }
+
+                    match Ticket::from_str(&input) {
+                        Ok(ticket) if ticket.is_expired() => Task::done(Global::Error(NetworkError::ExpiredTicket.into()).into()),
+                        Ok(ticket) => Task::batch(vec![
+                            Task::done(Global::Notify(Notification::info(format!("Connecting to {}...", ticket.name.clone().unwrap_or_else(|| ticket.id().fmt_short().to_string())))).into()),
+                            Task::done(Global::ConnectTo(ticket.addr).into())
+                        ]),
+                        Err(error) => Task::done(Global::Error(error).into())
+                    }
+                }
+                AddChatMessage::GroupInput(new_content) => {
+                    self.group_input = new_content;
+                    Task::none()
                 }
+                AddChatMessage::CreateGroup => Task::done(ChatMessage::CreateGroup(std::mem::take(&mut self.group_input)).into())
             },
             _ => Task::none()
         }
@@ -1,72 +1,590 @@
-use std::{collections::HashMap, mem::take, path::PathBuf};
-use iced::{Background, Border, Length, Shadow, Task, widget::{Column, Container, Row, Scrollable, button, scrollable::{AutoScroll, Rail, Scroller}, text, text_input}};
+use std::{collections::{HashMap, HashSet}, mem::take, num::NonZeroU64, path::PathBuf, str::FromStr, time::{Duration, Instant}};
+use iced::{Background, Border, Element, Length, Shadow, Task, widget::{Column, Container, Id, Row, Scrollable, button, operation::{RelativeOffset, snap_to}, scrollable::{AutoScroll, Rail, Scroller}, text, text_input}};
 
-use crate::{backend::chat::Chat, error::{Error, Res}, frontend::{application::Page, message::{Global, Message}, widget::Colour}, networking::packet::{Packet, PacketType, TrackedPacket, TrackedPacketResponse}};
+use iroh::EndpointId;
+
+use crate::{backend::{chat::{Chat, ChatId, PacketState}, contacts::Contact, history::{History, Record}, outbox::Outbox}, error::{ChatError, Error, Res}, frontend::{application::Page, message::{Global, Message}, notification::Notification, pages::{Pages, contacts_page::ContactMessage}, widget::{Colour, button_style}}, networking::{connection_manager::Distribution, group::{GroupId, Membership}, identity::SafetyNumber, packet::{MessageId, Packet, PacketType, Stamp, TrackedPacket, TrackedPacketResponse}, transfer::TransferEvent}, util::clock};
 
 #[derive(Debug, Clone)]
 pub enum ChatMessage {
-    SetActiveChat(usize),
-    ReceiveForeignPacket(usize, Packet),
-    SentLocalPacket(usize, Packet),
+    LoadHistory,
+    SetLocalId(EndpointId),
+    SetActiveChat(ChatId),
+    SetOnScreen(bool),
+    ReceiveForeignPacket(EndpointId, Packet),
     UsernameUpdate(String),
 
+    // Whether to tell peers when we have read their packets
+    SetReadReceipts(bool),
+
+    // Our contacts, whose petnames are shown in place of their usernames, and whose verification is shown beside them
+    SetContacts(HashMap<EndpointId, Contact>),
+    ToggleSafetyNumber,
+
     // Update the message box (paste, type)
     UpdateMessageBox(String),
 
+    // A peer has not said it is still typing for a while
+    ExpireTyping(ChatId, EndpointId),
+
     // Send the current message box contents to the current chat
     Send,
 
-    // Indicators on packet state
-    PacketConfirmed(usize, usize),
-    PacketFailed(usize, usize),
+    // Amend a packet we sent earlier. Editing loads the message into the message box, and the next Send replaces it.
+    EditPacket(ChatId, usize),
+    CancelEdit,
+    DeletePacket(ChatId, usize),
+
+    // Reply to an earlier packet. The reply context is attached to the next message sent from the message box.
+    ReplyTo(ChatId, usize),
+    CancelReply,
+    JumpTo(usize),
+
+    // Toggle the emoji palette beneath a packet, and toggle our own reaction to it.
+    ToggleReactions(ChatId, usize),
+    React(ChatId, usize, String),
+
+    // Indicators on packet state, for each recipient of a packet
+    PacketConfirmed(ChatId, EndpointId, usize),
+    PacketFailed(ChatId, EndpointId, usize),
+
+    // Outbox of undelivered packets
+    FlushOutbox(EndpointId),
+    ResumeTransfers(EndpointId),
+    RetryPacket(ChatId, usize),
+    DiscardPacket(ChatId, usize),
+
+    // Groups
+    CreateGroup(String),
+    UpdateMemberInput(String),
+    AddMember,
+    LeaveGroup,
+    SyncGroups(EndpointId),
 
     // Pick image
     PickImage,
-    ImagePicked(usize, PathBuf)
+    ImagePicked(ChatId, PathBuf),
+
+    // Pick a file, which is hashed off the GUI thread before being sent
+    PickFile,
+    FilePicked(ChatId, Res<Option<PathBuf>>),
+    FileHashed(ChatId, Res<Packet>),
+
+    // Progress of file transfers in either direction
+    Transfer(TransferEvent)
 }
 
+const CONVERSATION: &str = "conversation";
+
+// How often we tell peers that we are still typing, and how long they wait to hear it again before assuming we have stopped.
+const TYPING_THROTTLE: Duration = Duration::from_secs(3);
+const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+
 #[derive(Default)]
 pub struct ChatPage {
-    active_chat: usize,
-    chats: HashMap<usize, Chat>,
+    local_id: Option<EndpointId>,
+    active_chat: Option<ChatId>,
+    on_screen: bool,                        // Whether the active chat is shown in a focused, unlocked window.
+    chats: HashMap<ChatId, Chat>,
     message_box: String,
-    username: String
+    editing: Option<(ChatId, usize)>,       // The message being edited, whose replacement is the message box.
+    replying: Option<(ChatId, usize)>,      // The packet the message box replies to.
+    reacting: Option<(ChatId, usize)>,      // The packet the emoji palette is open beneath.
+    typing_sent: Option<(ChatId, Instant)>,             // The chat we last told we were typing in, and when.
+    typing: HashMap<(ChatId, EndpointId), Instant>,     // The peers typing in each chat, and when they last said so.
+    member_input: String,
+    username: String,
+    read_receipts: bool,                    // Whether peers are told when we have read their packets.
+    contacts: HashMap<EndpointId, Contact>,
+    show_safety_number: bool,               // Whether the safety number of the active direct chat is shown.
+    history: Option<History>,
+    outbox: Option<Outbox>,
+    transfers: HashMap<MessageId, u64>      // Bytes transferred so far for each file in flight.
 }
 
 impl ChatPage {
 
-    pub fn make_empty(&mut self, foreign_stable_id: usize) {
-        self.chats.insert(foreign_stable_id, Chat::new());
+    /// Ensure a chat exists for a peer. A reconnecting peer keeps its existing chat.
+    pub fn make_empty(&mut self, peer: EndpointId) {
+        self.chats.entry(ChatId::Direct(peer)).or_insert_with(Chat::new);
+    }
+
+    /// Persist a record to the chat's history before applying it to the chat.
+    /// Packets from peers that do not stamp them are stamped on receipt.
+    fn record(&mut self, chat: ChatId, record: Record) -> Res<()> {
+        let record = match record {
+            Record::Packet { local, packet, state } => Record::Packet { local, packet: self.stamp(chat, packet), state },
+            Record::Member { author, packet, state } => Record::Member { author, packet: self.stamp(chat, packet), state },
+            record => record
+        };
+
+        if let Some(history) = self.history.as_ref() {
+            history.append(chat, &record)?;
+        }
+
+        self.chats.entry(chat).or_insert_with(Chat::new).apply(record);
+        Ok(())
+    }
+
+    /// Stamp a packet with the chat's next logical clock and the current time, unless it is already stamped.
+    fn stamp(&self, chat: ChatId, packet: Packet) -> Packet {
+        if packet.stamp.is_some() { return packet; }
+        let clock = self.chats.get(&chat).map(Chat::next_clock).unwrap_or(NonZeroU64::MIN);
+        packet.stamped(Stamp { clock, sent: NonZeroU64::new(clock::now()) })
     }
 
     /// Function to record a packet exchange into the GUI.
-    fn add_packet(&mut self, foreign_stable_id: usize, local: bool, packet: Packet) -> Res<()> {
-        match self.chats.get_mut(&foreign_stable_id) {
-            Some(chat) => Ok(chat.add_packet(local, packet)),
-            None => {
-                let mut chat = Chat::new();
-                chat.add_packet(local, packet);
-                self.chats.insert(foreign_stable_id, chat);
-                Ok(())
+    fn add_packet(&mut self, chat: ChatId, local: bool, packet: Packet) -> Res<()> {
+        let state = if local { PacketState::Unknown } else { PacketState::Verified };
+        self.record(chat, Record::Packet { local, packet, state })
+    }
+
+    /// Any change of state begins or ends an attempt at delivery, so progress from a previous attempt is cleared.
+    fn update_state(&mut self, chat: ChatId, index: usize, state: PacketState) -> Res<()> {
+        if let Some(packet) = self.chats.get(&chat).and_then(|chat| chat.packet(index)) {
+            self.transfers.remove(&packet.id);
+        }
+
+        self.record(chat, Record::State { index, state })
+    }
+
+    /// Update the state of a packet for one of its recipients. In a group, the packet's overall state follows from those of its members.
+    fn update_delivery(&mut self, chat: ChatId, member: EndpointId, index: usize, state: PacketState) -> Res<()> {
+        match chat {
+            ChatId::Direct(_) => self.update_state(chat, index, state),
+            ChatId::Group(_) => {
+                if let Some(packet) = self.chats.get(&chat).and_then(|chat| chat.packet(index)) {
+                    self.transfers.remove(&packet.id);
+                }
+
+                self.record(chat, Record::Delivery { index, member, state })
             }
         }
     }
+
+    /// Send a packet that has been recorded at the given index of a chat, reporting its delivery to each recipient back to that index.
+    fn track(chat: ChatId, index: usize, packet: Packet, distribution: Distribution) -> Task<Message> {
+        let (tracked_packet, receiver) = TrackedPacket::distribute(distribution.clone(), packet);
+        let respond = move |response| match response {
+            TrackedPacketResponse::Confirmed(member) => ChatMessage::PacketConfirmed(chat, member, index),
+            TrackedPacketResponse::Failed(member) => ChatMessage::PacketFailed(chat, member, index)
+        }.into();
+
+        Task::batch(vec![
+            Task::done(Global::Send(tracked_packet).into()),
+            match distribution {
+                Distribution::Peer(peer) => Task::future(async move { receiver.recv().await }).map(move |message| match message {
+                    Ok(response) => respond(response),
+                    Err(_) => ChatMessage::PacketFailed(chat, peer, index).into()
+                }),
+                Distribution::Group(_) => Task::run(receiver, respond)
+            }
+        ])
+    }
+
+    /// Address a packet to every recipient of a chat, unless it is a group we have left.
+    fn address(&self, chat: ChatId, packet: Packet) -> Option<(Packet, Distribution)> {
+        match chat {
+            ChatId::Direct(peer) => Some((packet, Distribution::Peer(peer))),
+            ChatId::Group(group) => self.chats.get(&chat).filter(|chat| !chat.has_left()).and_then(Chat::membership)
+                .map(|membership| (packet.in_group(group), Distribution::Group(membership.members.clone())))
+        }
+    }
+
+    /// Record and send a new local packet to every recipient of a chat.
+    /// The packet is recorded before anything else can be, so that its delivery is reported back to the index it landed at.
+    fn send_local(&mut self, chat: ChatId, packet: Packet) -> Task<Message> {
+        let (packet, distribution) = match self.address(chat, self.stamp(chat, packet)) {
+            Some(addressed) => addressed,
+            None => return Task::done(Global::Error(ChatError::LeftGroup.into()).into())
+        };
+
+        let index = self.chats.get(&chat).map(Chat::get_unique_id).unwrap_or_default();
+        match self.add_packet(chat, true, packet.clone()) {
+            Ok(()) => Self::track(chat, index, packet, distribution),
+            Err(error) => Task::done(Global::Error(error).into())
+        }
+    }
+
+    /// Tell the recipients of the active chat that we are typing, at most once per TYPING_THROTTLE,
+    /// or that we have stopped once the message box is emptied.
+    fn announce_typing(&mut self) -> Task<Message> {
+        let chat = match self.active_chat {
+            Some(chat) => chat,
+            None => return Task::none()
+        };
+
+        if self.message_box.is_empty() { return self.stop_typing(); }
+        if self.typing_sent.is_some_and(|(sent, at)| sent == chat && at.elapsed() < TYPING_THROTTLE) { return Task::none(); }
+
+        let stopped = match self.typing_sent.is_some_and(|(sent, _)| sent != chat) {
+            true => self.stop_typing(),
+            false => Task::none()
+        };
+
+        self.typing_sent = Some((chat, Instant::now()));
+        Task::batch(vec![stopped, self.send_typing(chat, true)])
+    }
+
+    /// Tell the recipients of the chat we were last typing in that we have stopped.
+    fn stop_typing(&mut self) -> Task<Message> {
+        match self.typing_sent.take() {
+            Some((chat, _)) => self.send_typing(chat, false),
+            None => Task::none()
+        }
+    }
+
+    /// Typing packets are neither tracked nor recorded.
+    fn send_typing(&self, chat: ChatId, typing: bool) -> Task<Message> {
+        match self.address(chat, Packet::typing(typing)) {
+            Some((packet, distribution)) => Task::done(Global::Send(TrackedPacket::distribute(distribution, packet).0).into()),
+            None => Task::none()
+        }
+    }
+
+    /// Note whether a peer is typing in a chat. Without word that they have stopped, they are assumed to have after TYPING_TIMEOUT.
+    fn receive_typing(&mut self, chat: ChatId, author: EndpointId, typing: bool) -> Task<Message> {
+        match typing {
+            true => {
+                self.typing.insert((chat, author), Instant::now());
+                Task::perform(tokio::time::sleep(TYPING_TIMEOUT), move |_| ChatMessage::ExpireTyping(chat, author).into())
+            }
+            false => {
+                self.typing.remove(&(chat, author));
+                Task::none()
+            }
+        }
+    }
+
+    /// Send a queued packet again to each of the given recipients.
+    fn retry(&mut self, chat: ChatId, index: usize, members: Vec<EndpointId>) -> Task<Message> {
+        let id = match self.chats.get(&chat).and_then(|chat| chat.packet(index)) {
+            Some(packet) => packet.id,
+            None => return Task::none()
+        };
+
+        let mut tasks = Vec::new();
+        for member in members {
+            let packet = match self.outbox.as_ref().and_then(|outbox| outbox.get(member, id)) {
+                Some((_, packet)) => packet,
+                None => continue
+            };
+
+            tasks.push(match self.update_delivery(chat, member, index, PacketState::Unknown) {
+                Ok(()) => Self::track(chat, index, packet, Distribution::Peer(member)),
+                Err(error) => Task::done(Global::Error(error).into())
+            });
+        }
+
+        Task::batch(tasks)
+    }
+
+    /// The recipients of a local packet that are awaiting a retry: the peer of a direct chat, or the members of a group it failed to reach.
+    fn failed_recipients(&self, chat: ChatId, index: usize) -> Vec<EndpointId> {
+        match chat {
+            ChatId::Direct(peer) => vec![peer],
+            ChatId::Group(_) => self.chats.get(&chat).map(|chat| chat.failed_members(index)).unwrap_or_default()
+        }
+    }
+
+    /// Every chat shared with a peer: its direct chat, and any group it is a member of.
+    fn chats_with(&self, peer: EndpointId) -> Vec<ChatId> {
+        self.chats.iter()
+            .filter(|(id, chat)| **id == ChatId::Direct(peer) || chat.membership().is_some_and(|membership| membership.contains(&peer)))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Find a file exchanged with a peer by its message id, either one we sent or one the peer sent us.
+    fn locate_file(&self, peer: EndpointId, id: MessageId, local: bool) -> Option<(ChatId, usize)> {
+        self.chats_with(peer).into_iter().find_map(|chat| self.chats.get(&chat).and_then(|entry| entry.find_file(id, local, peer)).map(|index| (chat, index)))
+    }
+
+    /// Settle the state of an incoming file once its transfer has ended.
+    fn finish_transfer(&mut self, peer: EndpointId, id: MessageId, state: PacketState) -> Task<Message> {
+        match self.locate_file(peer, id, false).map(|(chat, index)| self.update_state(chat, index, state)) {
+            Some(Err(error)) => Task::done(Global::Error(error).into()),
+            _ => Task::none()
+        }
+    }
+
+    /// Raise a notification for a chat, unless it is the one being viewed, in which case its packets have been read.
+    fn notify(&mut self, chat: ChatId) -> Task<Message> {
+        if Some(chat) != self.active_chat || !self.on_screen {
+            Task::done(Global::AddNotification(chat).into())
+        } else {
+            self.mark_read(chat)
+        }
+    }
+
+    /// Mark every unread foreign packet in a chat as read, telling its recipients so unless read receipts are turned off.
+    /// Nothing is read unless the chat is on screen. Receipts are neither tracked nor recorded.
+    fn mark_read(&mut self, chat: ChatId) -> Task<Message> {
+        if Some(chat) != self.active_chat || !self.on_screen { return Task::none(); }
+        let unread = self.chats.get(&chat).map(Chat::unread).unwrap_or_default();
+        if unread.is_empty() { return Task::none(); }
+
+        if let Err(error) = unread.iter().try_for_each(|(index, _)| self.record(chat, Record::State { index: *index, state: PacketState::Read })) {
+            return Task::done(Global::Error(error).into());
+        }
+
+        let ids: Vec<MessageId> = unread.into_iter().map(|(_, id)| id).collect();
+        match self.read_receipts.then(|| self.address(chat, Packet::read(&ids))).flatten() {
+            Some((packet, distribution)) => Task::done(Global::Send(TrackedPacket::distribute(distribution, packet).0).into()),
+            None => Task::none()
+        }
+    }
+
+    /// A peer has read some of the packets we sent to a chat. Only packets it has confirmed receiving can be read.
+    fn receive_read(&mut self, chat: ChatId, author: EndpointId, ids: Vec<MessageId>) -> Task<Message> {
+        let indices: Vec<usize> = match self.chats.get(&chat) {
+            Some(entry) => ids.into_iter()
+                .filter_map(|id| entry.find(id))
+                .filter(|index| entry.is_local(*index) && entry.delivery(*index, author) == Some(PacketState::Verified))
+                .collect(),
+            None => return Task::none()
+        };
+
+        let updated = indices.into_iter().try_for_each(|index| match chat {
+            ChatId::Direct(_) => self.update_state(chat, index, PacketState::Read),
+            ChatId::Group(_) => self.update_delivery(chat, author, index, PacketState::Read)
+        });
+
+        match updated {
+            Ok(()) => Task::none(),
+            Err(error) => Task::done(Global::Error(error).into())
+        }
+    }
+
+    /// Handle a packet addressed to a group. Membership changes are applied, while anything else is only
+    /// accepted from a current member of a group we are still in.
+    fn receive_group_packet(&mut self, author: EndpointId, group: GroupId, packet: Packet) -> Task<Message> {
+        let chat = ChatId::Group(group);
+
+        match packet.kind {
+
+            // A member has added us, or someone else, to a group. Their member list is merged with ours.
+            // Anyone may invite us to a group we are not in, but only a current member may add to one we are in.
+            PacketType::GroupAdd => {
+                let existing = self.chats.get(&chat);
+
+                // Having left, we remind the sender rather than rejoining.
+                if existing.is_some_and(Chat::has_left) {
+                    return Task::done(Global::Send(TrackedPacket::new(author, Packet::group_leave(group)).0).into());
+                }
+                if existing.and_then(Chat::membership).is_some_and(|membership| !membership.contains(&author)) {
+                    return Task::none();
+                }
+
+                let announced = match packet.membership() {
+                    Some(membership) => membership,
+                    None => return Task::none()
+                };
+
+                let mut changed = existing.and_then(Chat::membership).is_none();
+                let mut membership = existing.and_then(Chat::membership).cloned().unwrap_or_else(|| Membership::new(announced.name));
+                for member in announced.members.into_iter().chain([author]) {
+                    if Some(member) != self.local_id {
+                        changed |= membership.add(member);
+                    }
+                }
+
+                if !changed { return Task::none(); }
+                match self.record(chat, Record::Membership(membership.clone())) {
+                    Ok(()) => Task::done(Global::GroupJoined(group, membership.name, membership.members).into()),
+                    Err(error) => Task::done(Global::Error(error).into())
+                }
+            }
+
+            PacketType::GroupLeave => {
+                let mut membership = match self.chats.get(&chat).and_then(Chat::membership).filter(|membership| membership.contains(&author)) {
+                    Some(membership) => membership.clone(),
+                    None => return Task::none()
+                };
+
+                membership.remove(&author);
+                let name = self.display_name(ChatId::Direct(author));
+                match self.record(chat, Record::Membership(membership.clone())) {
+                    Ok(()) => Task::done(Global::Notify(Notification::warning(format!("{name} left {}", membership.name), None)).into()),
+                    Err(error) => Task::done(Global::Error(error).into())
+                }
+            }
+
+            // Packets from outside a group, or from a group we have left, are dropped.
+            _ => match self.chats.get(&chat).filter(|entry| !entry.has_left() && entry.membership().is_some_and(|membership| membership.contains(&author))) {
+                Some(_) if packet.kind == PacketType::Typing => self.receive_typing(chat, author, packet.is_typing().unwrap_or(false)),
+                Some(_) if packet.kind == PacketType::Read => self.receive_read(chat, author, packet.read_ids()),
+                Some(_) => {
+                    self.typing.remove(&(chat, author));
+                    let amendment = packet.target().is_some();
+                    let task = match self.record(chat, Record::Member { author, packet, state: PacketState::Verified }) {
+                        Ok(()) => Task::none(),
+                        Err(error) => Task::done(Global::Notify(error.into()).into())
+                    };
+
+                    match amendment {
+                        true => task,
+                        false => Task::batch(vec![task, self.notify(chat)])
+                    }
+                }
+                None => Task::none()
+            }
+        }
+    }
+
+    /// The name we know each peer by: its petname if it is a contact, otherwise the username it sent us.
+    fn names(&self) -> HashMap<EndpointId, String> {
+        self.chats.iter()
+            .filter_map(|(id, chat)| match id {
+                ChatId::Direct(peer) => chat.foreign_username().map(|username| (*peer, username.clone())),
+                ChatId::Group(_) => None
+            })
+            .chain(self.contacts.iter().map(|(peer, contact)| (*peer, contact.petname.clone())))
+            .collect()
+    }
+
+    /// The contacts whose safety number we have confirmed with them. Nobody is verified until our own EndpointId is known.
+    fn verified(&self) -> HashSet<EndpointId> {
+        let local = match self.local_id {
+            Some(local) => local,
+            None => return HashSet::new()
+        };
+
+        self.contacts.iter()
+            .filter(|(peer, contact)| contact.is_verified(&SafetyNumber::between(&local, peer)))
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
+    /// Who is typing in the active chat.
+    fn typing_indicator(&self) -> Option<Row<'_, Message>> {
+        let names = self.names();
+        let typing: Vec<String> = self.typing.keys()
+            .filter(|(chat, _)| Some(*chat) == self.active_chat)
+            .map(|(_, peer)| names.get(peer).cloned().unwrap_or_else(|| peer.fmt_short().to_string()))
+            .collect();
+
+        match typing.len() {
+            0 => None,
+            1 => Some(Row::new().padding(5).push(text(format!("{} is typing…", typing[0])).size(12).color(Colour::loading()))),
+            _ => Some(Row::new().padding(5).push(text(format!("{} are typing…", typing.join(", "))).size(12).color(Colour::loading())))
+        }
+    }
+
+    /// Our own username, as shown beside the packets we send.
+    fn local_name(&self) -> String {
+        match self.username.is_empty() {
+            true => String::from("LOCAL"),
+            false => self.username.clone()
+        }
+    }
+
+    /// The petname or username of a peer if either is known, otherwise an abbreviated EndpointId. A group is known by its name.
+    fn display_name(&self, chat: ChatId) -> String {
+        match chat {
+            ChatId::Direct(peer) => self.names().remove(&peer).unwrap_or_else(|| peer.fmt_short().to_string()),
+            ChatId::Group(_) => self.chats.get(&chat).and_then(Chat::membership).map(|membership| membership.name.clone()).unwrap_or_default()
+        }
+    }
+
+    /// The name of the peer in the active direct chat, whether it is verified, and the safety number with which to verify it.
+    fn direct_header(&self, chat: ChatId) -> Option<Column<'_, Message>> {
+        let (peer, local) = match (chat, self.local_id) {
+            (ChatId::Direct(peer), Some(local)) => (peer, local),
+            _ => return None
+        };
+
+        let name = self.display_name(chat);
+        let number = SafetyNumber::between(&local, &peer);
+        let contact = self.contacts.get(&peer);
+        let verified = contact.is_some_and(|contact| contact.is_verified(&number));
+
+        Some(
+            Column::new().spacing(10).padding(10)
+                .push(
+                    Row::new().spacing(10)
+                        .push(text(name.clone()).size(20).color(Colour::accent()))
+                        .push(match verified {
+                            true => text("✔ Verified").size(15).color(Colour::success()),
+                            false => text("Not verified").size(15).color(Colour::loading())
+                        })
+                        .push(button(text(if self.show_safety_number { "HIDE SAFETY NUMBER" } else { "SAFETY NUMBER" }).size(15)).on_press(ChatMessage::ToggleSafetyNumber.into()).style(button_style))
+                )
+                .push(match self.show_safety_number {
+                    true => Some(
+                        Column::new().spacing(10)
+                            .push(text(format!("Compare this with {name} in person or over a call. If it matches theirs, nobody is impersonating either of you.")).size(12).color(Colour::loading()))
+                            .push(text(number.digits()).size(20).color(Colour::text()))
+                            .push(text(number.emoji()).size(20))
+                            .push(match (contact, verified) {
+                                (None, _) => Element::from(text(format!("Add {name} to your contacts to mark them as verified.")).size(12).color(Colour::loading())),
+                                (Some(_), false) => button(text("MARK AS VERIFIED").size(15)).on_press(ContactMessage::Verify(peer, Some(number)).into()).style(button_style).into(),
+                                (Some(_), true) => button(text("CLEAR VERIFICATION").size(15)).on_press(ContactMessage::Verify(peer, None).into()).style(button_style).into()
+                            })
+                    ),
+                    false => None
+                })
+        )
+    }
+
+    /// The name and members of the active group, with controls to add members or leave.
+    fn group_header<'a>(&'a self, chat: &'a Chat) -> Option<Column<'a, Message>> {
+        let membership = chat.membership()?;
+        let names = self.names();
+        let members = membership.members.iter()
+            .map(|member| names.get(member).cloned().unwrap_or_else(|| member.fmt_short().to_string()))
+            .collect::<Vec<String>>();
+
+        Some(
+            Column::new().spacing(10).padding(10)
+                .push(
+                    Row::new().spacing(10)
+                        .push(text(&membership.name).size(20).color(Colour::accent()))
+                        .push(text(match members.is_empty() {
+                            true => String::from("No other members"),
+                            false => members.join(", ")
+                        }).size(15).color(Colour::loading()))
+                )
+                .push(match chat.has_left() {
+                    true => Row::new().push(text("You have left this group.").color(Colour::loading())),
+                    false => Row::new().spacing(20)
+                        .push(
+                            text_input("Add member by NODE ID.", &self.member_input)
+                                .on_input(|new_value| ChatMessage::UpdateMemberInput(new_value).into())
+                                .on_submit(ChatMessage::AddMember.into())
+                                .size(15)
+                                .style(|_,_| iced::widget::text_input::Style {
+                                    background: Background::Color(Colour::foreground()),
+                                    border: Border::default().rounded(10),
+                                    icon: Colour::accent(),
+                                    placeholder: Colour::loading(),
+                                    value: Colour::text(),
+                                    selection: Colour::accent()
+                                })
+                        )
+                        .push(button(text("ADD").size(15)).on_press(ChatMessage::AddMember.into()).style(button_style))
+                        .push(button(text("LEAVE").size(15)).on_press(ChatMessage::LeaveGroup.into()).style(button_style))
+                })
+        )
+    }
 }
 
 impl Page for ChatPage {
     fn view(&self) -> Container<'_, Message> {
+        let active = self.active_chat.and_then(|id| self.chats.get(&id).map(|chat| (id, chat)));
+
         Container::new(
             Column::new().height(Length::Fill).padding(10)
+                .push(active.and_then(|(id, _)| self.direct_header(id)))
+                .push(active.and_then(|(_, chat)| self.group_header(chat)))
                 .push(
                     Scrollable::new(
-                        match self.chats.get(&self.active_chat) {
-                            Some(chat) => chat.view(match self.username.is_empty() {
-                                true => String::from("LOCAL"),
-                                false => self.username.clone()
-                            }),
+                        match active {
+                            Some((id, chat)) => chat.view(self.local_name(), id, &self.names(), &self.verified(), &self.transfers, self.reacting.filter(|(chat, _)| *chat == id).map(|(_, index)| index)),
                             None => Column::new()
                         }
                     )
+                    .id(Id::new(CONVERSATION))
                     .spacing(10)
                     .anchor_bottom()
                     .height(Length::FillPortion(10)).width(Length::FillPortion(1))
@@ -98,10 +616,23 @@ impl Page for ChatPage {
                             }
                         }
                     )
+                ).push(
+                    self.typing_indicator()
+                ).push(
+                    self.replying.filter(|(chat, _)| Some(*chat) == self.active_chat)
+                        .and_then(|(chat, index)| self.chats.get(&chat).map(|entry| (entry, index)))
+                        .map(|(entry, index)| Row::new().spacing(10).padding(5)
+                            .push(text(format!("Replying to {}: {}", entry.author_name(index, &self.local_name(), &self.names()), entry.preview(index))).size(12).color(Colour::loading()))
+                            .push(button(text("CANCEL").size(12)).on_press(ChatMessage::CancelReply.into()).style(button_style))
+                        )
                 ).push(
                     Row::new().spacing(20)
                         .push(
-                            text_input(&format!("Message {}", self.active_chat), &self.message_box)
+                            text_input(&match (self.active_chat, self.editing) {
+                                (_, Some(_)) => String::from("Edit message"),
+                                (Some(chat), None) => format!("Message {}", self.display_name(chat)),
+                                (None, None) => String::from("Message")
+                            }, &self.message_box)
                                 .on_input_maybe(Some(|new_value| ChatMessage::UpdateMessageBox(new_value).into()))
                                 .on_submit(ChatMessage::Send.into())
                                 .size(20)
@@ -113,6 +644,8 @@ impl Page for ChatPage {
                                     value: Colour::text(),
                                     selection: Colour::accent()
                                 })
+                        ).push(
+                            self.editing.map(|_| button(text!("CANCEL").size(15)).on_press(ChatMessage::CancelEdit.into()).style(button_style))
                         ).push(
                             button(text!("IMAGE").size(15))
                                 .on_press_with(|| ChatMessage::PickImage.into())
@@ -130,6 +663,10 @@ impl Page for ChatPage {
                                         snap: false
                                     }
                                 )
+                        ).push(
+                            button(text!("FILE").size(15))
+                                .on_press_with(|| ChatMessage::PickFile.into())
+                                .style(button_style)
                         )
                 )
         )
@@ -140,106 +677,409 @@ impl Page for ChatPage {
 
             Message::ChatMessage(message) => match message {
 
+                // Restore every conversation from disk, announcing each so it appears in the chat list.
+                // The history and the outbox are opened separately, so that neither failing hides the other.
+                ChatMessage::LoadHistory => {
+                    let (mut outbox, opened) = match Outbox::open() {
+                        Ok(outbox) => (Some(outbox), Task::none()),
+                        Err(error) => (None, Task::done(Global::Error(error).into()))
+                    };
+
+                    let history = match History::open() {
+                        Ok(history) => history,
+                        Err(error) => {
+                            self.outbox = outbox;
+                            return Task::batch(vec![opened, Task::done(Global::Error(error).into())]);
+                        }
+                    };
+
+                    let task = match history.load() {
+                        Ok((chats, damaged)) => {
+                            let restored: Vec<Task<Message>> = chats.into_iter().map(|(id, chat)| {
+
+                                // Packets that were still in flight when the app closed are now failed, and belong in the outbox.
+                                let (queued, restored) = match id {
+                                    ChatId::Direct(peer) => (
+                                        chat.failed_local().into_iter().try_for_each(|(index, packet)| outbox.as_mut().map_or(Ok(()), |outbox| outbox.enqueue(peer, index, packet))),
+                                        Global::ChatRestored(peer, chat.foreign_username().cloned())
+                                    ),
+                                    ChatId::Group(group) => (
+                                        chat.failed_deliveries().into_iter().try_for_each(|(member, index, packet)| outbox.as_mut().map_or(Ok(()), |outbox| outbox.enqueue(member, index, packet))),
+                                        Global::GroupJoined(
+                                            group,
+                                            chat.membership().map(|membership| membership.name.clone()).unwrap_or_default(),
+                                            match chat.has_left() {
+                                                true => Vec::new(),
+                                                false => chat.membership().map(|membership| membership.members.clone()).unwrap_or_default()
+                                            }
+                                        )
+                                    )
+                                };
+
+                                self.chats.insert(id, chat);
+                                Task::batch(vec![
+                                    Task::done(restored.into()),
+                                    match queued {
+                                        Ok(()) => Task::none(),
+                                        Err(error) => Task::done(Global::Error(error).into())
+                                    }
+                                ])
+                            }).collect();
+
+                            // A chat whose log was damaged is still shown, with everything before the damage.
+                            let reported = damaged.into_iter().map(|(chat, error)| {
+                                let name = self.display_name(chat);
+                                Task::done(Global::Notify(Notification::warning(format!("Part of the history with {name} could not be read"), Some(error.to_string()))).into())
+                            });
+
+                            Task::batch(restored.into_iter().chain(reported))
+                        }
+                        Err(error) => Task::done(Global::Error(error).into())
+                    };
+
+                    self.history = Some(history);
+                    self.outbox = outbox;
+                    Task::batch(vec![opened, task])
+                }
+
+                // Our own EndpointId, which is never listed among the members of a group.
+                ChatMessage::SetLocalId(id) => (self.local_id = Some(id)).into(),
+
                 // Set the active chat asynchronously
-                ChatMessage::SetActiveChat(stable_id) => {
-                    self.active_chat = stable_id;
-                    Task::none()
+                ChatMessage::SetActiveChat(chat) => {
+                    let stopped = match self.active_chat != Some(chat) {
+                        true => {
+                            self.editing = None;
+                            self.replying = None;
+                            self.show_safety_number = false;
+                            self.stop_typing()
+                        }
+                        false => Task::none()
+                    };
+                    self.active_chat = Some(chat);
+                    Task::batch(vec![stopped, self.mark_read(chat)])
+                }
+
+                // Whatever arrived in the active chat while it was hidden is read once it is shown again.
+                ChatMessage::SetOnScreen(on_screen) => {
+                    self.on_screen = on_screen;
+                    match self.active_chat.filter(|_| on_screen) {
+                        Some(chat) => Task::batch(vec![self.mark_read(chat), Task::done(Global::ClearNotifications(chat).into())]),
+                        None => Task::none()
+                    }
                 }
 
+                ChatMessage::SetReadReceipts(enabled) => (self.read_receipts = enabled).into(),
+
+                ChatMessage::SetContacts(contacts) => (self.contacts = contacts).into(),
+
+                ChatMessage::ToggleSafetyNumber => (self.show_safety_number = !self.show_safety_number).into(),
+
                 // Message to record an incoming message. This is the only interface through which the user can see a message.
                 ChatMessage::ReceiveForeignPacket(author, packet) => {
+                    if let Some(group) = packet.group {
+                        return self.receive_group_packet(author, group, packet);
+                    }
+
+                    // Whatever the peer sends, it has finished typing it.
+                    if let Some(typing) = packet.is_typing() {
+                        return self.receive_typing(ChatId::Direct(author), author, typing);
+                    }
+                    self.typing.remove(&(ChatId::Direct(author), author));
 
                     match packet.kind {
                         PacketType::Username => {
                             let foreign_username = String::from_utf8_lossy(&packet.data).to_string();
-                            if let Some(chat) = self.chats.get_mut(&author) {
-                                chat.set_foreign_username(foreign_username.clone());
-                            }
-                            Task::done(Global::BindUsernameToId(author, foreign_username).into())
+                            let task = match self.record(ChatId::Direct(author), Record::Username(foreign_username.clone())) {
+                                Ok(()) => Task::none(),
+                                Err(error) => Task::done(Global::Error(error).into())
+                            };
+                            Task::batch(vec![task, Task::done(Global::BindUsernameToId(author, foreign_username).into())])
+                        },
+
+                        PacketType::Read => self.receive_read(ChatId::Direct(author), author, packet.read_ids()),
+
+                        // The peer holds part of a file we sent, and is ready for the rest.
+                        PacketType::Resume => match packet.resumed_id().and_then(|id| self.locate_file(author, id, true))
+                            .filter(|(chat, index)| self.chats.get(chat).and_then(|chat| chat.delivery(*index, author)) == Some(PacketState::Failed)) {
+                            Some((chat, index)) => self.retry(chat, index, vec![author]),
+                            None => Task::none()
                         },
-                        
+
                         _ => {
 
-                            let task = match self.add_packet(author, false, packet) {
+                            // Amendments and reactions are shown on the packet they refer to, so do not count as new.
+                            let amendment = packet.target().is_some();
+                            let task = match self.add_packet(ChatId::Direct(author), false, packet) {
                                 Ok(()) => println!("Received packet into the GUI!").into(),
                                 Err(error) => Task::done(Global::Notify(error.into()).into())
                             };
 
-                            let other = if author != self.active_chat {
-                                Task::done(Global::AddNotification(author).into())
-                            } else {
-                                Task::none()
-                            };
-
-                            Task::batch(vec![task, other])
+                            match amendment {
+                                true => task,
+                                false => Task::batch(vec![task, self.notify(ChatId::Direct(author))])
+                            }
 
                         }
                     }
 
                 },
 
-                // Identical to ReceiveForeignPacket but idiomatically this is where packets locally initiated are sent to be displayed.
-                ChatMessage::SentLocalPacket(recipient, packet) => match self.add_packet(recipient, true, packet) {
-                    Ok(value) => value.into(),
-                    Err(error) => Task::done(Global::Notify(error.into()).into())
-                },
-
                 // Update the message box
-                ChatMessage::UpdateMessageBox(new_value) => (self.message_box = new_value).into(),
+                ChatMessage::UpdateMessageBox(new_value) => {
+                    self.message_box = new_value;
+                    self.announce_typing()
+                }
+
+                // A later Typing packet from the same peer pushes its expiry back.
+                ChatMessage::ExpireTyping(chat, peer) => {
+                    if self.typing.get(&(chat, peer)).is_some_and(|at| at.elapsed() >= TYPING_TIMEOUT) {
+                        self.typing.remove(&(chat, peer));
+                    }
+                    Task::none()
+                }
 
                 // Send the current contents of the message box to the current chat
                 ChatMessage::Send => {
                     if self.message_box.is_empty() { return Task::none(); }
-                    let message = take(&mut self.message_box);
-                    let packet = Packet::message(message);
-                    let (tracked_packet, receiver) = TrackedPacket::new(self.active_chat, packet.clone());
-                    let unique_packet_id = match self.chats.get(&self.active_chat) {
-                        Some(chat) => chat.get_unique_id(),
-                        None => 0
+                    let active_chat = match self.active_chat {
+                        Some(chat) => chat,
+                        None => return Task::done(Global::Error(ChatError::NoChatOpen.into()).into())
                     };
 
-                    let active_chat_clone = self.active_chat;
+                    let message = take(&mut self.message_box);
+                    let parent = self.replying.take().filter(|(chat, _)| *chat == active_chat)
+                        .and_then(|(chat, index)| self.chats.get(&chat).and_then(|chat| chat.packet(index)))
+                        .map(|parent| parent.id);
 
-                    Task::batch(vec![
-                        Task::done(Global::ClearNotifications(self.active_chat).into()),
-                        Task::done(Global::Send(tracked_packet).into()),
-                        Task::done(ChatMessage::SentLocalPacket(self.active_chat, packet).into()),
-                        Task::future(async move { receiver.recv().await }).map(move |message| match message {
-                            Ok(response) => match response {
-                                TrackedPacketResponse::Confirmed => ChatMessage::PacketConfirmed(active_chat_clone, unique_packet_id),
-                                TrackedPacketResponse::Failed => ChatMessage::PacketFailed(active_chat_clone, unique_packet_id)
-                            }
+                    let packet = match self.editing.take().filter(|(chat, _)| *chat == active_chat)
+                        .and_then(|(chat, index)| self.chats.get(&chat).and_then(|chat| chat.packet(index))) {
+                        Some(original) => Packet::edit(original.id, message),
+                        None => match parent {
+                            Some(parent) => Packet::message(message).replying_to(parent),
+                            None => Packet::message(message)
+                        }
+                    };
 
-                            Err(_) => ChatMessage::PacketFailed(active_chat_clone, unique_packet_id)
-                        }.into())
+                    Task::batch(vec![
+                        self.stop_typing(),
+                        Task::done(Global::ClearNotifications(active_chat).into()),
+                        self.send_local(active_chat, packet)
                     ])
                 },
 
-                // Handle a failed message
-                ChatMessage::PacketFailed(stable_id, unique_id) => {
-                    if let Some(chat) = self.chats.get_mut(&stable_id) {
-                        chat.update_state(unique_id, crate::backend::chat::PacketState::Failed);
+                ChatMessage::EditPacket(chat, index) => match self.chats.get(&chat).and_then(|entry| entry.text(index)) {
+                    Some(text) => {
+                        self.editing = Some((chat, index));
+                        self.replying = None;
+                        self.message_box = text;
+                        Task::none()
                     }
+                    None => Task::none()
+                }
 
+                ChatMessage::CancelEdit => {
+                    self.editing = None;
+                    self.message_box.clear();
                     Task::none()
                 }
 
-                // Handle a successful packet that received a confirmation code from the foreign client
-                ChatMessage::PacketConfirmed(stable_id, unique_id) => {
-                    if let Some(chat) = self.chats.get_mut(&stable_id) {
-                        chat.update_state(unique_id, crate::backend::chat::PacketState::Verified);
-                    }
+                ChatMessage::ReplyTo(chat, index) => {
+                    if self.editing.is_some() { self.message_box.clear(); }
+                    self.editing = None;
+                    self.replying = Some((chat, index));
+                    Task::none()
+                }
 
+                ChatMessage::CancelReply => (self.replying = None).into(),
+
+                // Scroll the conversation to a packet, such as the parent of a reply.
+                ChatMessage::JumpTo(index) => match self.active_chat.and_then(|chat| self.chats.get(&chat)).and_then(|chat| chat.offset_of(index)) {
+                    Some(offset) => snap_to(Id::new(CONVERSATION), RelativeOffset { x: 0f32, y: offset }),
+                    None => Task::none()
+                }
+
+                ChatMessage::ToggleReactions(chat, index) => {
+                    self.reacting = match self.reacting == Some((chat, index)) {
+                        true => None,
+                        false => Some((chat, index))
+                    };
                     Task::none()
+                }
+
+                ChatMessage::React(chat, index, emoji) => {
+                    self.reacting = None;
+                    match self.chats.get(&chat).and_then(|entry| entry.packet(index).map(|packet| (packet.id, entry.reacted(index, &emoji)))) {
+                        Some((id, reacted)) => self.send_local(chat, Packet::react(id, emoji, !reacted)),
+                        None => Task::none()
+                    }
+                }
+
+                ChatMessage::DeletePacket(chat, index) => match self.chats.get(&chat).and_then(|entry| entry.packet(index)) {
+                    Some(original) => self.send_local(chat, Packet::delete(original.id)),
+                    None => Task::none()
+                }
+
+                // Handle a failed delivery by queueing the packet in the recipient's outbox until they return.
+                ChatMessage::PacketFailed(chat, member, unique_id) => {
+                    let packet = self.chats.get(&chat).and_then(|entry| entry.packet(unique_id)).cloned();
+                    let queued = match (self.outbox.as_mut(), packet) {
+                        (Some(outbox), Some(packet)) => outbox.enqueue(member, unique_id, packet),
+                        _ => Ok(())
+                    };
+
+                    match queued.and_then(|_| self.update_delivery(chat, member, unique_id, PacketState::Failed)) {
+                        Ok(()) => Task::none(),
+                        Err(error) => Task::done(Global::Error(error).into())
+                    }
+                }
+
+                // Handle a successful packet that received a confirmation code from the foreign client.
+                // If it came from the outbox, continue flushing with the next packet queued for that recipient.
+                ChatMessage::PacketConfirmed(chat, member, unique_id) => {
+                    let dequeued = match (self.outbox.as_mut(), self.chats.get(&chat).and_then(|entry| entry.packet(unique_id))) {
+                        (Some(outbox), Some(packet)) => outbox.remove(member, packet.id),
+                        _ => Ok(false)
+                    };
+
+                    match dequeued.and_then(|dequeued| self.update_delivery(chat, member, unique_id, PacketState::Verified).map(|_| dequeued)) {
+                        Ok(true) => Task::done(ChatMessage::FlushOutbox(member).into()),
+                        Ok(false) => Task::none(),
+                        Err(error) => Task::done(Global::Error(error).into())
+                    }
                 },
 
+                // Send the oldest packet queued for a peer. Each confirmation triggers the next, so the queue is delivered in order.
+                // A head that is already being retried is left alone rather than sent twice.
+                ChatMessage::FlushOutbox(peer) => match self.outbox.as_ref().and_then(|outbox| outbox.head(peer))
+                    .map(|(index, packet)| (ChatId::of(peer, &packet), index, packet))
+                    .filter(|(chat, index, _)| self.chats.get(chat).and_then(|chat| chat.delivery(*index, peer)) == Some(PacketState::Failed)) {
+                    Some((chat, index, packet)) => match self.update_delivery(chat, peer, index, PacketState::Unknown) {
+                        Ok(()) => Self::track(chat, index, packet, Distribution::Peer(peer)),
+                        Err(error) => Task::done(Global::Error(error).into())
+                    },
+                    None => Task::none()
+                }
+
+                // Ask the peer to resume every file it was sending us when the connection was lost.
+                ChatMessage::ResumeTransfers(peer) => Task::batch(
+                    self.chats_with(peer).into_iter()
+                        .flat_map(|chat| self.chats.get(&chat).map(|chat| chat.interrupted_incoming(peer)).unwrap_or_default())
+                        .map(|id| Task::done(Global::Send(TrackedPacket::new(peer, Packet::resume(id)).0).into()))
+                        .collect::<Vec<Task<Message>>>()
+                ),
+
+                ChatMessage::RetryPacket(chat, index) => {
+                    let members = self.failed_recipients(chat, index);
+                    self.retry(chat, index, members)
+                }
+
+                ChatMessage::DiscardPacket(chat, index) => {
+                    let id = match self.chats.get(&chat).and_then(|entry| entry.packet(index)) {
+                        Some(packet) => packet.id,
+                        None => return Task::none()
+                    };
+
+                    let discarded = self.failed_recipients(chat, index).into_iter().try_for_each(|member| {
+                        if let Some(outbox) = self.outbox.as_mut() {
+                            outbox.remove(member, id)?;
+                        }
+                        self.update_delivery(chat, member, index, PacketState::Discarded)
+                    });
+
+                    match discarded {
+                        Ok(()) => Task::none(),
+                        Err(error) => Task::done(Global::Error(error).into())
+                    }
+                }
+
+                // Start a new group with no other members, who are added from within it.
+                ChatMessage::CreateGroup(name) => {
+                    if name.is_empty() { return Task::none(); }
+                    let group: GroupId = rand::random();
+                    let chat = ChatId::Group(group);
+
+                    match self.record(chat, Record::Membership(Membership::new(name.clone()))) {
+                        Ok(()) => Task::batch(vec![
+                            Task::done(Global::GroupJoined(group, name, Vec::new()).into()),
+                            Task::done(Global::SwitchTo(Pages::Chat(chat)).into())
+                        ]),
+                        Err(error) => Task::done(Global::Error(error).into())
+                    }
+                }
+
+                ChatMessage::UpdateMemberInput(new_value) => (self.member_input = new_value).into(),
+
+                // Add a peer to the active group, announcing the new member list to everyone in it, the new member included.
+                ChatMessage::AddMember => {
+                    let (chat, group) = match self.active_chat {
+                        Some(ChatId::Group(group)) => (ChatId::Group(group), group),
+                        _ => return Task::done(Global::Error(ChatError::NoChatOpen.into()).into())
+                    };
+
+                    let member = match EndpointId::from_str(&take(&mut self.member_input)) {
+                        Ok(member) if Some(member) != self.local_id => member,
+                        _ => return Task::done(Global::Error(ChatError::InvalidMember.into()).into())
+                    };
+
+                    let mut membership = match self.chats.get(&chat).filter(|chat| !chat.has_left()).and_then(Chat::membership) {
+                        Some(membership) => membership.clone(),
+                        None => return Task::done(Global::Error(ChatError::LeftGroup.into()).into())
+                    };
+
+                    if !membership.add(member) { return Task::none(); }
+                    match self.record(chat, Record::Membership(membership.clone())) {
+                        Ok(()) => Task::batch(vec![
+                            Task::done(Global::Send(TrackedPacket::distribute(Distribution::Group(membership.members.clone()), Packet::group_add(group, &membership)).0).into()),
+                            Task::done(Global::GroupJoined(group, membership.name, membership.members).into())
+                        ]),
+                        Err(error) => Task::done(Global::Error(error).into())
+                    }
+                }
+
+                // Leave the active group. Its history is kept, but nothing more is sent or accepted.
+                ChatMessage::LeaveGroup => {
+                    let (chat, group) = match self.active_chat {
+                        Some(ChatId::Group(group)) => (ChatId::Group(group), group),
+                        _ => return Task::done(Global::Error(ChatError::NoChatOpen.into()).into())
+                    };
+
+                    let members = match self.chats.get(&chat).filter(|chat| !chat.has_left()).and_then(Chat::membership) {
+                        Some(membership) => membership.members.clone(),
+                        None => return Task::none()
+                    };
+
+                    match self.record(chat, Record::Left) {
+                        Ok(()) => Task::done(Global::Send(TrackedPacket::distribute(Distribution::Group(members), Packet::group_leave(group)).0).into()),
+                        Err(error) => Task::done(Global::Error(error).into())
+                    }
+                }
+
+                // Bring a (re)connected peer up to date with every group we share, in case it missed a change while offline.
+                ChatMessage::SyncGroups(peer) => Task::batch(
+                    self.chats_with(peer).into_iter()
+                        .filter_map(|chat| match (chat, self.chats.get(&chat)) {
+                            (ChatId::Group(group), Some(entry)) => match (entry.has_left(), entry.membership()) {
+                                (true, _) => Some(Packet::group_leave(group)),
+                                (false, Some(membership)) => Some(Packet::group_add(group, membership)),
+                                _ => None
+                            },
+                            _ => None
+                        })
+                        .map(|packet| Task::done(Global::Send(TrackedPacket::new(peer, packet).0).into()))
+                        .collect::<Vec<Task<Message>>>()
+                ),
+
                 ChatMessage::PickImage => {
-                    let active_chat = self.active_chat;
+                    let active_chat = match self.active_chat {
+                        Some(chat) => chat,
+                        None => return Task::done(Global::Error(ChatError::NoChatOpen.into()).into())
+                    };
                     Task::perform(tokio::task::spawn_blocking(|| rfd::FileDialog::new().pick_file()), move |res| Global::LoadImage(active_chat, res.map_err(Error::from)).into())
                 }
 
-                ChatMessage::ImagePicked(stable_id_of_recipient, path) => {
-                    
+                ChatMessage::ImagePicked(chat, path) => {
+
                     let image = match image::open(path) {
                         Ok(image) => image,
                         Err(e) => return Task::done(Global::Error(e.into()).into())
@@ -247,42 +1087,76 @@ impl Page for ChatPage {
 
                     let packet = match Packet::image(&image) {
                         Ok(packet) => packet,
-                        Err(e) => return Task::done(Global::Error(e.into()).into())
+                        Err(e) => return Task::done(Global::Error(e).into())
                     };
 
-                    let (tracked_packet, receiver) = TrackedPacket::new(self.active_chat, packet.clone());
-                    let unique_packet_id = match self.chats.get(&self.active_chat) {
-                        Some(chat) => chat.get_unique_id(),
-                        None => 0
+                    self.send_local(chat, packet)
+                }
+
+                ChatMessage::PickFile => {
+                    let active_chat = match self.active_chat {
+                        Some(chat) => chat,
+                        None => return Task::done(Global::Error(ChatError::NoChatOpen.into()).into())
                     };
+                    Task::perform(tokio::task::spawn_blocking(|| rfd::FileDialog::new().pick_file()), move |res| ChatMessage::FilePicked(active_chat, res.map_err(Error::from)).into())
+                }
 
-                    Task::batch(vec![
-                        Task::done(Global::Send(tracked_packet).into()),
-                        Task::done(ChatMessage::SentLocalPacket(stable_id_of_recipient, packet).into()),
-                        Task::future(async move { receiver.recv().await }).map(move |message| match message {
-                            Ok(response) => match response {
-                                TrackedPacketResponse::Confirmed => ChatMessage::PacketConfirmed(stable_id_of_recipient, unique_packet_id),
-                                TrackedPacketResponse::Failed => ChatMessage::PacketFailed(stable_id_of_recipient, unique_packet_id)
-                            }
+                ChatMessage::FilePicked(chat, result) => match result {
+                    Ok(Some(path)) => Task::perform(tokio::task::spawn_blocking(move || Packet::file(&path)), move |res| {
+                        ChatMessage::FileHashed(chat, res.map_err(Error::from).and_then(|packet| packet)).into()
+                    }),
+                    Ok(None) => Task::done(Global::Error(ChatError::NoFileSelected.into()).into()),
+                    Err(error) => Task::done(Global::Error(error).into())
+                }
 
-                            Err(_) => ChatMessage::PacketFailed(stable_id_of_recipient, unique_packet_id)
-                        }.into())
-                    ])
+                ChatMessage::FileHashed(chat, result) => match result {
+                    Ok(packet) => self.send_local(chat, packet),
+                    Err(error) => Task::done(Global::Error(error).into())
                 }
 
-                ChatMessage::UsernameUpdate(username) => {
-                    self.username = username.clone();
+                ChatMessage::Transfer(event) => match event {
 
-                    let mut dispatch: Vec<Task<Message>> = vec![];
-                    let packet = Packet::username(username);
+                    // An incoming file is displayed as soon as it begins, and only marked verified once its hash has been checked.
+                    // A resumed file is already displayed, and returns to being in progress.
+                    TransferEvent::Incoming(author, packet) => {
+                        let chat = ChatId::of(author, &packet);
+                        if chat != ChatId::Direct(author) && !self.chats_with(author).contains(&chat) { return Task::none(); }
 
-                    for chat in self.chats.keys() {
-                        let (tracked_packet, _) = TrackedPacket::new(*chat, packet.clone());
-                        dispatch.push(Task::done(Global::Send(tracked_packet).into()));
+                        // A packet already recorded under the id is only resumed if it is a file from the same peer.
+                        let entry = self.chats.get(&chat);
+                        let recorded = match (entry.and_then(|entry| entry.find_file(packet.id, false, author)), entry.and_then(|entry| entry.find(packet.id))) {
+                            (Some(index), _) => self.update_state(chat, index, PacketState::Unknown),
+                            (None, Some(_)) => return Task::none(),
+                            (None, None) => match chat {
+                                ChatId::Direct(_) => self.record(chat, Record::Packet { local: false, packet, state: PacketState::Unknown }),
+                                ChatId::Group(_) => self.record(chat, Record::Member { author, packet, state: PacketState::Unknown })
+                            }
+                        };
+
+                        let task = match recorded {
+                            Ok(()) => Task::none(),
+                            Err(error) => Task::done(Global::Error(error).into())
+                        };
+
+                        Task::batch(vec![task, self.notify(chat)])
+                    }
+
+                    TransferEvent::Progress(id, bytes) => {
+                        self.transfers.insert(id, bytes);
+                        Task::none()
                     }
 
-                    Task::batch(dispatch)
+                    TransferEvent::Verified(peer, id) => {
+                        let task = self.finish_transfer(peer, id, PacketState::Verified);
+                        match self.locate_file(peer, id, false) {
+                            Some((chat, _)) if Some(chat) == self.active_chat => Task::batch(vec![task, self.mark_read(chat)]),
+                            _ => task
+                        }
+                    }
+                    TransferEvent::Failed(peer, id) => self.finish_transfer(peer, id, PacketState::Failed)
                 }
+
+                ChatMessage::UsernameUpdate(username) => (self.username = username).into(),
             },
             _ => Task::none()
         }
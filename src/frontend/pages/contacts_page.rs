@@ -0,0 +1,247 @@
+use std::str::FromStr;
+
+use iced::{Background, Border, Length, Shadow, Task, widget::{Column, Container, Row, Scrollable, button, text, text_input}};
+use iroh::EndpointId;
+
+use crate::{backend::contacts::ContactBook, frontend::{application::Page, message::{Global, Message}, notification::Notification, pages::chat_page::ChatMessage, widget::{Colour, button_style}}, networking::{access::AccessControl, identity::SafetyNumber}};
+
+/// The contact book, where peers are given petnames and notes, and chats can be started without retyping node IDs.
+/// Blocked peers are listed beneath the contacts.
+pub struct ContactsPage {
+    book: ContactBook,
+    access: AccessControl,      // Shared with networking, which lets contacts in whatever the access policy.
+    editing: Option<EndpointId>,
+    id_input: String,
+    petname_input: String,
+    notes_input: String
+}
+
+#[derive(Clone, Debug)]
+pub enum ContactMessage {
+    Load,
+    IdInput(String),
+    PetnameInput(String),
+    NotesInput(String),
+
+    // Add the contact in the inputs, or update it if it already exists.
+    Save,
+
+    // Block the peer whose node ID is in the inputs.
+    Block,
+
+    // Load a contact into the inputs to be changed. Saving it under a different node ID moves it to the new key.
+    Edit(EndpointId),
+    Remove(EndpointId),
+
+    // Record the safety number we confirmed with a contact, or clear it.
+    Verify(EndpointId, Option<SafetyNumber>),
+
+    // A peer reported its username, which is remembered if the peer is a contact.
+    Observe(EndpointId, String)
+}
+
+impl ContactsPage {
+
+    pub fn new(access: AccessControl) -> ContactsPage {
+        ContactsPage {
+            book: ContactBook::default(),
+            access,
+            editing: None,
+            id_input: String::new(),
+            petname_input: String::new(),
+            notes_input: String::new()
+        }
+    }
+
+    pub fn book(&self) -> &ContactBook {
+        &self.book
+    }
+
+    /// Tell networking who our contacts are, and the chat page their petnames and verification, so that it names peers as we do.
+    fn publish(&self) -> Task<Message> {
+        let contacts = self.book.all();
+        self.access.set_contacts(contacts.keys().copied());
+        Task::done(ChatMessage::SetContacts(contacts).into())
+    }
+}
+
+impl Page for ContactsPage {
+    fn view(&self) -> Container<'_, Message> {
+        let input_style = |_: &iced::Theme, _| iced::widget::text_input::Style {
+            background: Background::Color(Colour::foreground()),
+            border: Border::default().rounded(10),
+            icon: Colour::accent(),
+            placeholder: Colour::loading(),
+            value: Colour::text(),
+            selection: Colour::accent()
+        };
+
+        let contacts = self.book.sorted();
+        let blocked = self.access.blocked();
+        let unreadable = self.access.is_blocklist_unreadable();
+
+        Container::new(
+            Column::new().padding(10).spacing(10)
+                .push(text("CONTACTS").size(20).color(Colour::accent()))
+                .push(text_input("Enter NODE ID.", &self.id_input).on_input(|new_content| ContactMessage::IdInput(new_content).into()).style(input_style))
+                .push(
+                    text_input("Enter PETNAME.", &self.petname_input)
+                        .on_input(|new_content| ContactMessage::PetnameInput(new_content).into())
+                        .on_submit(ContactMessage::Save.into())
+                        .style(input_style)
+                )
+                .push(
+                    text_input("Notes...", &self.notes_input)
+                        .on_input(|new_content| ContactMessage::NotesInput(new_content).into())
+                        .on_submit(ContactMessage::Save.into())
+                        .style(input_style)
+                )
+                .push(
+                    Row::new().spacing(10)
+                        .push(button("Save contact").on_press(ContactMessage::Save.into()).style(button_style))
+                        .push(button("Block").on_press(ContactMessage::Block.into()).style(button_style))
+                )
+                .push(
+                    Container::new(
+                        Scrollable::new(
+                            Column::new().spacing(10)
+                                .extend(contacts.iter().map(|(peer, contact)|
+                                    Row::new().spacing(10)
+                                        .push(
+                                            Column::new().spacing(5).width(Length::Fill)
+                                                .push(
+                                                    Row::new().spacing(10)
+                                                        .push(text(&contact.petname).size(15).color(Colour::text()))
+                                                        .push(contact.username.as_ref().map(|username| text(format!("~{username}")).size(12).color(Colour::loading())))
+                                                        .push(text(peer.fmt_short().to_string()).size(12).color(Colour::loading()))
+                                                )
+                                                .push(match contact.notes.is_empty() {
+                                                    true => None,
+                                                    false => Some(text(&contact.notes).size(12).color(Colour::loading()))
+                                                })
+                                        )
+                                        .push(button(text("CHAT").size(12)).on_press(Global::OpenChat(**peer).into()).style(button_style))
+                                        .push(button(text("EDIT").size(12)).on_press(ContactMessage::Edit(**peer).into()).style(button_style))
+                                        .push(button(text("REMOVE").size(12)).on_press(ContactMessage::Remove(**peer).into()).style(button_style))
+                                        .push(button(text("BLOCK").size(12)).on_press(Global::Block(**peer).into()).style(button_style))
+                                        .into()
+                                ))
+                                .push(match contacts.is_empty() {
+                                    true => Some(text("You don't seem to have any contacts yet...").color(Colour::loading())),
+                                    false => None
+                                })
+                                .push(match blocked.is_empty() && !unreadable {
+                                    true => None,
+                                    false => Some(text("BLOCKED").size(15).color(Colour::error()))
+                                })
+                                .push(match unreadable {
+                                    true => Some(
+                                        Row::new().spacing(10)
+                                            .push(text("The blocklist could not be read, so anyone who dials you is asked about first.").size(12).color(Colour::error()).width(Length::Fill))
+                                            .push(button(text("RESET").size(12)).on_press(Global::ResetBlocklist.into()).style(button_style))
+                                    ),
+                                    false => None
+                                })
+                                .extend(blocked.into_iter().map(|peer|
+                                    Row::new().spacing(10)
+                                        .push(text(self.book.petname(&peer).cloned().unwrap_or_else(|| peer.fmt_short().to_string())).size(15).color(Colour::loading()).width(Length::Fill))
+                                        .push(button(text("UNBLOCK").size(12)).on_press(Global::Unblock(peer).into()).style(button_style))
+                                        .into()
+                                ))
+                        )
+                    ).style(|_|
+                        iced::widget::container::Style {
+                            background: Some(Background::Color(Colour::foreground())),
+                            text_color: None,
+                            border: Border::default().rounded(10),
+                            shadow: Shadow::default(),
+                            snap: false
+                        }
+                    ).padding(10).width(Length::Fill).height(Length::Fill)
+                )
+        )
+    }
+
+    fn update(&mut self, message: Message) -> Task<Message> {
+        match message {
+            Message::ContactMessage(message) => match message {
+
+                // An unreadable contact book is reported, and an empty one is used in its place.
+                ContactMessage::Load => match ContactBook::load() {
+                    Ok(book) => {
+                        self.book = book;
+                        self.publish()
+                    }
+                    Err(error) => Task::done(Global::Error(error).into())
+                },
+
+                ContactMessage::IdInput(new_content) => (self.id_input = new_content).into(),
+                ContactMessage::PetnameInput(new_content) => (self.petname_input = new_content).into(),
+                ContactMessage::NotesInput(new_content) => (self.notes_input = new_content).into(),
+
+                ContactMessage::Save => {
+                    let peer = match EndpointId::from_str(self.id_input.trim()) {
+                        Ok(peer) => peer,
+                        Err(_) => return Task::done(Global::Notify(Notification::error(String::from("Invalid ID"))).into())
+                    };
+
+                    let petname = self.petname_input.trim().to_string();
+                    if petname.is_empty() {
+                        return Task::done(Global::Notify(Notification::error(String::from("A contact needs a petname"))).into());
+                    }
+
+                    let rekeyed = match self.editing.take() {
+                        Some(old) if old != peer => self.book.rekey(&old, peer),
+                        _ => Ok(())
+                    };
+
+                    match rekeyed.and_then(|()| self.book.set(peer, petname.clone(), std::mem::take(&mut self.notes_input))) {
+                        Ok(()) => {
+                            self.id_input.clear();
+                            self.petname_input.clear();
+                            Task::batch(vec![
+                                self.publish(),
+                                Task::done(Global::Notify(Notification::success(format!("Saved {petname} to your contacts."))).into())
+                            ])
+                        }
+                        Err(error) => Task::done(Global::Error(error).into())
+                    }
+                }
+
+                ContactMessage::Block => match EndpointId::from_str(self.id_input.trim()) {
+                    Ok(peer) => {
+                        self.id_input.clear();
+                        Task::done(Global::Block(peer).into())
+                    }
+                    Err(_) => Task::done(Global::Notify(Notification::error(String::from("Invalid ID"))).into())
+                },
+
+                ContactMessage::Edit(peer) => {
+                    if let Some(contact) = self.book.get(&peer) {
+                        self.editing = Some(peer);
+                        self.id_input = peer.to_string();
+                        self.petname_input = contact.petname.clone();
+                        self.notes_input = contact.notes.clone();
+                    }
+                    Task::none()
+                }
+
+                ContactMessage::Remove(peer) => match self.book.remove(&peer) {
+                    Ok(()) => self.publish(),
+                    Err(error) => Task::done(Global::Error(error).into())
+                },
+
+                ContactMessage::Verify(peer, number) => match self.book.verify(&peer, number) {
+                    Ok(()) => self.publish(),
+                    Err(error) => Task::done(Global::Error(error).into())
+                },
+
+                ContactMessage::Observe(peer, username) => match self.book.observe(&peer, username) {
+                    Ok(()) => Task::none(),
+                    Err(error) => Task::done(Global::Error(error).into())
+                }
+            },
+            _ => Task::none()
+        }
+    }
+}
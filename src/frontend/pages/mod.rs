@@ -8,11 +8,18 @@ Plan
 
 */
 
+use crate::backend::chat::ChatId;
+
 #[derive(Clone, Copy, Debug)]
 pub enum Pages {
-    Chat(usize),
+    Chat(ChatId),
     AddChat,
+    Contacts,
+    Security,
+    Unlock,     // Shown alone while Rift is locked, in place of whichever page was open.
 }
 
 pub mod chat_page;
 pub mod add_chat_page;
+pub mod contacts_page;
+pub mod vault_page;
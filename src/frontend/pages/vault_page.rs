@@ -0,0 +1,206 @@
+use iced::{Background, Border, Length, Task, alignment::{Horizontal, Vertical}, widget::{Column, Container, Row, button, text, text_input}};
+
+use crate::{error::{Error, Res}, frontend::{application::Page, message::{Global, Message}, notification::Notification, widget::{Colour, button_style}}, util::vault};
+
+/// The unlock screen shown while Rift is locked, and the security settings where the passphrase is set, changed or removed.
+#[derive(Default)]
+pub struct VaultPage {
+    unlock_input: String,
+    current_input: String,
+    passphrase_input: String,
+    confirm_input: String,
+    unlock_error: Option<String>,
+    working: bool,      // Whether a key is being derived, which takes long enough that the buttons are disabled meanwhile.
+    auto_lock: u16
+}
+
+#[derive(Clone, Debug)]
+pub enum VaultMessage {
+    UnlockInput(String),
+    CurrentInput(String),
+    PassphraseInput(String),
+    ConfirmInput(String),
+
+    Unlock,
+    Unlocked(Res<()>),
+
+    // Set the passphrase, or change it if one is already set.
+    SetPassphrase,
+    RemovePassphrase,
+
+    // The passphrase was set, changed or removed, with whether one is now set.
+    Changed(Res<bool>),
+
+    SetAutoLock(u16)
+}
+
+impl VaultPage {
+
+    /// The only thing shown while Rift is locked.
+    pub fn unlock_view(&self) -> Container<'_, Message> {
+        Container::new(
+            Column::new().spacing(10).width(Length::Fixed(400.0))
+                .push(text("RIFT IS LOCKED").size(20).color(Colour::accent()))
+                .push(
+                    text_input("Passphrase...", &self.unlock_input)
+                        .secure(true)
+                        .on_input(|new_content| VaultMessage::UnlockInput(new_content).into())
+                        .on_submit(VaultMessage::Unlock.into())
+                        .style(input_style)
+                )
+                .push(button(text(if self.working { "UNLOCKING..." } else { "UNLOCK" })).on_press_maybe((!self.working).then_some(VaultMessage::Unlock.into())).style(button_style).width(Length::Fill))
+                .push(self.unlock_error.as_ref().map(|error| text(error).size(12).color(Colour::error())))
+        ).width(Length::Fill).height(Length::Fill).align_x(Horizontal::Center).align_y(Vertical::Center)
+        .style(|_| iced::widget::container::Style {
+            background: Some(Background::Color(Colour::background())),
+            ..Default::default()
+        })
+    }
+
+    fn derive(&mut self, task: impl FnOnce() -> Res<bool> + Send + 'static) -> Task<Message> {
+        self.working = true;
+        Task::perform(tokio::task::spawn_blocking(task), |res| VaultMessage::Changed(res.map_err(Error::from).and_then(|res| res)).into())
+    }
+}
+
+impl Page for VaultPage {
+    fn view(&self) -> Container<'_, Message> {
+        let enabled = vault::is_enabled();
+        let action = |message: VaultMessage| (!self.working).then_some(message.into());
+
+        Container::new(
+            Column::new().padding(10).spacing(10)
+                .push(text("SECURITY").size(20).color(Colour::accent()))
+                .push(text(match enabled {
+                    true => "Your chat history, contacts, settings and identity are encrypted with your passphrase.",
+                    false => "Your data is stored unencrypted. Set a passphrase to encrypt it, and to be asked for it whenever Rift starts."
+                }).size(15).color(Colour::text()))
+                .push(match enabled {
+                    true => Some(
+                        text_input("Current passphrase...", &self.current_input)
+                            .secure(true)
+                            .on_input(|new_content| VaultMessage::CurrentInput(new_content).into())
+                            .style(input_style)
+                    ),
+                    false => None
+                })
+                .push(
+                    text_input("New passphrase...", &self.passphrase_input)
+                        .secure(true)
+                        .on_input(|new_content| VaultMessage::PassphraseInput(new_content).into())
+                        .style(input_style)
+                )
+                .push(
+                    text_input("Confirm new passphrase...", &self.confirm_input)
+                        .secure(true)
+                        .on_input(|new_content| VaultMessage::ConfirmInput(new_content).into())
+                        .on_submit(VaultMessage::SetPassphrase.into())
+                        .style(input_style)
+                )
+                .push(
+                    Row::new().spacing(10)
+                        .push(button(if enabled { "Change passphrase" } else { "Set passphrase" }).on_press_maybe(action(VaultMessage::SetPassphrase)).style(button_style))
+                        .push(match enabled {
+                            true => Some(button("Remove passphrase").on_press_maybe(action(VaultMessage::RemovePassphrase)).style(button_style)),
+                            false => None
+                        })
+                        .push(match enabled {
+                            true => Some(button("Lock now").on_press(Global::Lock.into()).style(button_style)),
+                            false => None
+                        })
+                )
+                .push(match self.working {
+                    true => Some(text("Re-encrypting your data...").size(12).color(Colour::loading())),
+                    false => None
+                })
+                .push(
+                    button(text(match self.auto_lock {
+                        0 => String::from("AUTO-LOCK: OFF"),
+                        minutes => format!("AUTO-LOCK: {minutes} MIN")
+                    }).size(12)).on_press(Global::CycleAutoLock.into()).style(button_style)
+                )
+                .push(match enabled {
+                    true => None,
+                    false => Some(text("Auto-lock only takes effect once a passphrase is set.").size(12).color(Colour::loading()))
+                })
+        )
+    }
+
+    fn update(&mut self, message: Message) -> Task<Message> {
+        match message {
+            Message::VaultMessage(message) => match message {
+                VaultMessage::UnlockInput(new_content) => (self.unlock_input = new_content).into(),
+                VaultMessage::CurrentInput(new_content) => (self.current_input = new_content).into(),
+                VaultMessage::PassphraseInput(new_content) => (self.passphrase_input = new_content).into(),
+                VaultMessage::ConfirmInput(new_content) => (self.confirm_input = new_content).into(),
+
+                // The key is derived off the UI thread, which is otherwise frozen for as long as it takes.
+                VaultMessage::Unlock => {
+                    if self.working { return Task::none(); }
+                    self.working = true;
+                    let passphrase = std::mem::take(&mut self.unlock_input);
+                    Task::perform(
+                        tokio::task::spawn_blocking(move || vault::unlock(&passphrase)),
+                        |res| VaultMessage::Unlocked(res.map_err(Error::from).and_then(|res| res)).into()
+                    )
+                }
+
+                VaultMessage::Unlocked(result) => {
+                    self.working = false;
+                    match result {
+                        Ok(()) => {
+                            self.unlock_error = None;
+                            Task::done(Global::Unlocked.into())
+                        }
+                        Err(error) => (self.unlock_error = Some(error.to_string())).into()
+                    }
+                }
+
+                VaultMessage::SetPassphrase => {
+                    if self.working { return Task::none(); }
+                    if self.passphrase_input.is_empty() {
+                        return Task::done(Global::Notify(Notification::error(String::from("A passphrase cannot be empty"))).into());
+                    }
+                    if self.passphrase_input != self.confirm_input {
+                        return Task::done(Global::Notify(Notification::error(String::from("The passphrases do not match"))).into());
+                    }
+
+                    let current = vault::is_enabled().then(|| std::mem::take(&mut self.current_input));
+                    let passphrase = std::mem::take(&mut self.passphrase_input);
+                    self.confirm_input.clear();
+                    self.derive(move || vault::change(current.as_deref(), Some(&passphrase)).map(|()| true))
+                }
+
+                VaultMessage::RemovePassphrase => {
+                    if self.working { return Task::none(); }
+                    let current = std::mem::take(&mut self.current_input);
+                    self.derive(move || vault::change(Some(&current), None).map(|()| false))
+                }
+
+                VaultMessage::Changed(result) => {
+                    self.working = false;
+                    let status = match result {
+                        Ok(true) => "Your data is now encrypted with your passphrase.",
+                        Ok(false) => "Your passphrase was removed. Your data is now stored unencrypted.",
+                        Err(error) => return Task::done(Global::Error(error).into())
+                    };
+                    Task::done(Global::Notify(Notification::success(String::from(status))).into())
+                }
+
+                VaultMessage::SetAutoLock(minutes) => (self.auto_lock = minutes).into()
+            },
+            _ => Task::none()
+        }
+    }
+}
+
+fn input_style(_: &iced::Theme, _: text_input::Status) -> text_input::Style {
+    text_input::Style {
+        background: Background::Color(Colour::foreground()),
+        border: Border::default().rounded(10),
+        icon: Colour::accent(),
+        placeholder: Colour::loading(),
+        value: Colour::text(),
+        selection: Colour::accent()
+    }
+}
@@ -1,9 +1,10 @@
-use iced::{Color, color};
+use iced::{Background, Border, Color, Shadow, Theme, color, widget::button};
 
 pub struct Colour;
 impl Colour {
     pub fn error() -> Color { color!(0xB33930) }
     pub fn warning() -> Color { color!(0xEBAB34) }
+    pub fn success() -> Color { color!(0x4E9A5B) }
     pub fn loading() -> Color { color!(0x696969) }
     pub fn text() -> Color { color!(0xB0B0B0) }
     pub fn background() -> Color { color!(0x29292e) }
@@ -12,3 +13,19 @@ impl Colour {
 }
 
 pub mod packet_widget;
+pub mod toast_widget;
+
+/// The standard button appearance used throughout the sidebar and pages.
+pub fn button_style(_: &Theme, status: button::Status) -> button::Style {
+    button::Style {
+        background: Some(Background::Color(match status {
+            button::Status::Active => Colour::accent(),
+            button::Status::Hovered => Colour::foreground(),
+            _ => Colour::background()
+        })),
+        text_color: Colour::text(),
+        border: Border::default().rounded(10),
+        shadow: Shadow::default(),
+        snap: false
+    }
+}
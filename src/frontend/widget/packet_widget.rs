@@ -1,37 +1,188 @@
 use iced::widget::Column;
 use iced::widget::Container;
+use iced::widget::Row;
+use iced::widget::button;
+use iced::widget::progress_bar;
 use iced::widget::text;
-use iced::Length;
-
-use crate::backend::chat::PacketState;
-use crate::frontend::widget::Colour;
+use iced::{Alignment, Border, Length};
+use crate::backend::chat::{ChatId, PacketState, Revision};
+use crate::frontend::pages::chat_page::ChatMessage;
+use crate::frontend::widget::{Colour, button_style};
 use crate::{frontend::message::Message, networking::packet::{Packet, PacketType}};
 
+/// What a chat knows about a packet beyond the packet itself.
+pub struct Annotations<'a> {
+    pub revision: Revision<'a>,
+    pub time: Option<String>,                               // The local time the packet was sent, if known.
+    pub progress: Option<u64>,                              // Bytes transferred so far, for a file in flight.
+    pub deliveries: Option<Vec<(String, PacketState)>>,     // The state for each member, for a packet sent to a group.
+    pub reply: Option<(String, String, usize)>,             // The author, preview and index of the packet this one replies to.
+    pub reactions: Vec<(String, usize, bool)>,              // Each emoji, its count, and whether one of them is our own.
+    pub palette: bool,                                      // Whether the emoji palette is open beneath this packet.
+    pub headerless: bool,                                   // Whether the previous packet has the same author, so the author need not be repeated.
+    pub verified: bool                                      // Whether the author is a contact whose safety number we have verified.
+}
+
+/// The emoji offered when reacting to a packet.
+const REACTIONS: [&str; 6] = ["👍", "❤️", "😂", "😮", "😢", "🎉"];
+
 pub struct PacketWidget;
 impl PacketWidget {
-    pub fn parse(author: String, packet: &Packet, packet_state: PacketState, headerless: bool) -> Container<'_, Message> {
-        let content_widget = match packet.kind {
-            PacketType::Message => {
-               Container::new(text(String::from_utf8_lossy(&packet.data))
+    pub fn parse<'a>(author: String, chat: ChatId, index: usize, entry: &'a (bool, Packet, PacketState), annotations: Annotations<'a>) -> Container<'a, Message> {
+        let (local, packet, packet_state) = (entry.0, &entry.1, entry.2);
+        let Annotations { revision, time, progress, deliveries, reply, reactions, palette, headerless, verified } = annotations;
+        let content_widget = match (packet.kind, revision) {
+            (_, Revision::Deleted) => Container::new(text("This message was deleted.").size(15).color(Colour::loading())),
+            (PacketType::Message, revision) => {
+               Container::new(Row::new().spacing(5)
+                   .push(text(match revision {
+                        Revision::Edited(edited) => edited.to_string(),
+                        _ => String::from_utf8_lossy(&packet.data).to_string()
+                    })
                    .size(15)
                     .color(match packet_state {
                         PacketState::Unknown => Colour::loading(),
                         PacketState::Failed => Colour::error(),
-                        PacketState::Verified => Colour::text()
+                        PacketState::Verified | PacketState::Read => Colour::text(),
+                        PacketState::Discarded => Colour::loading()
+                    }))
+                   .push(match revision {
+                        Revision::Edited(_) => Some(text("(edited)").size(12).color(Colour::loading())),
+                        _ => None
                     })
                )
             },
-            PacketType::Image => {
+            (PacketType::Image, _) => {
                 Container::new(packet.decoded_image.as_ref().map(iced::widget::image))
                     .height(Length::Fixed(512f32))
             },
-            PacketType::Username => Container::new(text(format!("Username Update: {}", String::from_utf8_lossy(&packet.data))))
+            (PacketType::Username, _) => Container::new(text(format!("Username Update: {}", String::from_utf8_lossy(&packet.data)))),
+            (PacketType::File, _) => Container::new(match packet.file_header() {
+                Some(header) => Column::new().spacing(5)
+                    .push(text(format!("{} ({}, {})", header.name, Self::size(header.size), header.mime)).size(15).color(match packet_state {
+                        PacketState::Verified | PacketState::Read => Colour::text(),
+                        PacketState::Failed => Colour::error(),
+                        _ => Colour::loading()
+                    }))
+                    .push(match packet_state {
+                        PacketState::Unknown => Some(Row::new().spacing(10)
+                            .push(progress_bar(0f32..=header.size as f32, progress.unwrap_or(0) as f32).length(Length::Fixed(256f32)))
+                            .push(text(format!("{} of {}", Self::size(progress.unwrap_or(0)), Self::size(header.size))).size(12).color(Colour::loading()))
+                        ),
+                        _ => None
+                    })
+                    .push(match (local, packet_state, packet.path.as_ref()) {
+                        (false, PacketState::Verified | PacketState::Read, Some(path)) => Some(text(format!("Saved to {}", path.display())).size(12).color(Colour::loading())),
+                        _ => None
+                    }),
+                None => Column::new().push(text("Malformed file.").color(Colour::error()))
+            }),
+            (PacketType::Resume | PacketType::GroupAdd | PacketType::GroupLeave | PacketType::Edit | PacketType::Delete | PacketType::React | PacketType::Typing | PacketType::Read | PacketType::Unknown(_), _) => Container::new(text("Unsupported packet.").color(Colour::loading()))
+        };
+
+        // Failed packets wait in the outbox, from which the user may retry or discard them.
+        let state_widget = match packet_state {
+            PacketState::Failed if !local => Some(Row::new().push(text("Not received in full, waiting for the sender to resume.").size(12).color(Colour::error()))),
+            PacketState::Failed => Some(Row::new().spacing(5)
+                .push(text("Not delivered, queued for retry.").size(12).color(Colour::error()))
+                .push(button(text("RETRY").size(12)).on_press(ChatMessage::RetryPacket(chat, index).into()).style(button_style))
+                .push(button(text("DISCARD").size(12)).on_press(ChatMessage::DiscardPacket(chat, index).into()).style(button_style))
+            ),
+            PacketState::Discarded => Some(Row::new().push(text("Discarded.").size(12).color(Colour::loading()))),
+            PacketState::Read if local => Some(Row::new().push(text("✓✓ Read").size(12).color(Colour::success()))),
+            _ => None
+        };
+
+        // A quoted preview of the packet this one replies to, which scrolls to it when pressed.
+        let reply_widget = reply.map(|(parent_author, preview, parent)| button(
+            Column::new()
+                .push(text(parent_author).size(12).color(Colour::accent()))
+                .push(text(preview).size(12).color(Colour::loading()))
+        ).on_press(ChatMessage::JumpTo(parent).into()).style(button_style));
+
+        // Any delivered packet may be replied to, and our own may be amended. Only messages can be edited.
+        let action_widget = match (packet_state, revision, packet.kind) {
+            (PacketState::Verified | PacketState::Read, Revision::Original | Revision::Edited(_), kind @ (PacketType::Message | PacketType::Image | PacketType::File)) => Some(Row::new().spacing(5)
+                .push(button(text("REPLY").size(12)).on_press(ChatMessage::ReplyTo(chat, index).into()).style(button_style))
+                .push(button(text("REACT").size(12)).on_press(ChatMessage::ToggleReactions(chat, index).into()).style(button_style))
+                .push(match (local, kind) {
+                    (true, PacketType::Message) => Some(button(text("EDIT").size(12)).on_press(ChatMessage::EditPacket(chat, index).into()).style(button_style)),
+                    _ => None
+                })
+                .push(match local {
+                    true => Some(button(text("DELETE").size(12)).on_press(ChatMessage::DeletePacket(chat, index).into()).style(button_style)),
+                    false => None
+                })
+            ),
+            _ => None
         };
 
+        // Pressing a reaction toggles our own. Reactions we have made are outlined.
+        let reaction_widget = (!reactions.is_empty() && revision != Revision::Deleted).then(|| Row::from_iter(reactions.into_iter().map(|(emoji, count, mine)|
+            button(text(format!("{emoji} {count}")).size(12))
+                .on_press(ChatMessage::React(chat, index, emoji).into())
+                .style(move |theme, status| button::Style {
+                    border: Border::default().rounded(10).color(Colour::text()).width(if mine { 1 } else { 0 }),
+                    ..button_style(theme, status)
+                })
+                .into()
+        )).spacing(5));
+
+        let palette_widget = palette.then(|| Row::from_iter(REACTIONS.iter().map(|emoji|
+            button(text(*emoji).size(15)).on_press(ChatMessage::React(chat, index, emoji.to_string()).into()).style(button_style).into()
+        )).spacing(5));
+
+        // A packet sent to a group shows how far it has reached each member.
+        let delivery_widget = deliveries.map(|deliveries| Row::from_iter(deliveries.into_iter().map(|(member, state)| {
+            let (symbol, colour) = match state {
+                PacketState::Unknown => ("…", Colour::loading()),
+                PacketState::Failed => ("✗", Colour::error()),
+                PacketState::Verified => ("✓", Colour::success()),
+                PacketState::Discarded => ("–", Colour::loading()),
+                PacketState::Read => ("✓✓", Colour::success())
+            };
+            text(format!("{member} {symbol}")).size(12).color(colour).into()
+        })).spacing(10));
+
         Container::new(
             Column::new()
-                .push(if headerless { None } else { Some(text(author).color(Colour::accent()).size(20)) })
-                .push(content_widget)
+                .push(if headerless { None } else { Some(Row::new().spacing(10).align_y(Alignment::End)
+                    .push(text(author).color(Colour::accent()).size(20))
+                    .push(if verified { Some(text("✔").size(15).color(Colour::success())) } else { None })
+                    .push(time.clone().map(|time| text(time).size(12).color(Colour::loading())))
+                ) })
+                .push(reply_widget)
+                .push(Row::new().spacing(10).align_y(Alignment::End)
+                    .push(content_widget)
+                    .push(time.filter(|_| headerless).map(|time| text(time).size(12).color(Colour::loading())))
+                )
+                .push(reaction_widget)
+                .push(delivery_widget)
+                .push(state_widget)
+                .push(action_widget)
+                .push(palette_widget)
         )
     }
+
+    /// A heading between the packets of one day and the next.
+    pub fn separator<'a>(label: String) -> Container<'a, Message> {
+        Container::new(text(label).size(12).color(Colour::loading())).center_x(Length::Fill)
+    }
+
+    /// A byte count in the largest unit that keeps it above one.
+    fn size(bytes: u64) -> String {
+        let units = ["B", "KB", "MB", "GB", "TB"];
+        let mut size = bytes as f64;
+        let mut unit = 0;
+
+        while size >= 1024f64 && unit < units.len() - 1 {
+            size /= 1024f64;
+            unit += 1;
+        }
+
+        match unit {
+            0 => format!("{bytes} B"),
+            _ => format!("{size:.1} {}", units[unit])
+        }
+    }
 }
@@ -0,0 +1,76 @@
+use iced::widget::Column;
+use iced::widget::Container;
+use iced::widget::Row;
+use iced::widget::Scrollable;
+use iced::widget::button;
+use iced::widget::container;
+use iced::widget::text;
+use iced::{Background, Border, Length, Shadow};
+use crate::frontend::message::{Global, Message};
+use crate::frontend::notification::Notification;
+use crate::frontend::widget::{Colour, button_style};
+
+const TOAST_WIDTH: f32 = 320f32;
+const HISTORY_HEIGHT: f32 = 400f32;
+
+pub struct ToastWidget;
+impl ToastWidget {
+
+    /// A notification on screen, outlined in the colour of its kind. A notification raised more than once shows how many times.
+    /// Any technical details are hidden until expanded.
+    pub fn parse<'a>(id: u64, notification: &'a Notification, count: usize, expanded: bool) -> Container<'a, Message> {
+        let colour = notification.colour();
+
+        Container::new(
+            Row::new().spacing(10)
+                .push(Column::new().spacing(5).width(Length::Fill)
+                    .push(Row::new().spacing(5)
+                        .push(text(notification.heading()).size(15).color(colour))
+                        .push(if count > 1 { Some(text(format!("×{count}")).size(12).color(Colour::loading())) } else { None })
+                    )
+                    .push(notification.body().map(|body| text(body).size(12).color(Colour::text())))
+                    .push(notification.details().map(|_| button(text(if expanded { "HIDE DETAILS" } else { "DETAILS" }).size(10))
+                        .on_press(Global::ToggleNotificationDetails(id).into())
+                        .style(button_style)))
+                    .push(notification.details().filter(|_| expanded).map(|details| text(details).size(10).color(Colour::loading())))
+                )
+                .push(button(text("✕").size(12)).on_press(Global::DismissNotification(id).into()).style(button_style))
+        ).padding(10).width(Length::Fixed(TOAST_WIDTH)).style(move |_|
+            container::Style {
+                background: Some(Background::Color(Colour::foreground())),
+                text_color: None,
+                border: Border::default().rounded(10).width(1).color(colour),
+                shadow: Shadow::default(),
+                snap: false
+            }
+        )
+    }
+
+    /// Every past notification, most recent first.
+    pub fn history<'a>(notifications: &'a [Notification]) -> Container<'a, Message> {
+        Container::new(
+            Column::new().spacing(10)
+                .push(Row::new().spacing(10)
+                    .push(text("Notifications").size(20).color(Colour::accent()).width(Length::Fill))
+                    .push(button(text("✕").size(12)).on_press(Global::ToggleNotificationHistory.into()).style(button_style))
+                )
+                .push(match notifications.is_empty() {
+                    true => Some(text("Nothing yet.").size(12).color(Colour::loading())),
+                    false => None
+                })
+                .push(Scrollable::new(Column::from_iter(notifications.iter().map(|notification| Column::new()
+                    .push(text(notification.heading()).size(15).color(notification.colour()))
+                    .push(notification.body().map(|body| text(body).size(12).color(Colour::text())))
+                    .into()
+                )).spacing(10)).height(Length::Shrink))
+        ).padding(10).width(Length::Fixed(TOAST_WIDTH)).max_height(HISTORY_HEIGHT).style(|_|
+            container::Style {
+                background: Some(Background::Color(Colour::foreground())),
+                text_color: None,
+                border: Border::default().rounded(10),
+                shadow: Shadow::default(),
+                snap: false
+            }
+        )
+    }
+}
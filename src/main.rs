@@ -14,7 +14,10 @@ use crate::frontend::message::Global;
 use crate::frontend::message::Message;
 
 fn main() -> iced::Result {
-    iced::application(|| (Application::default(), Task::done(Message::Global(Global::LoadNetworking))), Application::update, Application::view)
+    util::clock::init();
+    util::vault::init();
+    iced::application(|| (Application::default(), Task::done(Message::Global(Global::Start))), Application::update, Application::view)
         .title("rift")
+        .subscription(Application::subscription)
         .run()
 }
@@ -0,0 +1,229 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use iroh::EndpointId;
+use iroh::endpoint::{Connection, VarInt};
+
+use crate::error::{Res, StorageError};
+use crate::networking::protocol::Session;
+use crate::util::storage::{data_dir, read_sealed, write_sealed};
+use crate::util::sync;
+
+const BLOCKLIST_FILE: &str = "blocklist";
+
+// The application error code given to peers whose connections we close.
+const REFUSED: VarInt = VarInt::from_u32(1);
+
+/// Who may open a connection to us. Peers we dial ourselves are always let in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AccessPolicy {
+    ContactsOnly,
+    #[default]
+    Ask,        // Contacts and peers we already chat with are let in, anyone else waits for our approval.
+    Open
+}
+
+impl AccessPolicy {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            AccessPolicy::ContactsOnly => 0,
+            AccessPolicy::Ask => 1,
+            AccessPolicy::Open => 2
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Res<AccessPolicy> {
+        Ok(match byte {
+            0 => AccessPolicy::ContactsOnly,
+            1 => AccessPolicy::Ask,
+            2 => AccessPolicy::Open,
+            _ => return Err(StorageError::CorruptSettings.into())
+        })
+    }
+
+    /// The next policy, from the most to the least restrictive and back.
+    pub fn next(self) -> AccessPolicy {
+        match self {
+            AccessPolicy::ContactsOnly => AccessPolicy::Ask,
+            AccessPolicy::Ask => AccessPolicy::Open,
+            AccessPolicy::Open => AccessPolicy::ContactsOnly
+        }
+    }
+}
+
+/// What becomes of an incoming connection, decided before any packet is read from it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Admit,
+    Ask,
+    Refuse
+}
+
+/// The access policy, the blocklist, and the connections waiting for our approval.
+/// Shared between the frontend, which sets the policy and answers requests, and the listening thread, which enforces it.
+/// Survives networking restarts, so a new identity keeps the same blocklist.
+#[derive(Debug, Clone, Default)]
+pub struct AccessControl {
+    state: Arc<Mutex<State>>
+}
+
+#[derive(Debug, Default)]
+struct State {
+    policy: AccessPolicy,
+    blocked: HashSet<EndpointId>,                           // Persisted, unlike everything below.
+    unreadable: bool,                                       // Whether the stored blocklist could not be read, so is neither trusted nor saved over.
+    contacts: HashSet<EndpointId>,
+    known: HashSet<EndpointId>,                             // Peers we already have a chat with.
+    rejected: HashSet<EndpointId>,                          // Peers turned away since startup, who are not asked about again.
+    pending: HashMap<EndpointId, (Connection, Session)>     // Connections that completed the handshake and await our approval.
+}
+
+impl AccessControl {
+
+    /*
+        Encoding
+        The blocklist is the concatenation of every blocked EndpointId, 32 bytes each.
+    */
+
+    /// Read the stored blocklist, if one has been saved.
+    /// An unreadable blocklist is kept as it is, and every peer that dials us is asked about until it is reset.
+    pub fn load_blocklist(&self) -> Res<()> {
+        let blocked = Self::read_blocklist();
+        let mut state = self.lock();
+        state.unreadable = blocked.is_err();
+        state.blocked = blocked?;
+        Ok(())
+    }
+
+    fn read_blocklist() -> Res<HashSet<EndpointId>> {
+        let path = data_dir()?.join(BLOCKLIST_FILE);
+        if !path.exists() { return Ok(HashSet::new()); }
+
+        let bytes = read_sealed(&path)?;
+        let (chunks, rest) = bytes.as_chunks::<32>();
+        if !rest.is_empty() { return Err(StorageError::CorruptBlocklist.into()); }
+
+        chunks.iter()
+            .map(|chunk| EndpointId::from_bytes(chunk).map_err(|_| StorageError::CorruptBlocklist.into()))
+            .collect()
+    }
+
+    /// Whether the stored blocklist could not be read.
+    pub fn is_blocklist_unreadable(&self) -> bool {
+        self.lock().unreadable
+    }
+
+    /// Replace an unreadable blocklist with the peers blocked since startup. Everyone it held is forgotten.
+    pub fn reset_blocklist(&self) -> Res<()> {
+        let mut state = self.lock();
+        state.unreadable = false;
+        Self::save_blocklist(&state)
+    }
+
+    fn save_blocklist(state: &State) -> Res<()> {
+        if state.unreadable { return Err(StorageError::CorruptBlocklist.into()); }
+        let bytes: Vec<u8> = state.blocked.iter().flat_map(|peer| peer.as_bytes().to_vec()).collect();
+        write_sealed(&data_dir()?.join(BLOCKLIST_FILE), bytes)
+    }
+
+    pub fn set_policy(&self, policy: AccessPolicy) {
+        self.lock().policy = policy;
+    }
+
+    pub fn set_contacts(&self, contacts: impl IntoIterator<Item = EndpointId>) {
+        self.lock().contacts = contacts.into_iter().collect();
+    }
+
+    /// Note a peer we have a chat with, which is let in without asking.
+    pub fn know(&self, peer: EndpointId) {
+        self.lock().known.insert(peer);
+    }
+
+    pub fn blocked(&self) -> Vec<EndpointId> {
+        self.lock().blocked.iter().copied().collect()
+    }
+
+    /// Block a peer, closing its connection if it is waiting for approval.
+    /// The peer stays blocked until restart even if the blocklist cannot be saved.
+    pub fn block(&self, peer: EndpointId) -> Res<()> {
+        let mut state = self.lock();
+        if let Some((connection, _)) = state.pending.remove(&peer) {
+            connection.close(REFUSED, b"blocked");
+        }
+        state.blocked.insert(peer);
+        Self::save_blocklist(&state)
+    }
+
+    pub fn unblock(&self, peer: &EndpointId) -> Res<()> {
+        let mut state = self.lock();
+        if !state.blocked.remove(peer) { return Ok(()); }
+        Self::save_blocklist(&state)
+    }
+
+    /// Whether a peer that has dialed us may connect, must be approved first, or is turned away.
+    pub fn admission(&self, peer: &EndpointId) -> Admission {
+        let state = self.lock();
+        if state.blocked.contains(peer) || state.rejected.contains(peer) { return Admission::Refuse; }
+
+        // Without the blocklist, any peer may be one we blocked, so none is let in without asking.
+        match state.policy {
+            AccessPolicy::ContactsOnly if !state.contacts.contains(peer) => return Admission::Refuse,
+            _ if state.unreadable => return Admission::Ask,
+            _ => ()
+        }
+        if state.contacts.contains(peer) { return Admission::Admit; }
+
+        match state.policy {
+            AccessPolicy::Open => Admission::Admit,
+            AccessPolicy::Ask if state.known.contains(peer) => Admission::Admit,
+            AccessPolicy::Ask => Admission::Ask,
+            AccessPolicy::ContactsOnly => Admission::Refuse
+        }
+    }
+
+    /// Hold a connection until it is approved or rejected. A newer connection from the same peer replaces the one held.
+    /// Returns whether the peer was not already waiting.
+    pub fn hold(&self, peer: EndpointId, connection: Connection, session: Session) -> bool {
+        match self.lock().pending.insert(peer, (connection, session)) {
+            Some((previous, _)) => {
+                previous.close(REFUSED, b"superseded");
+                false
+            }
+            None => true
+        }
+    }
+
+    /// Release a held connection once it is approved. The peer is let in without asking from then on.
+    pub fn approve(&self, peer: EndpointId) -> Option<(Connection, Session)> {
+        let mut state = self.lock();
+        state.known.insert(peer);
+        state.pending.remove(&peer)
+    }
+
+    /// Close a held connection, and refuse the peer until restart.
+    pub fn reject(&self, peer: EndpointId) {
+        let mut state = self.lock();
+        if let Some((connection, _)) = state.pending.remove(&peer) {
+            connection.close(REFUSED, b"rejected");
+        }
+        state.rejected.insert(peer);
+    }
+
+    /// Forget a held connection that the peer closed, returning whether it was still held.
+    pub fn withdraw(&self, peer: &EndpointId, stable_id: usize) -> bool {
+        let mut state = self.lock();
+        match state.pending.get(peer) {
+            Some((connection, _)) if connection.stable_id() == stable_id => state.pending.remove(peer).is_some(),
+            _ => false
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        sync::lock(&self.state)
+    }
+}
+
+/// Turn away a connection without reading anything from it.
+pub fn refuse(connection: &Connection) {
+    connection.close(REFUSED, b"refused");
+}
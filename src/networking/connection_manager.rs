@@ -1,16 +1,28 @@
-use std::collections::HashMap;
-
 use async_channel::Sender;
 use async_channel::unbounded;
 use iroh::Endpoint;
+use iroh::EndpointId;
+use iroh::endpoint::Connection;
 use tokio::task::JoinHandle;
 
+use crate::error::ChannelError;
 use crate::error::ChatError;
 use crate::error::Error;
 use crate::error::Res;
+use crate::networking::access::AccessControl;
+use crate::networking::access::Admission;
+use crate::networking::access::refuse;
+use crate::networking::error::NetworkError;
 use crate::networking::packet::Packet;
+use crate::networking::packet::PacketType;
 use crate::networking::packet::TrackedPacket;
+use crate::networking::peer::PeerTable;
+use crate::networking::protocol::Session;
 use crate::networking::server::Foreign;
+use crate::networking::supervisor::Supervisor;
+use crate::networking::supervisor::SupervisorMessage;
+use crate::networking::transfer::TransferEvent;
+use crate::networking::window::ReceiveWindow;
 use crate::util::channel::send;
 
 type Send = async_channel::Sender<ConnectionManagerMessage>;
@@ -20,41 +32,64 @@ type Recv = async_channel::Receiver<ConnectionManagerMessage>;
 pub enum ConnectionManagerMessage {
     Quit,
     Add(Foreign),
+    Closed(EndpointId, usize, String),      // A connection (by stable_id) to the peer has closed for the given reason.
+    Block(EndpointId),                      // Close the peer's live connection and stop redialing it.
     Error(Error),
 
     // Output
-    SuccessfulConnection(usize),
+    SuccessfulConnection(EndpointId),
+    Disconnected(EndpointId, String),       // The peer no longer has any live connection.
+    Reconnecting(EndpointId, u32),          // The Supervisor is making the given attempt to redial the peer.
+    Request(EndpointId),                    // The peer dialed us and is waiting for our approval.
+    Withdrawn(EndpointId),                  // The peer gave up waiting for our approval.
+    Awaiting(EndpointId),                   // A peer we dialed is holding the connection until its user approves it.
 
     // Message
-    Message(TrackedPacket)                  // Signal the management thread to find a client with this stable_id and distribute the packet to it.
+    Message(TrackedPacket)                  // Signal the management thread to find the live connection to this peer and distribute the packet to it.
 }
 
+/// The recipients of a TrackedPacket.
 #[derive(Debug, Clone)]
-pub enum Distribution {}
+pub enum Distribution {
+    Peer(EndpointId),
+    Group(Vec<EndpointId>)      // Each member is sent their own copy, and delivery to each is reported separately.
+}
+
+impl Distribution {
+    pub fn recipients(&self) -> Vec<EndpointId> {
+        match self {
+            Distribution::Peer(peer) => vec![*peer],
+            Distribution::Group(members) => members.clone()
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct ConnectionManager {
     _listen_handle: JoinHandle<Res<()>>,
     _manage_handle: JoinHandle<Res<()>>,
+    _supervise_handle: JoinHandle<Res<()>>,
     sender_to_thread: Send,
     output: Recv
 }
 
 impl ConnectionManager {
 
-    pub fn new(endpoint: Endpoint, packet_sender: Sender<(usize, Packet)>) -> ConnectionManager {
+    pub fn new(endpoint: Endpoint, packet_sender: Sender<(EndpointId, Packet)>, transfer_sender: Sender<TransferEvent>, window: ReceiveWindow, access: AccessControl) -> ConnectionManager {
         let (thread_sender, thread_receiver) = unbounded();
         let (output_sender, output_receiver) = unbounded();
+        let (supervisor_sender, supervisor_receiver) = unbounded();
 
         ConnectionManager {
-            _listen_handle: tokio::task::spawn(Self::listen(endpoint, thread_sender.clone(), output_sender.clone(), packet_sender)),
-            _manage_handle: tokio::task::spawn(Self::manage(thread_receiver, output_sender)),
+            _listen_handle: tokio::task::spawn(Self::listen(endpoint.clone(), thread_sender.clone(), output_sender.clone(), packet_sender.clone(), transfer_sender.clone(), window.clone(), access)),
+            _manage_handle: tokio::task::spawn(Self::manage(thread_sender.clone(), thread_receiver, output_sender.clone(), supervisor_sender)),
+            _supervise_handle: tokio::task::spawn(Supervisor::supervise(endpoint, supervisor_receiver, thread_sender.clone(), output_sender, packet_sender, transfer_sender, window)),
             sender_to_thread: thread_sender,
             output: output_receiver
         }
     }
 
-    async fn listen(endpoint: Endpoint, task_sender: Send, output: Send, packet_sender: Sender<(usize, Packet)>) -> Res<()> {
+    async fn listen(endpoint: Endpoint, task_sender: Send, output: Send, packet_sender: Sender<(EndpointId, Packet)>, transfer_sender: Sender<TransferEvent>, window: ReceiveWindow, access: AccessControl) -> Res<()> {
         loop {
             let res = match endpoint.accept().await {
                 Some(accept) => accept.await,
@@ -65,45 +100,121 @@ impl ConnectionManager {
 
             match res {
 
-                // A new connection has been aquired.
-                Ok(connection) => send(ConnectionManagerMessage::Add(Foreign::new(connection, packet_sender.clone())), &task_sender).await?,
+                // A new connection has been aquired. Handshake off the listening thread so that a slow peer cannot stall others.
+                // Peers the access policy turns away are closed before the handshake, so nothing they send is ever read.
+                Ok(connection) => {
+                    let admission = access.admission(&connection.remote_id());
+                    if admission == Admission::Refuse {
+                        refuse(&connection);
+                        continue;
+                    }
+
+                    let (task_sender, output, packet_sender, transfer_sender, window, access) = (task_sender.clone(), output.clone(), packet_sender.clone(), transfer_sender.clone(), window.clone(), access.clone());
+                    tokio::spawn(async move {
+                        match admission {
+                            Admission::Ask => match Session::respond(&connection, true).await {
+                                Ok(session) => Self::hold(connection, session, access, output).await,
+                                Err(error) => send(ConnectionManagerMessage::Error(error), &output).await
+                            },
+                            _ => match Foreign::accept(connection, packet_sender, transfer_sender, window).await {
+                                Ok(foreign) => send(ConnectionManagerMessage::Add(foreign), &task_sender).await,
+                                Err(error) => send(ConnectionManagerMessage::Error(error), &output).await
+                            }
+                        }
+                    });
+                },
                 Err(e) => send(ConnectionManagerMessage::Error(e.into()), &output).await?
             }
         }
     }
 
-    async fn manage(receiver: Recv, sender: Send) -> Res<()> {
+    /// Hold a connection for our approval, without reading from it, and withdraw the request if the peer gives up first.
+    /// The peer was told during the handshake that it is held, so it neither times out nor sends anything while it waits,
+    /// and does not count us as connected until approved. Nothing is routed to it meanwhile, so packets for it are queued as for an offline peer.
+    async fn hold(connection: Connection, session: Session, access: AccessControl, output: Send) -> Result<(), ChannelError> {
+        let (peer, stable_id) = (connection.remote_id(), connection.stable_id());
+        if access.hold(peer, connection.clone(), session) {
+            send(ConnectionManagerMessage::Request(peer), &output).await?;
+        }
+
+        connection.closed().await;
+        if access.withdraw(&peer, stable_id) {
+            send(ConnectionManagerMessage::Withdrawn(peer), &output).await?;
+        }
+        Ok(())
+    }
+
+    async fn manage(task_sender: Send, receiver: Recv, sender: Send, supervisor: Sender<SupervisorMessage>) -> Res<()> {
 
-        let mut connections: HashMap<usize, Foreign> = HashMap::new();
+        let mut peers = PeerTable::default();
 
         while let Ok(task) = receiver.recv().await {
             match task {
-                ConnectionManagerMessage::Quit => return Ok(()),
+                ConnectionManagerMessage::Quit => {
+                    send(SupervisorMessage::Quit, &supervisor).await?;
+                    return Ok(())
+                },
                 ConnectionManagerMessage::Add(connection) => {
-                    send(ConnectionManagerMessage::SuccessfulConnection(connection.stable_id()), &sender).await?;
-                    let _ = connections.insert(connection.stable_id(), connection);
+                    send(ConnectionManagerMessage::SuccessfulConnection(connection.remote_id()), &sender).await?;
+
+                    if connection.dialed() {
+                        send(SupervisorMessage::Dialed(connection.remote_id()), &supervisor).await?;
+                    }
+                    send(SupervisorMessage::Connected(connection.remote_id()), &supervisor).await?;
+
+                    // Watch the connection so that it is evicted as soon as it dies.
+                    let watched = connection.clone();
+                    let task_sender = task_sender.clone();
+                    tokio::spawn(async move {
+                        let reason = watched.closed().await;
+                        send(ConnectionManagerMessage::Closed(watched.remote_id(), watched.stable_id(), reason), &task_sender).await
+                    });
+
+                    let _ = peers.bind(connection);
+                },
+                ConnectionManagerMessage::Closed(peer, stable_id, reason) => {
+                    // A superseded connection closing does not mean the peer is gone.
+                    if peers.unbind(&peer, stable_id) {
+                        send(ConnectionManagerMessage::Disconnected(peer, reason), &sender).await?;
+                        send(SupervisorMessage::Lost(peer), &supervisor).await?;
+                    }
+                },
+                // The watcher reports the closure, so the peer is then disconnected as usual.
+                ConnectionManagerMessage::Block(peer) => {
+                    send(SupervisorMessage::Forget(peer), &supervisor).await?;
+                    if let Some(foreign) = peers.route(&peer) {
+                        foreign.close();
+                    }
                 },
                 ConnectionManagerMessage::Message(mut tracked_packet) => {
 
+                    let recipients = tracked_packet.recipient.recipients();
                     let packet = match tracked_packet.take_packet().await {
                         Some(packet) => packet,
                         None => {
-                            tracked_packet.indicate_failure().await?;
+                            for recipient in recipients { tracked_packet.indicate_failure(recipient).await; }
                             continue;
                         }
                     };
-                    
-                    if let Some(foreign) = connections.get(&tracked_packet.recipient_stable_id) {
-                        let kind = packet.kind;
-                        match foreign.distribute(packet).await {
-                            Ok(is_valid) => if !is_valid {
-                                if kind.verify() { tracked_packet.indicate_failure().await?; }
-                                send(ConnectionManagerMessage::Error(ChatError::InvalidCode.into()), &sender).await?
-                            } else if kind.verify() { tracked_packet.confirm_success().await? },
-                            Err(error) => {
-                                if kind.verify() { tracked_packet.indicate_failure().await?; }
-                                send(ConnectionManagerMessage::Error(error), &sender).await?
+
+                    let fan_out = matches!(tracked_packet.recipient, Distribution::Group(_));
+                    for recipient in recipients {
+                        let foreign = match peers.route(&recipient) {
+                            Some(foreign) => foreign,
+
+                            // Fail immediately rather than waiting on a connection that does not exist.
+                            // An offline member is expected of a group, so only a direct packet reports an error, and only if it is worth reporting.
+                            None => {
+                                if packet.kind.verify() { tracked_packet.indicate_failure(recipient).await; }
+                                if !fan_out && !packet.kind.is_ephemeral() { send(ConnectionManagerMessage::Error(NetworkError::PeerOffline.into()), &sender).await?; }
+                                continue;
                             }
+                        };
+
+                        // Files, and the copies of a group packet, are delivered on their own tasks so that they do not hold up the packets behind them.
+                        match packet.kind == PacketType::File || fan_out {
+                            true => { tokio::spawn(Self::deliver(foreign.clone(), tracked_packet.clone(), packet.clone(), sender.clone())); },
+                            false => Self::deliver(foreign.clone(), tracked_packet.clone(), packet.clone(), sender.clone()).await?
                         }
                     }
                 }
@@ -114,6 +225,23 @@ impl ConnectionManager {
         Ok(())
     }
 
+    /// Distribute a packet to a peer's live connection, reporting the outcome through the TrackedPacket.
+    async fn deliver(foreign: Foreign, tracked_packet: TrackedPacket, packet: Packet, sender: Send) -> Res<()> {
+        let (kind, recipient) = (packet.kind, foreign.remote_id());
+        match foreign.distribute(packet).await {
+            Ok(is_valid) => if !is_valid {
+                if kind.verify() { tracked_packet.indicate_failure(recipient).await; }
+                send(ConnectionManagerMessage::Error(ChatError::UnconfirmedDelivery.into()), &sender).await?
+            } else if kind.verify() { tracked_packet.confirm_success(recipient).await },
+            Err(error) => {
+                if kind.verify() { tracked_packet.indicate_failure(recipient).await; }
+                if !kind.is_ephemeral() { send(ConnectionManagerMessage::Error(error), &sender).await? }
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn yield_sender(&self) -> Send {
         self.sender_to_thread.clone()
     }
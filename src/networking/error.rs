@@ -1,5 +1,32 @@
+use std::fmt::{Display, Formatter};
+
 #[derive(Debug, Clone)]
 pub enum NetworkError {
     InvalidPacket,
-    MalformedCode
+    MalformedCode,
+    PeerOffline,
+    IncompatibleVersion,
+    InvalidHandshake,
+    Unsupported,
+    TransferInterrupted,
+    MalformedTicket,
+    ExpiredTicket
 }
+
+impl Display for NetworkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            NetworkError::InvalidPacket => "A malformed packet was received",
+            NetworkError::MalformedCode => "The peer's confirmation was malformed",
+            NetworkError::PeerOffline => "The peer is offline",
+            NetworkError::IncompatibleVersion => "The peer's version of Rift is incompatible",
+            NetworkError::InvalidHandshake => "The peer's handshake was invalid",
+            NetworkError::Unsupported => "The peer's version of Rift does not support this",
+            NetworkError::TransferInterrupted => "The file transfer was interrupted",
+            NetworkError::MalformedTicket => "That is not a valid node ID or invite",
+            NetworkError::ExpiredTicket => "The invite has expired"
+        })
+    }
+}
+
+impl std::error::Error for NetworkError {}
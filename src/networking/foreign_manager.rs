@@ -1,42 +1,81 @@
 use std::time::Duration;
 
 use async_channel::Sender;
+use iroh::EndpointId;
 use iroh::endpoint::Connection;
 use tokio::task::JoinHandle;
 
-use crate::{error::{ChannelError, Res}, networking::{error::NetworkError, packet::Packet}, util::channel::send};
+use crate::{error::{ChannelError, Res}, networking::{error::NetworkError, packet::{MAXIMUM_DATA_LENGTH, Packet, PacketType}, protocol::Session, transfer::{TransferEvent, receive_file, send_file}, window::ReceiveWindow}, util::channel::send};
+
+const ACKNOWLEDGEMENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// The tail of a file may still be in flight when the stream is finished, so files are given longer to be acknowledged.
+const FILE_ACKNOWLEDGEMENT_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug)]
 pub struct ForeignManager {
    connection: Connection,
+   session: Session,
+   transfer_sender: Sender<TransferEvent>,
    _receive_handle: JoinHandle<Res<()>>
 }
 
 impl ForeignManager {
 
-    pub fn new(connection: Connection, packet_sender: Sender<(usize, Packet)>) -> ForeignManager {
+    pub fn new(connection: Connection, session: Session, packet_sender: Sender<(EndpointId, Packet)>, transfer_sender: Sender<TransferEvent>, window: ReceiveWindow) -> ForeignManager {
         ForeignManager {
             connection: connection.clone(),
-            _receive_handle: tokio::spawn(ForeignManager::receive(connection, packet_sender))
+            session,
+            transfer_sender: transfer_sender.clone(),
+            _receive_handle: tokio::spawn(ForeignManager::receive(connection, session, packet_sender, transfer_sender, window))
         }
     }
 
     /// Establish a bi-directional channel through which the message can be streamed.
     /// Ok(bool) represents the message being sent correctly, and the boolean indicates whether a confirmation was received.
+    /// Packets sent without FLAG_VERIFY are not acknowledged, and always yield Ok(true) once written.
+    /// The contents of a File packet are streamed from disk after its frame, reporting progress through the transfer sender.
     /// This function yields a future that must be executed.
-    pub async fn send_task(connection: Connection, packet: Packet) -> Res<bool> {
+    pub async fn send_task(connection: Connection, session: Session, packet: Packet, transfer_sender: Sender<TransferEvent>) -> Res<bool> {
+
+        // Never send a peer something it has not told us it understands.
+        // Ephemeral packets are only a courtesy, so a peer that does not understand them simply goes without.
+        if !session.supports(&packet) {
+            return match packet.kind.is_ephemeral() {
+                true => Ok(true),
+                false => Err(NetworkError::Unsupported.into())
+            };
+        }
+        let packet = session.adapt(packet);
 
         // Open a bi-directional channel to the targetted connection (usually a clone)
         let (mut send, mut recv) = connection.open_bi().await?;
         // Cache the security code
         let expected_reply = packet.code;
+        // Legacy peers acknowledge everything, so they must always be waited on.
+        let verify = session.is_legacy() || packet.verify();
+        let (id, path) = (packet.id, packet.path.clone());
+        let timeout = match packet.kind {
+            PacketType::File => FILE_ACKNOWLEDGEMENT_TIMEOUT,
+            _ => ACKNOWLEDGEMENT_TIMEOUT
+        };
+
         // Write all the bytes into the stream before closing it, idiomatically signalling the end of this discrete packet.
-        let bytes = &packet.to_bytes();
+        let bytes = &match session.is_legacy() {
+            true => packet.to_legacy_bytes(),
+            false => packet.to_bytes()
+        };
         send.write_all(bytes).await?;
+
+        if let Some(path) = path {
+            send_file(&mut send, &mut recv, &path, id, &transfer_sender).await?;
+        }
         send.finish()?;
 
+        if !verify { return Ok(true); }
+
         // Create a buffer to accept the verification code.
-        match tokio::time::timeout(Duration::from_secs(2), recv.read_to_end(4)).await {
+        match tokio::time::timeout(timeout, recv.read_to_end(4)).await {
             Ok(read_result) => {
                 let buffer = match read_result {
                     Ok(buffer) => buffer,
@@ -60,34 +99,57 @@ impl ForeignManager {
         }
     }
 
-    pub async fn receive(connection: Connection, packet_sender: Sender<(usize, Packet)>) -> Res<()> {
+    /// Repeats of packets already received, identified by their message id, are acknowledged again but not delivered twice.
+    /// Legacy packets carry no message id, so cannot be recognised as repeats.
+    pub async fn receive(connection: Connection, session: Session, packet_sender: Sender<(EndpointId, Packet)>, transfer_sender: Sender<TransferEvent>, window: ReceiveWindow) -> Res<()> {
 
-        let author: usize = connection.stable_id();
+        let author: EndpointId = connection.remote_id();
         
         loop {
 
             // Accept a single bidirectional channel instance for this packet exchange.
+            // An error here means the connection has closed, which the ConnectionManager observes and reports separately.
             let (mut sender, mut receiver) = match connection.accept_bi().await {
                 Ok(v) => v,
-                _ => return Ok(())
+                Err(error) => return Err(error.into())
             };
 
 
-            let buffer = match receiver.read_to_end(1000000000).await {
-                Ok(buffer) => buffer,
+            // Framed packets are read one frame at a time, so nothing larger than a frame is ever buffered.
+            let packet = match session.is_legacy() {
+                true => match receiver.read_to_end(MAXIMUM_DATA_LENGTH).await {
+                    Ok(buffer) => Packet::from_legacy_bytes(buffer),
+                    Err(error) => Err(error.into())
+                },
+                false => Packet::read_from(&mut receiver).await
+            };
 
-                // All errors indicate failure.
+            // A malformed frame only costs the packet it arrived in, not the connection.
+            let packet = match packet {
+                Ok(packet) => packet,
                 Err(_) => {
-                    sender.finish()?;
+                    let _ = sender.finish();
                     continue;
                 }
             };
 
-            let packet = Packet::from_bytes(buffer)?;
-            sender.write_all(&packet.code.to_be_bytes()).await?;
+            // The contents of a file follow its frame. They are received on their own task, which acknowledges the file once verified,
+            // so that other packets are not held up behind it.
+            if let PacketType::File = packet.kind {
+                tokio::spawn(receive_file(receiver, sender, packet, author, transfer_sender.clone(), window.clone()));
+                continue;
+            }
+
+            if packet.verify() { sender.write_all(&packet.code.to_be_bytes()).await?; }
             let _ = sender.finish();
 
-            // Send the packet off to be processed, alongside this connection id.
+            // Packets from newer versions are acknowledged so the sender does not retry them, but are otherwise dropped.
+            if let PacketType::Unknown(_) = packet.kind { continue; }
+
+            // A repeat was acknowledged above in case the first acknowledgement was lost, but has already been delivered.
+            if !session.is_legacy() && !window.admit(author, packet.id) { continue; }
+
+            // Send the packet off to be processed, alongside the id of the peer.
             send((author, packet), &packet_sender).await?;
         }
 
@@ -96,4 +158,12 @@ impl ForeignManager {
     pub fn clone_connection(&self) -> Connection {
         self.connection.clone()
     }
+
+    pub fn session(&self) -> Session {
+        self.session
+    }
+
+    pub fn transfer_sender(&self) -> Sender<TransferEvent> {
+        self.transfer_sender.clone()
+    }
 }
@@ -0,0 +1,95 @@
+use iroh::EndpointId;
+
+use crate::error::Res;
+use crate::networking::error::NetworkError;
+
+/// Peers advertising this capability accept packets addressed to a group, along with PacketType::GroupAdd and GroupLeave.
+pub const CAPABILITY_GROUP: u32 = 0b0000_0100;
+
+/// A globally unique identifier for a group, chosen at random by whoever creates it.
+pub type GroupId = u128;
+
+/// The name and members of a group. Every member holds its own copy, kept in step by GroupAdd and GroupLeave packets.
+/// The local peer is never in its own member list.
+#[derive(Debug, Clone, Default)]
+pub struct Membership {
+    pub name: String,
+    pub members: Vec<EndpointId>
+}
+
+impl Membership {
+
+    pub fn new(name: String) -> Membership {
+        Membership { name, members: Vec::new() }
+    }
+
+    pub fn contains(&self, peer: &EndpointId) -> bool {
+        self.members.contains(peer)
+    }
+
+    /// Add a member, returning whether they were not already present.
+    pub fn add(&mut self, peer: EndpointId) -> bool {
+        if self.contains(&peer) { return false; }
+        self.members.push(peer);
+        true
+    }
+
+    pub fn remove(&mut self, peer: &EndpointId) {
+        self.members.retain(|member| member != peer);
+    }
+
+    /*
+        Encoding
+        [name length: u16][name: utf8][members: 32 bytes each]
+    */
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![
+            (self.name.len() as u16).to_be_bytes().to_vec(),
+            self.name.as_bytes().to_vec(),
+            self.members.iter().flat_map(|member| member.as_bytes().to_vec()).collect()
+        ].into_iter().flatten().collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Res<Membership> {
+        let (length, rest) = bytes.split_first_chunk::<2>().ok_or(NetworkError::InvalidPacket)?;
+        let length = u16::from_be_bytes(*length) as usize;
+        if rest.len() < length { return Err(NetworkError::InvalidPacket.into()); }
+        let (name, members) = rest.split_at(length);
+
+        let (members, remainder) = members.as_chunks::<32>();
+        if !remainder.is_empty() { return Err(NetworkError::InvalidPacket.into()); }
+
+        Ok(Membership {
+            name: String::from_utf8_lossy(name).to_string(),
+            members: members.iter()
+                .map(|member| EndpointId::from_bytes(member).map_err(|_| NetworkError::InvalidPacket.into()))
+                .collect::<Res<Vec<EndpointId>>>()?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::SecretKey;
+
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let membership = Membership { name: String::from("friends"), members: (1..=3).map(|seed| SecretKey::from_bytes(&[seed; 32]).public()).collect() };
+        let decoded = Membership::from_bytes(&membership.to_bytes()).unwrap();
+        assert_eq!((decoded.name, decoded.members), (membership.name, membership.members));
+    }
+
+    #[test]
+    fn truncated() {
+        let membership = Membership { name: String::from("friends"), members: vec![SecretKey::from_bytes(&[1; 32]).public()] };
+        let bytes = membership.to_bytes();
+
+        // Cutting the list at a member boundary leaves a shorter, valid one, so only the name and the member itself are checked.
+        for length in (0..bytes.len()).filter(|length| *length != 2 + membership.name.len()) {
+            assert!(Membership::from_bytes(&bytes[..length]).is_err(), "accepted {length} of {} bytes", bytes.len());
+        }
+    }
+}
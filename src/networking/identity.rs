@@ -0,0 +1,128 @@
+use std::fs::read;
+use std::path::{Path, PathBuf};
+
+use iroh::{EndpointId, SecretKey};
+use rand::rng;
+
+use crate::error::{Res, StorageError};
+use crate::util::clock;
+use crate::util::storage::{data_dir, read_sealed, write_private, write_sealed};
+
+const KEY_FILE: &str = "identity.key";
+const RETIRED_EXTENSION: &str = "old";
+
+// Separates the hashing of safety numbers from every other use of BLAKE3 in Rift.
+const SAFETY_NUMBER_CONTEXT: &str = "rift safety number v1";
+
+/// The alphabet of the emoji form of a safety number, one emoji for each six bits.
+const SAFETY_EMOJI: [&str; 64] = [
+    "🐶", "🐱", "🐭", "🐹", "🐰", "🦊", "🐻", "🐼", "🐨", "🐯", "🦁", "🐮", "🐷", "🐸", "🐵", "🐔",
+    "🐧", "🐦", "🐤", "🦆", "🦅", "🦉", "🦇", "🐺", "🐗", "🐴", "🦄", "🐝", "🐛", "🦋", "🐌", "🐞",
+    "🐢", "🐍", "🦎", "🐙", "🦑", "🦀", "🐡", "🐠", "🐟", "🐬", "🐳", "🦈", "🐊", "🐘", "🦒", "🦓",
+    "🍎", "🍐", "🍊", "🍋", "🍌", "🍉", "🍇", "🍓", "🍒", "🍍", "🥝", "🥕", "🌽", "🍄", "🌵", "🌻"
+];
+
+/// The persistent secret key from which our EndpointId is derived.
+/// Generated once and stored in the data directory so that contacts can keep reaching us across restarts.
+pub struct Identity;
+
+impl Identity {
+
+    /// Load the stored secret key, generating and storing a new one if this is the first launch.
+    pub fn load_or_generate() -> Res<SecretKey> {
+        let path = Self::path()?;
+        match path.exists() {
+            true => Self::read_key(&path),
+            false => Self::generate(&path)
+        }
+    }
+
+    /// Deliberately replace the stored identity with a freshly generated one, yielding where the previous key was kept.
+    /// The previous key is kept alongside it so an accidental rotation can be undone by importing it.
+    pub fn rotate() -> Res<(SecretKey, Option<PathBuf>)> {
+        let path = Self::path()?;
+        let retired = Self::retire(&path)?;
+        Ok((Self::generate(&path)?, retired))
+    }
+
+    /// Replace the stored identity with the key file at the given path, yielding where the previous key was kept.
+    pub fn import(source: &Path) -> Res<(SecretKey, Option<PathBuf>)> {
+        let key = Self::read_key(source)?;
+        let path = Self::path()?;
+        let retired = Self::retire(&path)?;
+        write_sealed(&path, key.to_bytes().to_vec())?;
+        Ok((key, retired))
+    }
+
+    /// Write a copy of the stored key to the given path. The copy is never sealed, so that it can be imported without the passphrase.
+    pub fn export(destination: &Path) -> Res<()> {
+        let key = Self::load_or_generate()?;
+        write_private(destination, &key.to_bytes())
+    }
+
+    fn path() -> Res<PathBuf> {
+        Ok(data_dir()?.join(KEY_FILE))
+    }
+
+    /// Copy the stored key, if there is one, to a file named for when it was retired, so that no earlier retired key is overwritten.
+    /// The stored key is left in place, to be replaced in a single write.
+    fn retire(path: &Path) -> Res<Option<PathBuf>> {
+        if !path.exists() { return Ok(None); }
+        let retired = path.with_file_name(format!("{KEY_FILE}.{}.{RETIRED_EXTENSION}", clock::now()));
+        write_private(&retired, &read(path)?)?;
+        Ok(Some(retired))
+    }
+
+    fn generate(path: &Path) -> Res<SecretKey> {
+        let key = SecretKey::generate(&mut rng());
+        write_sealed(path, key.to_bytes().to_vec())?;
+        Ok(key)
+    }
+
+    fn read_key(path: &Path) -> Res<SecretKey> {
+        let bytes: [u8; 32] = read_sealed(path)?.try_into().map_err(|_| StorageError::MalformedKey)?;
+        Ok(SecretKey::from_bytes(&bytes))
+    }
+}
+
+/// A fingerprint of the identities at both ends of a chat. Comparing it in person, or over a call,
+/// confirms that each node ID belongs to whom we think it does, since anyone in the middle would change it.
+/// The two EndpointIds are sorted before hashing, so both ends derive the same number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SafetyNumber([u8; 32]);
+
+impl SafetyNumber {
+
+    pub fn between(local: &EndpointId, peer: &EndpointId) -> SafetyNumber {
+        let (first, second) = match local.as_bytes() <= peer.as_bytes() {
+            true => (local, peer),
+            false => (peer, local)
+        };
+
+        let mut hasher = blake3::Hasher::new_derive_key(SAFETY_NUMBER_CONTEXT);
+        hasher.update(first.as_bytes());
+        hasher.update(second.as_bytes());
+        SafetyNumber(*hasher.finalize().as_bytes())
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> SafetyNumber {
+        SafetyNumber(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Six groups of five digits, each group taken from the next five bytes of the fingerprint.
+    pub fn digits(&self) -> String {
+        self.0.chunks_exact(5).take(6)
+            .map(|chunk| format!("{:05}", chunk.iter().fold(0u64, |value, byte| value << 8 | *byte as u64) % 100_000))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    /// Eight emoji, for comparing at a glance.
+    pub fn emoji(&self) -> String {
+        self.0.iter().take(8).map(|byte| SAFETY_EMOJI[(byte & 0x3F) as usize]).collect::<Vec<&str>>().join(" ")
+    }
+}
@@ -1,19 +1,39 @@
 /*
     Overview
-    Each 'Server' is a permanent IROH node address stored in a database. The Server listens for clients and accepts them as long as they provide the correct ALPN.
+    Each 'Server' is a permanent IROH node address, derived from a secret key persisted in the user's data directory. The Server listens for clients and accepts them as long as they provide the correct ALPN
+    and are let in by the access policy, which may hold a connection until the user approves it (see access.rs).
     Each of the Server's clients is a foreign Server. All communication is done through single-use bidirectional streams.
     Each complete packet exchance (full message / file) is verified after transmission.
+    Peers are dialed by EndpointId alone, their addresses found through discovery, or by an invite ticket carrying their relay and direct addresses (see ticket.rs).
 
     Protocol
-    The first byte of the packet identifies its type.
-    The second-fifth bytes of the packet are a unique 32-bit identifier for that packet that must be echoed back to confirm transmission.
-    The rest of the bytes are 'data' as defined by the standard for that packet type.
+    Every connection opens with a handshake on its first stream, in which both sides exchange the versions and capabilities they support (see protocol.rs).
+    A connection held for approval says so in the reply, and the dialer sends nothing until a stream opened by the other side admits it.
+    Each packet is then a length-prefixed frame headed by the negotiated version and a set of flags.
+    The frame carries a unique 32-bit code that must be echoed back to confirm transmission when the verify flag is set,
+    and a 128-bit message id that identifies the packet across retransmissions.
+    A packet whose message id was received recently is acknowledged again but not delivered twice (see window.rs).
+    Unknown packet types are acknowledged and dropped, so newer peers can introduce them without breaking older ones.
+
+    Legacy Protocol
+    Peers that only speak hchap1/v1 skip the handshake. The first byte of the packet identifies its type.
+    The second-fifth bytes of the packet are the code, which is always echoed back, and the rest of the bytes are 'data'.
 */
 
-const ALPN: &[u8] = b"hchap1/v1";
+const ALPN: &[u8] = b"hchap1/v2";
+const LEGACY_ALPN: &[u8] = b"hchap1/v1";
 
 pub mod server;
+pub mod access;
 pub mod connection_manager;
 pub mod foreign_manager;
+pub mod group;
 pub mod packet;
+pub mod protocol;
 pub mod error;
+pub mod identity;
+pub mod peer;
+pub mod supervisor;
+pub mod ticket;
+pub mod transfer;
+pub mod window;
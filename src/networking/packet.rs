@@ -1,27 +1,80 @@
 use std::io::Cursor;
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
 use iced::widget::image::Handle;
 
 use async_channel::{Receiver, Sender, unbounded};
+use iroh::EndpointId;
+use iroh::endpoint::RecvStream;
 use image::DynamicImage;
 use rand::{Rng, rng};
 
-use crate::{error::Res, networking::error::NetworkError, util::channel::send};
+use crate::{error::Res, networking::{connection_manager::Distribution, error::NetworkError, group::{CAPABILITY_GROUP, GroupId, Membership}, protocol::{FLAG_GROUP, FLAG_REPLY, FLAG_STAMP, FLAG_VERIFY, SUPPORTED_VERSIONS}, transfer::{CAPABILITY_FILE, CAPABILITY_RESUME, FileHeader}}, util::channel::send};
+
+/// A globally unique identifier for a packet, which unlike the code is stable across retransmission.
+pub type MessageId = u128;
+
+/// The largest payload accepted in a single frame. Anything larger should be sent as a file, which is streamed instead.
+pub const MAXIMUM_DATA_LENGTH: usize = 64 * 1024 * 1024;
+
+const HEADER_LENGTH: usize = 27;
+
+/// Peers advertising this capability accept PacketType::Edit and Delete.
+pub const CAPABILITY_EDIT: u32 = 0b0000_1000;
+
+/// Peers advertising this capability accept packets that reply to an earlier message.
+pub const CAPABILITY_REPLY: u32 = 0b0001_0000;
+
+/// Peers advertising this capability accept PacketType::React.
+pub const CAPABILITY_REACT: u32 = 0b0010_0000;
+
+/// Peers advertising this capability accept PacketType::Typing.
+pub const CAPABILITY_TYPING: u32 = 0b0100_0000;
+
+/// Peers advertising this capability accept PacketType::Read.
+pub const CAPABILITY_READ: u32 = 0b1000_0000;
+
+/// Peers advertising this capability accept packets stamped with a logical clock and the time they were sent.
+pub const CAPABILITY_STAMP: u32 = 0b1_0000_0000;
+
+const STAMP_LENGTH: usize = 16;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PacketType {
     Username,
     Message,
-    Image
+    Image,
+    File,
+    Resume,             // Asks the sender of an interrupted file to try again. The data is the file's message id.
+    GroupAdd,           // The name and members of a group, which the recipient merges into its own copy.
+    GroupLeave,         // The author has left the group.
+    Edit,               // Replaces the text of an earlier message by the same author. The data is the message id followed by the new text.
+    Delete,             // Withdraws an earlier packet by the same author. The data is the message id.
+    React,              // Adds or removes the author's emoji on an earlier packet. The data is the message id, whether it is added, then the emoji.
+    Typing,             // Whether the author is composing a message. Never acknowledged or recorded.
+    Read,               // The author has viewed the packets with the given message ids. The data is the ids, one after another.
+
+    // A packet type introduced by a newer version of rift. It is acknowledged but otherwise ignored.
+    Unknown(u8)
 }
 
 impl PacketType {
-    pub fn from_byte(byte: u8) -> Res<PacketType> {
-        Ok(match byte {
+    pub fn from_byte(byte: u8) -> PacketType {
+        match byte {
             0 => PacketType::Username,
             1 => PacketType::Message,
             2 => PacketType::Image,
-            _ => return Err(NetworkError::InvalidPacket.into())
-        })
+            3 => PacketType::File,
+            4 => PacketType::Resume,
+            5 => PacketType::GroupAdd,
+            6 => PacketType::GroupLeave,
+            7 => PacketType::Edit,
+            8 => PacketType::Delete,
+            9 => PacketType::React,
+            10 => PacketType::Typing,
+            11 => PacketType::Read,
+            other => PacketType::Unknown(other)
+        }
     }
 
     pub fn to_byte(self) -> u8 {
@@ -29,6 +82,16 @@ impl PacketType {
             PacketType::Username => 0,
             PacketType::Message => 1,
             PacketType::Image => 2,
+            PacketType::File => 3,
+            PacketType::Resume => 4,
+            PacketType::GroupAdd => 5,
+            PacketType::GroupLeave => 6,
+            PacketType::Edit => 7,
+            PacketType::Delete => 8,
+            PacketType::React => 9,
+            PacketType::Typing => 10,
+            PacketType::Read => 11,
+            PacketType::Unknown(byte) => byte
         }
     }
 
@@ -36,117 +99,449 @@ impl PacketType {
         match self {
             PacketType::Username => false,
             PacketType::Message => true,
-            PacketType::Image => true
+            PacketType::Image => true,
+            PacketType::File => true,
+            PacketType::Resume => false,
+            PacketType::GroupAdd => true,
+            PacketType::GroupLeave => true,
+            PacketType::Edit => true,
+            PacketType::Delete => true,
+            PacketType::React => true,
+            PacketType::Typing => false,
+            PacketType::Read => false,
+            PacketType::Unknown(_) => false
+        }
+    }
+
+    /// Whether this packet type is only a courtesy, so is worth neither retrying nor reporting the failure of.
+    pub fn is_ephemeral(self) -> bool {
+        matches!(self, PacketType::Typing | PacketType::Read)
+    }
+
+    /// Whether this packet type existed in the legacy protocol.
+    pub fn is_legacy(self) -> bool {
+        matches!(self, PacketType::Username | PacketType::Message | PacketType::Image)
+    }
+
+    /// The capability a peer must advertise before it can be sent this packet type, if any.
+    pub fn capability(self) -> Option<u32> {
+        match self {
+            PacketType::File => Some(CAPABILITY_FILE),
+            PacketType::Resume => Some(CAPABILITY_RESUME),
+            PacketType::GroupAdd | PacketType::GroupLeave => Some(CAPABILITY_GROUP),
+            PacketType::Edit | PacketType::Delete => Some(CAPABILITY_EDIT),
+            PacketType::React => Some(CAPABILITY_REACT),
+            PacketType::Typing => Some(CAPABILITY_TYPING),
+            PacketType::Read => Some(CAPABILITY_READ),
+            _ => None
         }
     }
 }
 
+/// Where a packet falls within its conversation. Each conversation keeps a Lamport clock: a packet is stamped with one more
+/// than the highest clock its sender has seen, so a packet is always stamped later than anything its sender had received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Stamp {
+    pub clock: NonZeroU64,
+    pub sent: Option<NonZeroU64>    // Milliseconds since the Unix epoch by the sender's clock, unless the packet predates stamps.
+}
+
+impl Stamp {
+
+    /*
+        Encoding
+        [clock: u64][sent: u64]
+        Clocks begin at one. A sent time of zero is unknown.
+    */
+
+    fn to_bytes(self) -> Vec<u8> {
+        [self.clock.get().to_be_bytes(), self.sent.map_or(0, NonZeroU64::get).to_be_bytes()].concat()
+    }
+
+    fn from_bytes(bytes: &[u8; STAMP_LENGTH]) -> Res<Stamp> {
+        let value = u128::from_be_bytes(*bytes);
+        Ok(Stamp {
+            clock: NonZeroU64::new((value >> 64) as u64).ok_or(NetworkError::InvalidPacket)?,
+            sent: NonZeroU64::new(value as u64)
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Packet {
     pub kind: PacketType,
+    pub flags: u8,
     pub code: u32,
+    pub id: MessageId,
     pub data: Vec<u8>,
-    pub decoded_image: Option<Handle>
-}
+    pub decoded_image: Option<Handle>,
 
-impl Packet {
-    pub fn from_bytes(bytes: Vec<u8>) -> Res<Packet> {
-        let mut iterator = bytes.into_iter();
-        let kind_byte = iterator.next().ok_or(NetworkError::InvalidPacket)?;
-        let kind = PacketType::from_byte(kind_byte)?;
+    // Where a file lives on disk: the source of an outgoing transfer, or the destination of an incoming one.
+    // This is never sent to the peer.
+    pub path: Option<PathBuf>,
 
-        let endians = [
-            iterator.next().ok_or(NetworkError::InvalidPacket)?,
-            iterator.next().ok_or(NetworkError::InvalidPacket)?,
-            iterator.next().ok_or(NetworkError::InvalidPacket)?,
-            iterator.next().ok_or(NetworkError::InvalidPacket)?
-        ];
+    // The group the packet belongs to, if it was not sent directly.
+    pub group: Option<GroupId>,
 
-        let code = u32::from_be_bytes(endians);
-        let data: Vec<u8> = iterator.collect();
+    // The message id of the earlier packet this one replies to.
+    pub in_reply_to: Option<MessageId>,
 
-        let decoded_image = if let PacketType::Image = kind {
-            Some(Handle::from_bytes(data.clone()))
-        } else { None };
+    // Where the packet falls within its conversation. Packets from peers that do not stamp them are stamped on receipt.
+    pub stamp: Option<Stamp>
+}
 
-        Ok(Packet {
+impl Packet {
+
+    /// Create a packet with a fresh code and id.
+    fn new(kind: PacketType, data: Vec<u8>) -> Packet {
+        let mut rng = rng();
+        let code = rng.random_range(u32::MIN..=u32::MAX);
+
+        Packet {
             kind,
+            flags: if kind.verify() { FLAG_VERIFY } else { 0 },
             code,
+            id: rng.random(),
+            decoded_image: if let PacketType::Image = kind { Some(Handle::from_bytes(data.clone())) } else { None },
             data,
-            decoded_image
-        })
+            path: None,
+            group: None,
+            in_reply_to: None,
+            stamp: None
+        }
+    }
+
+    pub fn verify(&self) -> bool {
+        self.flags & FLAG_VERIFY != 0
+    }
+
+    /*
+        Frame
+        [version: u8][flags: u8][type: u8][code: u32][id: u128][length: u32][group: u128 if FLAG_GROUP][in reply to: u128 if FLAG_REPLY][stamp: 16 bytes if FLAG_STAMP][data: u8 * length]
+        The contents of a File packet follow the frame on the same stream. Otherwise, any bytes after the data are ignored.
+    */
+
+    /// Decode a frame, yielding the packet and any bytes following it.
+    fn decode(bytes: &[u8]) -> Res<(Packet, &[u8])> {
+        let (header, rest) = bytes.split_first_chunk::<HEADER_LENGTH>().ok_or(NetworkError::InvalidPacket)?;
+        let (kind, flags, code, id, length) = Self::decode_header(header)?;
+
+        let (group, rest) = Self::decode_extension(flags & FLAG_GROUP != 0, rest)?;
+        let (in_reply_to, rest) = Self::decode_extension(flags & FLAG_REPLY != 0, rest)?;
+        let (stamp, rest) = match rest.split_first_chunk::<STAMP_LENGTH>() {
+            Some((stamp, rest)) if flags & FLAG_STAMP != 0 => (Some(Stamp::from_bytes(stamp)?), rest),
+            None if flags & FLAG_STAMP != 0 => return Err(NetworkError::InvalidPacket.into()),
+            _ => (None, rest)
+        };
+
+        if rest.len() < length { return Err(NetworkError::InvalidPacket.into()); }
+        let (data, rest) = rest.split_at(length);
+
+        Ok((Packet {
+            flags,
+            code,
+            id,
+            group,
+            in_reply_to,
+            stamp,
+            ..Packet::new(kind, data.to_vec())
+        }, rest))
+    }
+
+    /// Decode an optional u128 following the header, which is present only when its flag is set.
+    fn decode_extension(present: bool, bytes: &[u8]) -> Res<(Option<u128>, &[u8])> {
+        match present {
+            true => bytes.split_first_chunk::<16>().map(|(value, rest)| (Some(u128::from_be_bytes(*value)), rest)).ok_or(NetworkError::InvalidPacket.into()),
+            false => Ok((None, bytes))
+        }
+    }
+
+    fn decode_header(header: &[u8; HEADER_LENGTH]) -> Res<(PacketType, u8, u32, MessageId, usize)> {
+        let ([version, flags, kind], rest) = header.split_first_chunk::<3>().ok_or(NetworkError::InvalidPacket)?;
+        if !SUPPORTED_VERSIONS.contains(version) { return Err(NetworkError::IncompatibleVersion.into()); }
+
+        let (code, rest) = rest.split_first_chunk::<4>().ok_or(NetworkError::InvalidPacket)?;
+        let (id, rest) = rest.split_first_chunk::<16>().ok_or(NetworkError::InvalidPacket)?;
+        let length = u32::from_be_bytes(rest.try_into().map_err(|_| NetworkError::InvalidPacket)?) as usize;
+        if length > MAXIMUM_DATA_LENGTH { return Err(NetworkError::InvalidPacket.into()); }
+
+        Ok((PacketType::from_byte(*kind), *flags, u32::from_be_bytes(*code), u128::from_be_bytes(*id), length))
+    }
+
+    /// Read a single frame from the front of a stream, leaving anything after it (such as the contents of a file) unread.
+    pub async fn read_from(stream: &mut RecvStream) -> Res<Packet> {
+        let mut header = [0; HEADER_LENGTH];
+        stream.read_exact(&mut header).await?;
+        let (kind, flags, code, id, length) = Self::decode_header(&header)?;
+
+        let group = Self::read_extension(flags & FLAG_GROUP != 0, stream).await?;
+        let in_reply_to = Self::read_extension(flags & FLAG_REPLY != 0, stream).await?;
+        let stamp = match flags & FLAG_STAMP != 0 {
+            true => {
+                let mut stamp = [0; STAMP_LENGTH];
+                stream.read_exact(&mut stamp).await?;
+                Some(Stamp::from_bytes(&stamp)?)
+            }
+            false => None
+        };
+
+        let mut data = vec![0; length];
+        stream.read_exact(&mut data).await?;
+
+        Ok(Packet { flags, code, id, group, in_reply_to, stamp, ..Packet::new(kind, data) })
+    }
+
+    async fn read_extension(present: bool, stream: &mut RecvStream) -> Res<Option<u128>> {
+        if !present { return Ok(None); }
+        let mut value = [0; 16];
+        stream.read_exact(&mut value).await?;
+        Ok(Some(u128::from_be_bytes(value)))
     }
 
     #[allow(clippy::wrong_self_convention)]
     pub fn to_bytes(self) -> Vec<u8> {
-        let endians = self.code.to_be_bytes().to_vec();
+        let flags = match self.group {
+            Some(_) => self.flags | FLAG_GROUP,
+            None => self.flags & !FLAG_GROUP
+        };
+
+        let flags = match self.in_reply_to {
+            Some(_) => flags | FLAG_REPLY,
+            None => flags & !FLAG_REPLY
+        };
+
+        let flags = match self.stamp {
+            Some(_) => flags | FLAG_STAMP,
+            None => flags & !FLAG_STAMP
+        };
+
+        vec![
+            vec![SUPPORTED_VERSIONS[SUPPORTED_VERSIONS.len() - 1], flags, self.kind.to_byte()],
+            self.code.to_be_bytes().to_vec(),
+            self.id.to_be_bytes().to_vec(),
+            (self.data.len() as u32).to_be_bytes().to_vec(),
+            self.group.map(|group| group.to_be_bytes().to_vec()).unwrap_or_default(),
+            self.in_reply_to.map(|parent| parent.to_be_bytes().to_vec()).unwrap_or_default(),
+            self.stamp.map(Stamp::to_bytes).unwrap_or_default(),
+            self.data
+        ].into_iter().flatten().collect()
+    }
+
+    /*
+        Storage
+        [frame][path: utf8]
+        The frame is followed by the local path of the packet's file, if it has one.
+    */
+
+    pub fn from_stored_bytes(bytes: Vec<u8>) -> Res<Packet> {
+        let (packet, path) = Self::decode(&bytes)?;
+        Ok(Packet {
+            path: if path.is_empty() { None } else { Some(PathBuf::from(String::from_utf8_lossy(path).to_string())) },
+            ..packet
+        })
+    }
+
+    #[allow(clippy::wrong_self_convention)]
+    pub fn to_stored_bytes(self) -> Vec<u8> {
+        let path = self.path.as_ref().map(|path| path.to_string_lossy().as_bytes().to_vec()).unwrap_or_default();
+        vec![self.to_bytes(), path].into_iter().flatten().collect()
+    }
+
+    /*
+        Legacy (hchap1/v1)
+        [type: u8][code: u32][data]
+        There is no id, so one is generated on receipt, and every packet is verified.
+        Only the types v1 defined are read. Any other type byte is unknown, since v1 cannot carry what later types need.
+    */
+
+    pub fn from_legacy_bytes(bytes: Vec<u8>) -> Res<Packet> {
+        let (&kind, rest) = bytes.split_first().ok_or(NetworkError::InvalidPacket)?;
+        let (code, data) = rest.split_first_chunk::<4>().ok_or(NetworkError::InvalidPacket)?;
+        let kind = match PacketType::from_byte(kind) {
+            legacy if legacy.is_legacy() => legacy,
+            _ => PacketType::Unknown(kind)
+        };
+
+        Ok(Packet {
+            flags: FLAG_VERIFY,
+            code: u32::from_be_bytes(*code),
+            ..Packet::new(kind, data.to_vec())
+        })
+    }
+
+    #[allow(clippy::wrong_self_convention)]
+    pub fn to_legacy_bytes(self) -> Vec<u8> {
         vec![
             vec![self.kind.to_byte()],
-            endians,
+            self.code.to_be_bytes().to_vec(),
             self.data
         ].into_iter().flatten().collect()
     }
 
     pub fn message(message: String) -> Self {
+        Packet::new(PacketType::Message, message.into_bytes())
+    }
 
-        let mut rng = rng();
-        let code = rng.random_range(u32::MIN..=u32::MAX);
+    pub fn username(username: String) -> Self {
+        Packet::new(PacketType::Username, username.into_bytes())
+    }
 
-        Packet {
-            kind: PacketType::Message,
-            code,
-            data: message.into_bytes(),
-            decoded_image: None
+    pub fn image(image: &DynamicImage) -> Res<Self> {
+        let mut data = Vec::new();
+        image.write_to(&mut Cursor::new(&mut data), image::ImageFormat::Png)?;
+        Ok(Packet::new(PacketType::Image, data))
+    }
+
+    /// Describe a file on disk to be streamed to a peer. This hashes the whole file, so should not be called on the GUI thread.
+    pub fn file(path: &Path) -> Res<Self> {
+        let header = FileHeader::from_path(path)?;
+        Ok(Packet { path: Some(path.to_path_buf()), ..Packet::new(PacketType::File, header.to_bytes()) })
+    }
+
+    /// Ask a peer to resume sending the file with the given message id.
+    pub fn resume(id: MessageId) -> Self {
+        Packet::new(PacketType::Resume, id.to_be_bytes().to_vec())
+    }
+
+    /// The message id a Resume packet refers to.
+    pub fn resumed_id(&self) -> Option<MessageId> {
+        match self.kind {
+            PacketType::Resume => self.data.first_chunk::<16>().map(|id| u128::from_be_bytes(*id)),
+            _ => None
         }
     }
 
-    pub fn username(username: String) -> Self {
+    /// Replace the text of an earlier message.
+    pub fn edit(target: MessageId, text: String) -> Self {
+        Packet::new(PacketType::Edit, [target.to_be_bytes().to_vec(), text.into_bytes()].concat())
+    }
 
-        let mut rng = rng();
-        let code = rng.random_range(u32::MIN..=u32::MAX);
+    /// Withdraw an earlier packet.
+    pub fn delete(target: MessageId) -> Self {
+        Packet::new(PacketType::Delete, target.to_be_bytes().to_vec())
+    }
 
-        Packet {
-            kind: PacketType::Username,
-            code,
-            data: username.into_bytes(),
-            decoded_image: None
+    /// Add or remove an emoji on an earlier packet.
+    pub fn react(target: MessageId, emoji: String, add: bool) -> Self {
+        Packet::new(PacketType::React, [target.to_be_bytes().to_vec(), vec![add as u8], emoji.into_bytes()].concat())
+    }
+
+    /// The message id an Edit, Delete or React packet refers to.
+    pub fn target(&self) -> Option<MessageId> {
+        match self.kind {
+            PacketType::Edit | PacketType::Delete | PacketType::React => self.data.first_chunk::<16>().map(|id| u128::from_be_bytes(*id)),
+            _ => None
         }
     }
 
-    pub fn image(image: &DynamicImage) -> Res<Self> {
+    /// The replacement text carried by an Edit packet.
+    pub fn edited_text(&self) -> Option<String> {
+        match self.kind {
+            PacketType::Edit => self.data.get(16..).map(|text| String::from_utf8_lossy(text).to_string()),
+            _ => None
+        }
+    }
 
-        let mut rng = rng();
-        let code = rng.random_range(u32::MIN..=u32::MAX);
-        let mut data = Vec::new();
-        image.write_to(&mut Cursor::new(&mut data), image::ImageFormat::Png)?;
+    /// Tell a peer whether we are composing a message.
+    pub fn typing(typing: bool) -> Self {
+        Packet::new(PacketType::Typing, vec![typing as u8])
+    }
 
-        Ok(Packet {
-            kind: PacketType::Image,
-            code,
-            data: data.clone(),
-            decoded_image: Some(Handle::from_bytes(data))
-        })
+    /// Whether the author of a Typing packet is composing a message.
+    pub fn is_typing(&self) -> Option<bool> {
+        match self.kind {
+            PacketType::Typing => self.data.first().map(|typing| *typing != 0),
+            _ => None
+        }
+    }
+
+    /// Tell a peer that we have viewed the packets with the given message ids.
+    pub fn read(ids: &[MessageId]) -> Self {
+        Packet::new(PacketType::Read, ids.iter().flat_map(|id| id.to_be_bytes()).collect())
+    }
+
+    /// The message ids acknowledged as read by a Read packet.
+    pub fn read_ids(&self) -> Vec<MessageId> {
+        match self.kind {
+            PacketType::Read => self.data.as_chunks::<16>().0.iter().map(|id| u128::from_be_bytes(*id)).collect(),
+            _ => Vec::new()
+        }
+    }
+
+    /// The emoji carried by a React packet, and whether it is being added rather than removed.
+    pub fn reaction(&self) -> Option<(String, bool)> {
+        match self.kind {
+            PacketType::React => self.data.get(16..).and_then(|rest| rest.split_first())
+                .map(|(add, emoji)| (String::from_utf8_lossy(emoji).to_string(), *add != 0)),
+            _ => None
+        }
+    }
+
+    /// Share the name and members of a group.
+    pub fn group_add(group: GroupId, membership: &Membership) -> Self {
+        Packet::new(PacketType::GroupAdd, membership.to_bytes()).in_group(group)
+    }
+
+    /// Announce that we have left a group.
+    pub fn group_leave(group: GroupId) -> Self {
+        Packet::new(PacketType::GroupLeave, Vec::new()).in_group(group)
+    }
+
+    /// Mark this packet as a reply to an earlier one.
+    pub fn replying_to(self, parent: MessageId) -> Self {
+        Packet { in_reply_to: Some(parent), ..self }
+    }
+
+    pub fn stamped(self, stamp: Stamp) -> Self {
+        Packet { stamp: Some(stamp), ..self }
+    }
+
+    /// Address this packet to a group rather than to a single peer.
+    pub fn in_group(self, group: GroupId) -> Self {
+        Packet { group: Some(group), ..self }
+    }
+
+    /// The membership carried by a GroupAdd packet.
+    pub fn membership(&self) -> Option<Membership> {
+        match self.kind {
+            PacketType::GroupAdd => Membership::from_bytes(&self.data).ok(),
+            _ => None
+        }
+    }
+
+    /// The metadata of a File packet.
+    pub fn file_header(&self) -> Option<FileHeader> {
+        match self.kind {
+            PacketType::File => FileHeader::from_bytes(&self.data).ok(),
+            _ => None
+        }
     }
 }
 
+/// The outcome of delivering a packet to one of its recipients.
 #[derive(Clone, Debug)]
 pub enum TrackedPacketResponse {
-    Confirmed,
-    Failed,
+    Confirmed(EndpointId),
+    Failed(EndpointId),
 }
 
 #[derive(Clone, Debug)]
 pub struct TrackedPacket {
-    pub recipient_stable_id: usize,
+    pub recipient: Distribution,
     pub packet: Option<Packet>,
     sender: Sender<TrackedPacketResponse>
 }
 
 impl TrackedPacket {
-    pub fn new(recipient_stable_id: usize, packet: Packet) -> (TrackedPacket, Receiver<TrackedPacketResponse>) {
+    pub fn new(recipient: EndpointId, packet: Packet) -> (TrackedPacket, Receiver<TrackedPacketResponse>) {
+        Self::distribute(Distribution::Peer(recipient), packet)
+    }
+
+    /// Track a packet with any distribution. A response is yielded for each recipient.
+    pub fn distribute(recipient: Distribution, packet: Packet) -> (TrackedPacket, Receiver<TrackedPacketResponse>) {
         let (sender, receiver) = unbounded();
         (
-            TrackedPacket { recipient_stable_id, packet: Some(packet), sender },
+            TrackedPacket { recipient, packet: Some(packet), sender },
             receiver
         )
     }
@@ -155,11 +550,129 @@ impl TrackedPacket {
         self.packet.take()
     }
 
-    pub async fn confirm_success(&self) -> Res<()> {
-        send(TrackedPacketResponse::Confirmed, &self.sender).await.map_err(|x| x.into())
+    // Control packets are sent without anyone waiting on the outcome, so a closed channel is not an error.
+
+    pub async fn confirm_success(&self, recipient: EndpointId) {
+        let _ = send(TrackedPacketResponse::Confirmed(recipient), &self.sender).await;
+    }
+
+    pub async fn indicate_failure(&self, recipient: EndpointId) {
+        let _ = send(TrackedPacketResponse::Failed(recipient), &self.sender).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_same(decoded: &Packet, packet: &Packet) {
+        assert_eq!(decoded.kind, packet.kind);
+        assert_eq!(decoded.flags, packet.flags);
+        assert_eq!(decoded.code, packet.code);
+        assert_eq!(decoded.id, packet.id);
+        assert_eq!(decoded.data, packet.data);
+        assert_eq!(decoded.group, packet.group);
+        assert_eq!(decoded.in_reply_to, packet.in_reply_to);
+        assert_eq!(decoded.stamp, packet.stamp);
+    }
+
+    fn stamped() -> Packet {
+        Packet::message(String::from("hello"))
+            .replying_to(7)
+            .in_group(42)
+            .stamped(Stamp { clock: NonZeroU64::new(3).unwrap(), sent: NonZeroU64::new(1_700_000_000_000) })
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        for packet in [Packet::message(String::from("hello")), Packet::typing(true), stamped()] {
+            let bytes = packet.clone().to_bytes();
+            let (decoded, rest) = Packet::decode(&bytes).unwrap();
+            assert_same(&decoded, &Packet { flags: bytes[1], ..packet });
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[test]
+    fn frame_sets_extension_flags() {
+        let bytes = stamped().to_bytes();
+        assert_eq!(bytes[1] & (FLAG_GROUP | FLAG_REPLY | FLAG_STAMP), FLAG_GROUP | FLAG_REPLY | FLAG_STAMP);
+
+        let bytes = Packet { flags: FLAG_GROUP | FLAG_REPLY | FLAG_STAMP, ..Packet::message(String::new()) }.to_bytes();
+        assert_eq!(bytes[1] & (FLAG_GROUP | FLAG_REPLY | FLAG_STAMP), 0);
+        assert_eq!(bytes.len(), HEADER_LENGTH);
+    }
+
+    #[test]
+    fn frame_leaves_trailing_bytes() {
+        let bytes = [stamped().to_bytes(), vec![1, 2, 3]].concat();
+        assert_eq!(Packet::decode(&bytes).unwrap().1, [1, 2, 3]);
     }
 
-    pub async fn indicate_failure(&self) -> Res<()> {
-        send(TrackedPacketResponse::Failed, &self.sender).await.map_err(|x| x.into())
+    #[test]
+    fn frame_truncated() {
+        let bytes = stamped().to_bytes();
+        for length in 0..bytes.len() {
+            assert!(Packet::decode(&bytes[..length]).is_err(), "accepted {length} of {} bytes", bytes.len());
+        }
+    }
+
+    #[test]
+    fn frame_malformed() {
+        let mut bytes = stamped().to_bytes();
+        bytes[0] = 0;
+        assert!(Packet::decode(&bytes).is_err());
+
+        // A length beyond the largest payload is refused before anything is allocated for it.
+        let mut bytes = Packet::message(String::new()).to_bytes();
+        bytes[23..27].copy_from_slice(&(MAXIMUM_DATA_LENGTH as u32 + 1).to_be_bytes());
+        assert!(Packet::decode(&bytes).is_err());
+
+        // Clocks begin at one.
+        let mut bytes = Packet::message(String::new()).stamped(Stamp { clock: NonZeroU64::MIN, sent: None }).to_bytes();
+        bytes[HEADER_LENGTH..HEADER_LENGTH + 8].fill(0);
+        assert!(Packet::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn unknown_types_survive_a_round_trip() {
+        let packet = Packet::new(PacketType::Unknown(200), vec![1, 2, 3]);
+        let (decoded, _) = Packet::decode(&packet.clone().to_bytes()).unwrap();
+        assert_eq!(decoded.kind, PacketType::Unknown(200));
+        assert_same(&decoded, &packet);
+    }
+
+    #[test]
+    fn stored_round_trip() {
+        let packet = Packet { path: Some(PathBuf::from("/tmp/photo.png")), ..stamped() };
+        let decoded = Packet::from_stored_bytes(packet.clone().to_stored_bytes()).unwrap();
+        assert_eq!(decoded.path, packet.path);
+        assert_same(&decoded, &Packet { flags: decoded.flags, ..packet });
+
+        assert!(Packet::from_stored_bytes(Packet::message(String::new()).to_stored_bytes()).unwrap().path.is_none());
+    }
+
+    #[test]
+    fn legacy_round_trip() {
+        let packet = Packet { flags: FLAG_VERIFY, ..Packet::message(String::from("hello")) };
+        let decoded = Packet::from_legacy_bytes(packet.clone().to_legacy_bytes()).unwrap();
+        assert_eq!(decoded.kind, packet.kind);
+        assert_eq!(decoded.code, packet.code);
+        assert_eq!(decoded.data, packet.data);
+        assert!(decoded.verify());
+    }
+
+    #[test]
+    fn legacy_reads_only_v1_types() {
+        let bytes = Packet::edit(1, String::from("edited")).to_legacy_bytes();
+        assert_eq!(Packet::from_legacy_bytes(bytes.clone()).unwrap().kind, PacketType::Unknown(bytes[0]));
+    }
+
+    #[test]
+    fn legacy_truncated() {
+        let bytes = Packet::message(String::new()).to_legacy_bytes();
+        for length in 0..bytes.len() {
+            assert!(Packet::from_legacy_bytes(bytes[..length].to_vec()).is_err(), "accepted {length} of {} bytes", bytes.len());
+        }
     }
 }
@@ -0,0 +1,35 @@
+use std::collections::HashMap;
+
+use iroh::EndpointId;
+
+use crate::networking::server::Foreign;
+
+/// Maps each peer to whichever connection to it is currently live.
+/// A peer receives a new stable_id every time it reconnects, but its EndpointId never changes,
+/// so everything above the networking layer addresses peers rather than connections.
+#[derive(Debug, Default)]
+pub struct PeerTable {
+    connections: HashMap<EndpointId, Foreign>
+}
+
+impl PeerTable {
+
+    /// Bind a connection to its peer, returning the connection it supersedes, if any.
+    pub fn bind(&mut self, foreign: Foreign) -> Option<Foreign> {
+        self.connections.insert(foreign.remote_id(), foreign)
+    }
+
+    /// Evict the connection with the given stable_id, if it is still the live connection for its peer.
+    /// Returns whether the peer has been left without a connection.
+    pub fn unbind(&mut self, peer: &EndpointId, stable_id: usize) -> bool {
+        match self.connections.get(peer) {
+            Some(foreign) if foreign.stable_id() == stable_id => self.connections.remove(peer).is_some(),
+            _ => false
+        }
+    }
+
+    /// Find the live connection for a peer.
+    pub fn route(&self, peer: &EndpointId) -> Option<&Foreign> {
+        self.connections.get(peer)
+    }
+}
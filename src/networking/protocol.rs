@@ -0,0 +1,243 @@
+use std::time::Duration;
+
+use iroh::endpoint::Connection;
+
+use crate::error::{ChannelError, Res};
+use crate::networking::LEGACY_ALPN;
+use crate::networking::error::NetworkError;
+use crate::networking::group::CAPABILITY_GROUP;
+use crate::networking::packet::{CAPABILITY_EDIT, CAPABILITY_REACT, CAPABILITY_READ, CAPABILITY_REPLY, CAPABILITY_STAMP, CAPABILITY_TYPING, Packet};
+use crate::networking::transfer::{CAPABILITY_FILE, CAPABILITY_RESUME};
+
+/// Versions of the framed protocol we can speak, in ascending order.
+pub const SUPPORTED_VERSIONS: &[u8] = &[2];
+
+/// The unframed protocol spoken by peers that only offer the legacy ALPN.
+pub const LEGACY_VERSION: u8 = 1;
+
+/// The sender is waiting for the code to be echoed back as confirmation of delivery.
+pub const FLAG_VERIFY: u8 = 0b0000_0001;
+
+/// The frame carries the id of the group the packet belongs to.
+pub const FLAG_GROUP: u8 = 0b0000_0010;
+
+/// The frame carries the message id of the packet this one replies to.
+pub const FLAG_REPLY: u8 = 0b0000_0100;
+
+/// The frame carries the sender's logical clock and the time the packet was sent.
+pub const FLAG_STAMP: u8 = 0b0000_1000;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAXIMUM_HELLO_LENGTH: usize = 256;
+
+/// A bitfield of optional features. Packet types that older peers may not understand each claim a bit,
+/// and are only sent once both sides have advertised it during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+
+    /// Every capability this build supports.
+    pub fn local() -> Capabilities {
+        Capabilities(CAPABILITY_FILE | CAPABILITY_RESUME | CAPABILITY_GROUP | CAPABILITY_EDIT | CAPABILITY_REPLY | CAPABILITY_REACT | CAPABILITY_TYPING | CAPABILITY_READ | CAPABILITY_STAMP)
+    }
+
+    pub fn contains(self, capability: u32) -> bool {
+        self.0 & capability == capability
+    }
+
+    pub fn intersection(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+/// The protocol version and capabilities agreed with a peer for the lifetime of a connection.
+#[derive(Debug, Clone, Copy)]
+pub struct Session {
+    pub version: u8,
+    pub capabilities: Capabilities
+}
+
+impl Session {
+
+    pub fn legacy() -> Session {
+        Session { version: LEGACY_VERSION, capabilities: Capabilities(0) }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == LEGACY_VERSION
+    }
+
+    /// Whether the peer understands the given packet, by its type and whether it is addressed to a group.
+    pub fn supports(&self, packet: &Packet) -> bool {
+        match self.is_legacy() {
+            true => packet.kind.is_legacy() && packet.group.is_none(),
+            false => packet.kind.capability().is_none_or(|capability| self.capabilities.contains(capability))
+                && (packet.group.is_none() || self.capabilities.contains(CAPABILITY_GROUP))
+        }
+    }
+
+    /// Strip anything from a packet that the peer would not understand but can do without.
+    /// A reply is still readable as a plain message by a peer that does not support replies,
+    /// and a peer that does not support stamps orders packets as they arrive.
+    pub fn adapt(&self, packet: Packet) -> Packet {
+        let packet = match self.capabilities.contains(CAPABILITY_REPLY) {
+            true => packet,
+            false => Packet { in_reply_to: None, ..packet }
+        };
+
+        match self.capabilities.contains(CAPABILITY_STAMP) {
+            true => packet,
+            false => Packet { stamp: None, ..packet }
+        }
+    }
+
+    /// Negotiate a session as the dialing side of a connection, yielding whether the peer is holding it for approval.
+    /// Our hello is sent first, and the peer replies with its own.
+    /// Nothing may be sent on a held connection until Session::await_admission returns.
+    pub async fn initiate(connection: &Connection) -> Res<(Session, bool)> {
+        if connection.alpn() == LEGACY_ALPN { return Ok((Session::legacy(), false)); }
+
+        let (mut send, mut recv) = connection.open_bi().await?;
+        send.write_all(&Hello::local().to_bytes()).await?;
+        send.finish()?;
+
+        let reply = match tokio::time::timeout(HANDSHAKE_TIMEOUT, recv.read_to_end(MAXIMUM_HELLO_LENGTH)).await {
+            Ok(read_result) => Hello::from_bytes(&read_result?)?,
+            Err(_) => return Err(ChannelError::ChannelDead.into())
+        };
+
+        Ok((Hello::local().negotiate(&reply)?, reply.held))
+    }
+
+    /// Negotiate a session as the accepting side of a connection, telling the peer whether we are holding it for approval.
+    pub async fn respond(connection: &Connection, held: bool) -> Res<Session> {
+        if connection.alpn() == LEGACY_ALPN { return Ok(Session::legacy()); }
+
+        let (mut send, mut recv) = match tokio::time::timeout(HANDSHAKE_TIMEOUT, connection.accept_bi()).await {
+            Ok(accept_result) => accept_result?,
+            Err(_) => return Err(ChannelError::ChannelDead.into())
+        };
+
+        let hello = Hello::from_bytes(&recv.read_to_end(MAXIMUM_HELLO_LENGTH).await?)?;
+        send.write_all(&Hello { held, ..Hello::local() }.to_bytes()).await?;
+        send.finish()?;
+
+        Hello::local().negotiate(&hello)
+    }
+
+    /// Tell the peer of a held connection that it has been approved, by opening a stream with a single byte on it.
+    pub async fn admit(connection: &Connection) -> Res<()> {
+        let mut send = connection.open_uni().await?;
+        send.write_all(&[1]).await?;
+        send.finish()?;
+        Ok(())
+    }
+
+    /// Wait for the peer holding our connection to approve it. A rejected connection is closed, which fails the wait.
+    pub async fn await_admission(connection: &Connection) -> Res<()> {
+        connection.accept_uni().await?;
+        Ok(())
+    }
+}
+
+/// The first exchange on every framed connection.
+struct Hello {
+    versions: Vec<u8>,
+    capabilities: Capabilities,
+    held: bool      // Whether the connection is held until our user approves it. Only ever set in a reply.
+}
+
+impl Hello {
+
+    fn local() -> Hello {
+        Hello { versions: SUPPORTED_VERSIONS.to_vec(), capabilities: Capabilities::local(), held: false }
+    }
+
+    /// Choose the highest version both sides support, and the capabilities both sides have.
+    fn negotiate(&self, other: &Hello) -> Res<Session> {
+        let version = self.versions.iter()
+            .filter(|version| other.versions.contains(version))
+            .max()
+            .ok_or(NetworkError::IncompatibleVersion)?;
+
+        Ok(Session { version: *version, capabilities: self.capabilities.intersection(other.capabilities) })
+    }
+
+    /*
+        Encoding
+        [version count: u8][versions: u8 * count][capabilities: u32][held: u8]
+        A hello without the held byte is not held. Any trailing bytes are reserved for future use and ignored.
+    */
+
+    fn to_bytes(&self) -> Vec<u8> {
+        vec![
+            vec![self.versions.len() as u8],
+            self.versions.clone(),
+            self.capabilities.0.to_be_bytes().to_vec(),
+            vec![self.held as u8]
+        ].into_iter().flatten().collect()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Res<Hello> {
+        let (count, rest) = bytes.split_first().ok_or(NetworkError::InvalidHandshake)?;
+        if rest.len() < *count as usize { return Err(NetworkError::InvalidHandshake.into()); }
+
+        let (versions, rest) = rest.split_at(*count as usize);
+        let (capabilities, rest) = rest.split_first_chunk::<4>().ok_or(NetworkError::InvalidHandshake)?;
+
+        Ok(Hello {
+            versions: versions.to_vec(),
+            capabilities: Capabilities(u32::from_be_bytes(*capabilities)),
+            held: rest.first().is_some_and(|held| *held != 0)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_round_trip() {
+        for held in [false, true] {
+            let hello = Hello { held, ..Hello::local() };
+            let decoded = Hello::from_bytes(&hello.to_bytes()).unwrap();
+            assert_eq!(decoded.versions, hello.versions);
+            assert_eq!(decoded.capabilities, hello.capabilities);
+            assert_eq!(decoded.held, held);
+        }
+    }
+
+    #[test]
+    fn hello_without_held_byte_is_not_held() {
+        let bytes = Hello { held: true, ..Hello::local() }.to_bytes();
+        assert!(!Hello::from_bytes(&bytes[..bytes.len() - 1]).unwrap().held);
+    }
+
+    #[test]
+    fn hello_ignores_trailing_bytes() {
+        let bytes = [Hello { held: true, ..Hello::local() }.to_bytes(), vec![0xff; 4]].concat();
+        assert!(Hello::from_bytes(&bytes).unwrap().held);
+    }
+
+    #[test]
+    fn hello_truncated() {
+        let bytes = Hello::local().to_bytes();
+        for length in 0..bytes.len() - 1 {
+            assert!(Hello::from_bytes(&bytes[..length]).is_err(), "accepted {length} of {} bytes", bytes.len());
+        }
+    }
+
+    #[test]
+    fn negotiate() {
+        let ours = Hello::local();
+        let theirs = Hello { versions: vec![0, SUPPORTED_VERSIONS[0]], capabilities: Capabilities(CAPABILITY_FILE | CAPABILITY_EDIT | 1 << 31), held: false };
+        let session = ours.negotiate(&theirs).unwrap();
+        assert_eq!(session.version, SUPPORTED_VERSIONS[0]);
+        assert_eq!(session.capabilities, Capabilities::local().intersection(Capabilities(CAPABILITY_FILE | CAPABILITY_EDIT | 1 << 31)));
+
+        let incompatible = Hello { versions: vec![0], ..Hello::local() };
+        assert!(ours.negotiate(&incompatible).is_err());
+    }
+}
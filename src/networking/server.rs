@@ -5,65 +5,107 @@ use async_channel::Sender;
 use async_channel::unbounded;
 use iroh::Endpoint;
 use iroh::EndpointAddr;
+use iroh::EndpointId;
+use iroh::endpoint::ConnectOptions;
 use iroh::endpoint::Connection;
 
 use crate::error::Res;
 use crate::networking::ALPN;
+use crate::networking::LEGACY_ALPN;
+use crate::networking::access::AccessControl;
+use crate::networking::access::refuse;
 use crate::networking::connection_manager::ConnectionManager;
 use crate::networking::connection_manager::ConnectionManagerMessage;
 use crate::networking::foreign_manager::ForeignManager;
+use crate::networking::identity::Identity;
 use crate::networking::packet::Packet;
 use crate::networking::packet::TrackedPacket;
+use crate::networking::protocol::Session;
+use crate::networking::transfer::TransferEvent;
+use crate::networking::window::ReceiveWindow;
 use crate::util::channel::send;
 
 #[derive(Debug)]
 pub struct Local {
     endpoint: Endpoint,
     connection_manager: ConnectionManager,
-    packet_sender: Sender<(usize, Packet)>,
-    packet_receiver: Receiver<(usize, Packet)>
+    packet_sender: Sender<(EndpointId, Packet)>,
+    packet_receiver: Receiver<(EndpointId, Packet)>,
+    transfer_sender: Sender<TransferEvent>,
+    transfer_receiver: Receiver<TransferEvent>,
+    window: ReceiveWindow
 }
 
 impl Local {
 
-    pub async fn establish() -> Res<Local> {
+    pub async fn establish(access: AccessControl) -> Res<Local> {
         let endpoint = Endpoint::builder()
-            .alpns(vec![ALPN.to_vec()])
+            .secret_key(Identity::load_or_generate()?)
+            .alpns(vec![ALPN.to_vec(), LEGACY_ALPN.to_vec()])
             .bind()
             .await?;
 
-        println!("ENDPOINT: {}", endpoint.addr().id);
-
         let (packet_sender, packet_receiver) = unbounded();
+        let (transfer_sender, transfer_receiver) = unbounded();
+        let window = ReceiveWindow::default();
 
         Ok(Local {
             endpoint: endpoint.clone(),
-            connection_manager: ConnectionManager::new(endpoint, packet_sender.clone()),
+            connection_manager: ConnectionManager::new(endpoint, packet_sender.clone(), transfer_sender.clone(), window.clone(), access),
             packet_sender,
-            packet_receiver
+            packet_receiver,
+            transfer_sender,
+            transfer_receiver,
+            window
         })
     }
 
-    pub async fn connect(endpoint: Endpoint, sender: Sender<ConnectionManagerMessage>, packet_sender: Sender<(usize, Packet)>, target: EndpointAddr) -> Res<usize> {
-        let foreign = Foreign::establish(endpoint, target, packet_sender).await?;
-        let id = foreign.stable_id;
+    pub async fn connect(endpoint: Endpoint, sender: Sender<ConnectionManagerMessage>, packet_sender: Sender<(EndpointId, Packet)>, transfer_sender: Sender<TransferEvent>, window: ReceiveWindow, target: EndpointAddr) -> Res<EndpointId> {
+        let foreign = Foreign::establish(endpoint, target, &sender, packet_sender, transfer_sender, window).await?;
+        let id = foreign.remote_id;
         send(ConnectionManagerMessage::Add(foreign), &sender).await?;
         Ok(id)
     }
 
+    /// Let in a peer whose connection was held for our approval, telling it so. Nothing happens if the peer has since given up.
+    pub async fn approve(sender: Sender<ConnectionManagerMessage>, packet_sender: Sender<(EndpointId, Packet)>, transfer_sender: Sender<TransferEvent>, window: ReceiveWindow, access: AccessControl, peer: EndpointId) -> Res<()> {
+        if let Some((connection, session)) = access.approve(peer) {
+            Session::admit(&connection).await?;
+            send(ConnectionManagerMessage::Add(Foreign::new(connection, session, packet_sender, transfer_sender, window)), &sender).await?;
+        }
+        Ok(())
+    }
+
+    /// Close the connection to a blocked peer, and stop redialing it.
+    pub async fn disconnect(sender: Sender<ConnectionManagerMessage>, peer: EndpointId) -> Res<()> {
+        send(ConnectionManagerMessage::Block(peer), &sender).await?;
+        Ok(())
+    }
+
+    /// Close the endpoint, terminating every connection, the listening thread and the management thread.
+    pub async fn shutdown(&self) {
+        let _ = send(ConnectionManagerMessage::Quit, &self.cs()).await;
+        self.endpoint.close().await
+    }
+
     pub fn ep(&self) -> Endpoint { self.endpoint.clone() }
     pub fn cs(&self) -> Sender<ConnectionManagerMessage> { self.connection_manager.yield_sender() }
-    pub fn ps(&self) -> Sender<(usize, Packet)> { self.packet_sender.clone() }
+    pub fn ps(&self) -> Sender<(EndpointId, Packet)> { self.packet_sender.clone() }
+    pub fn ts(&self) -> Sender<TransferEvent> { self.transfer_sender.clone() }
+    pub fn rw(&self) -> ReceiveWindow { self.window.clone() }
     
     /// Get a clone of the packet output receiver to be used with the frontend.
-    pub fn yield_packet_output(&self) -> Receiver<(usize, Packet)> { self.packet_receiver.clone() }
+    pub fn yield_packet_output(&self) -> Receiver<(EndpointId, Packet)> { self.packet_receiver.clone() }
+
+    /// Get a clone of the file transfer event receiver to be used with the frontend.
+    pub fn yield_transfer_output(&self) -> Receiver<TransferEvent> { self.transfer_receiver.clone() }
 
     /// Get a clone of the connection manager output receiver to be used with the frontend.
     /// This reports events such as errors or succesful connections, foreign and locally initiated.
     pub fn yield_output(&self) -> Receiver<ConnectionManagerMessage> { self.connection_manager.yield_output() }
 
-    /// Send a request through to a ForeignManager by ID to relay a packet through a new bi-directional stream.
-    /// (Send a packet to a given stable_id)
+    /// Send a request through to the ForeignManager of a peer to relay a packet through a new bi-directional stream.
+    /// (Send a packet to a given EndpointId)
     pub async fn send_packet_to(sender: Sender<ConnectionManagerMessage>, tracked_packet: TrackedPacket) -> Res<()> {
         send(ConnectionManagerMessage::Message(tracked_packet), &sender).await?;
         Ok(())
@@ -73,29 +115,67 @@ impl Local {
 #[derive(Clone, Debug)]
 pub struct Foreign {
     stable_id: usize,
+    remote_id: EndpointId,
+    dialed: bool,
     foreign_manager: Arc<ForeignManager>
 }
 
 impl Foreign {
 
-    pub fn new(connection: Connection, packet_sender: Sender<(usize, Packet)>) -> Foreign {
+    pub fn new(connection: Connection, session: Session, packet_sender: Sender<(EndpointId, Packet)>, transfer_sender: Sender<TransferEvent>, window: ReceiveWindow) -> Foreign {
         Foreign {
             stable_id: connection.stable_id(),
-            foreign_manager: Arc::new(ForeignManager::new(connection, packet_sender))
+            remote_id: connection.remote_id(),
+            dialed: false,
+            foreign_manager: Arc::new(ForeignManager::new(connection, session, packet_sender, transfer_sender, window))
         }
     }
+
+    /// Complete the handshake on a connection the peer initiated.
+    pub async fn accept(connection: Connection, packet_sender: Sender<(EndpointId, Packet)>, transfer_sender: Sender<TransferEvent>, window: ReceiveWindow) -> Res<Foreign> {
+        let session = Session::respond(&connection, false).await?;
+        Ok(Foreign::new(connection, session, packet_sender, transfer_sender, window))
+    }
     
-    pub async fn establish(endpoint: Endpoint, target: EndpointAddr, packet_sender: Sender<(usize, Packet)>) -> Res<Foreign> {
-        let connection = endpoint.connect(target, ALPN).await?;
-        Ok(Foreign::new(connection, packet_sender))
+    /// Dial a peer, offering the legacy ALPN as a fallback for peers that predate the framed protocol.
+    /// A peer that holds the connection for its user's approval is reported as such, and only yielded once approved.
+    pub async fn establish(endpoint: Endpoint, target: EndpointAddr, task_sender: &Sender<ConnectionManagerMessage>, packet_sender: Sender<(EndpointId, Packet)>, transfer_sender: Sender<TransferEvent>, window: ReceiveWindow) -> Res<Foreign> {
+        let options = ConnectOptions::new().with_additional_alpns(vec![LEGACY_ALPN.to_vec()]);
+        let connection = endpoint.connect_with_opts(target, ALPN, options).await?.await?;
+        let (session, held) = Session::initiate(&connection).await?;
+        if held {
+            send(ConnectionManagerMessage::Awaiting(connection.remote_id()), task_sender).await?;
+            Session::await_admission(&connection).await?;
+        }
+        Ok(Foreign { dialed: true, ..Foreign::new(connection, session, packet_sender, transfer_sender, window) })
     }
 
     pub fn stable_id(&self) -> usize {
         self.stable_id
     }
 
+    /// The EndpointId of the peer, which unlike the stable_id persists across connections.
+    pub fn remote_id(&self) -> EndpointId {
+        self.remote_id
+    }
+
+    /// Whether we initiated this connection, rather than accepting it.
+    pub fn dialed(&self) -> bool {
+        self.dialed
+    }
+
+    /// Wait for the connection to close, yielding the reason.
+    pub async fn closed(&self) -> String {
+        self.foreign_manager.clone_connection().closed().await.to_string()
+    }
+
+    /// Close the connection, as when its peer is blocked.
+    pub fn close(&self) {
+        refuse(&self.foreign_manager.clone_connection());
+    }
+
     pub async fn distribute(&self, packet: Packet) -> Res<bool> {
         let connection = self.foreign_manager.clone_connection();
-        ForeignManager::send_task(connection, packet).await
+        ForeignManager::send_task(connection, self.foreign_manager.session(), packet, self.foreign_manager.transfer_sender()).await
     }
 }
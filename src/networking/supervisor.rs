@@ -0,0 +1,102 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use async_channel::{Receiver, Sender};
+use iroh::{Endpoint, EndpointId};
+use rand::{Rng, rng};
+use tokio::task::JoinHandle;
+
+use crate::error::Res;
+use crate::networking::connection_manager::ConnectionManagerMessage;
+use crate::networking::packet::Packet;
+use crate::networking::server::Foreign;
+use crate::networking::transfer::TransferEvent;
+use crate::networking::window::ReceiveWindow;
+use crate::util::channel::send;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAXIMUM_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub enum SupervisorMessage {
+    Quit,
+    Dialed(EndpointId),         // We initiated a connection to this peer, so we are responsible for restoring it.
+    Connected(EndpointId),      // The peer has a live connection again, by whatever means.
+    Lost(EndpointId),           // The peer no longer has any live connection.
+    Forget(EndpointId)          // The peer has been blocked, and must not be redialed.
+}
+
+/// Remembers every peer we dialed and redials them, with exponential backoff and jitter, whenever they are lost.
+/// Peers that dialed us are left to redial us themselves.
+pub struct Supervisor;
+
+impl Supervisor {
+
+    pub async fn supervise(
+        endpoint: Endpoint,
+        receiver: Receiver<SupervisorMessage>,
+        task_sender: Sender<ConnectionManagerMessage>,
+        output: Sender<ConnectionManagerMessage>,
+        packet_sender: Sender<(EndpointId, Packet)>,
+        transfer_sender: Sender<TransferEvent>,
+        window: ReceiveWindow
+    ) -> Res<()> {
+
+        let mut dialed: HashSet<EndpointId> = HashSet::new();
+        let mut redials: HashMap<EndpointId, JoinHandle<Res<()>>> = HashMap::new();
+
+        while let Ok(message) = receiver.recv().await {
+            match message {
+                SupervisorMessage::Quit => break,
+                SupervisorMessage::Dialed(peer) => { dialed.insert(peer); },
+                SupervisorMessage::Connected(peer) => if let Some(handle) = redials.remove(&peer) {
+                    handle.abort();
+                },
+                SupervisorMessage::Forget(peer) => {
+                    dialed.remove(&peer);
+                    if let Some(handle) = redials.remove(&peer) { handle.abort(); }
+                },
+                SupervisorMessage::Lost(peer) => if dialed.contains(&peer) && !redials.contains_key(&peer) {
+                    redials.insert(peer, tokio::spawn(Self::redial(endpoint.clone(), peer, task_sender.clone(), output.clone(), packet_sender.clone(), transfer_sender.clone(), window.clone())));
+                }
+            }
+        }
+
+        for handle in redials.values() {
+            handle.abort();
+        }
+
+        Ok(())
+    }
+
+    /// Repeatedly attempt to connect to a peer until successful, handing the new connection to the ConnectionManager.
+    async fn redial(
+        endpoint: Endpoint,
+        peer: EndpointId,
+        task_sender: Sender<ConnectionManagerMessage>,
+        output: Sender<ConnectionManagerMessage>,
+        packet_sender: Sender<(EndpointId, Packet)>,
+        transfer_sender: Sender<TransferEvent>,
+        window: ReceiveWindow
+    ) -> Res<()> {
+
+        let mut attempt: u32 = 0;
+
+        loop {
+            tokio::time::sleep(Self::backoff(attempt)).await;
+            attempt += 1;
+            send(ConnectionManagerMessage::Reconnecting(peer, attempt), &output).await?;
+
+            if let Ok(foreign) = Foreign::establish(endpoint.clone(), peer.into(), &task_sender, packet_sender.clone(), transfer_sender.clone(), window.clone()).await {
+                return Ok(send(ConnectionManagerMessage::Add(foreign), &task_sender).await?);
+            }
+        }
+    }
+
+    /// The delay before a given attempt, doubling each time up to a maximum and scattered by up to 50% either way
+    /// so that both ends of a dropped connection do not redial in lockstep.
+    fn backoff(attempt: u32) -> Duration {
+        let exponential = INITIAL_BACKOFF.saturating_mul(2u32.saturating_pow(attempt)).min(MAXIMUM_BACKOFF);
+        exponential.mul_f64(rng().random_range(0.5..1.5))
+    }
+}
@@ -0,0 +1,204 @@
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use data_encoding::BASE32_NOPAD;
+use iroh::{EndpointAddr, EndpointId, RelayUrl, TransportAddr};
+
+use crate::error::Res;
+use crate::networking::error::NetworkError;
+use crate::util::clock;
+
+const PREFIX: &str = "rift";
+const VERSION: u8 = 1;
+
+/// A shareable invite carrying everything needed to dial us: our node ID, our home relay and our direct addresses,
+/// so that the peer need not rely on discovery to find us. Optionally names us and expires.
+/// Written as "rift" followed by the encoding below in lowercase base32, though either case is read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ticket {
+    pub addr: EndpointAddr,
+    pub name: Option<String>,
+    pub expires: Option<u64>    // Milliseconds since the Unix epoch.
+}
+
+impl Ticket {
+
+    pub fn new(addr: EndpointAddr, name: Option<String>, expires: Option<u64>) -> Ticket {
+        Ticket { addr, name, expires }
+    }
+
+    pub fn id(&self) -> EndpointId {
+        self.addr.id
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= clock::now())
+    }
+
+    /*
+        Encoding
+        [version: u8][endpoint id: 32 bytes][expires: u64, 0 if never][name length: u8][name][address count: u8][addresses]
+        Each address is [kind: u8][length: u16][address], so that addresses of kinds added later can be skipped.
+        Relay: [0][url]
+        IPv4:  [1][ip: 4 bytes][port: u16]
+        IPv6:  [2][ip: 16 bytes][port: u16]
+        Names longer than 255 bytes are truncated.
+    */
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let name = self.name.as_deref().unwrap_or_default();
+        let length = (0..=name.len().min(u8::MAX as usize)).rev().find(|length| name.is_char_boundary(*length)).unwrap_or_default();
+        let name = &name.as_bytes()[..length];
+
+        let addresses: Vec<Vec<u8>> = self.addr.addrs.iter().filter_map(|address| match address {
+            TransportAddr::Relay(url) => Some((0, url.to_string().into_bytes())),
+            TransportAddr::Ip(SocketAddr::V4(address)) => Some((1, [address.ip().octets().as_slice(), &address.port().to_be_bytes()].concat())),
+            TransportAddr::Ip(SocketAddr::V6(address)) => Some((2, [address.ip().octets().as_slice(), &address.port().to_be_bytes()].concat())),
+            _ => None
+        })
+        .filter(|(_, address)| address.len() <= u16::MAX as usize)
+        .map(|(kind, address)| [vec![kind], (address.len() as u16).to_be_bytes().to_vec(), address].concat())
+        .take(u8::MAX as usize).collect();
+
+        [
+            vec![VERSION],
+            self.addr.id.as_bytes().to_vec(),
+            self.expires.unwrap_or_default().to_be_bytes().to_vec(),
+            vec![name.len() as u8],
+            name.to_vec(),
+            vec![addresses.len() as u8],
+            addresses.concat()
+        ].concat()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Res<Ticket> {
+        let (version, rest) = bytes.split_first().ok_or(NetworkError::MalformedTicket)?;
+        if *version != VERSION { return Err(NetworkError::MalformedTicket.into()); }
+
+        let (id, rest) = rest.split_first_chunk::<32>().ok_or(NetworkError::MalformedTicket)?;
+        let (expires, rest) = rest.split_first_chunk::<8>().ok_or(NetworkError::MalformedTicket)?;
+        let (name, rest) = split_prefixed(rest, 1)?;
+        let (count, mut rest) = rest.split_first().ok_or(NetworkError::MalformedTicket)?;
+
+        let mut addresses = Vec::new();
+        for _ in 0..*count {
+            let (kind, remaining) = rest.split_first().ok_or(NetworkError::MalformedTicket)?;
+            let (address, remaining) = split_prefixed(remaining, 2)?;
+            rest = remaining;
+
+            addresses.push(match kind {
+                0 => TransportAddr::Relay(RelayUrl::from_str(&String::from_utf8_lossy(address)).map_err(|_| NetworkError::MalformedTicket)?),
+                1 => TransportAddr::Ip(socket_address::<4>(address)?),
+                2 => TransportAddr::Ip(socket_address::<16>(address)?),
+                _ => continue
+            });
+        }
+
+        Ok(Ticket {
+            addr: EndpointAddr::from_parts(EndpointId::from_bytes(id).map_err(|_| NetworkError::MalformedTicket)?, addresses),
+            name: Some(String::from_utf8_lossy(name).to_string()).filter(|name| !name.is_empty()),
+            expires: Some(u64::from_be_bytes(*expires)).filter(|expires| *expires != 0)
+        })
+    }
+}
+
+impl Display for Ticket {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{PREFIX}{}", BASE32_NOPAD.encode(&self.to_bytes()).to_ascii_lowercase())
+    }
+}
+
+impl FromStr for Ticket {
+    type Err = crate::error::Error;
+
+    fn from_str(ticket: &str) -> Res<Ticket> {
+        let ticket = ticket.trim();
+        let encoded = match ticket.get(..PREFIX.len()) {
+            Some(prefix) if prefix.eq_ignore_ascii_case(PREFIX) => &ticket[PREFIX.len()..],
+            _ => return Err(NetworkError::MalformedTicket.into())
+        };
+        let bytes = BASE32_NOPAD.decode(encoded.to_ascii_uppercase().as_bytes()).map_err(|_| NetworkError::MalformedTicket)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+/// Split off a field prefixed by its length, which is the given number of big-endian bytes.
+fn split_prefixed(bytes: &[u8], width: usize) -> Res<(&[u8], &[u8])> {
+    if bytes.len() < width { return Err(NetworkError::MalformedTicket.into()); }
+    let (length, rest) = bytes.split_at(width);
+    let length = length.iter().fold(0, |length, byte| (length << 8) | *byte as usize);
+    if rest.len() < length { return Err(NetworkError::MalformedTicket.into()); }
+    Ok(rest.split_at(length))
+}
+
+/// An IP address of the given length followed by a port.
+fn socket_address<const LENGTH: usize>(address: &[u8]) -> Res<SocketAddr> where IpAddr: From<[u8; LENGTH]> {
+    match address.split_first_chunk::<LENGTH>() {
+        Some((ip, [high, low])) => Ok(SocketAddr::new(IpAddr::from(*ip), u16::from_be_bytes([*high, *low]))),
+        _ => Err(NetworkError::MalformedTicket.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use iroh::SecretKey;
+
+    use super::*;
+
+    fn ticket() -> Ticket {
+        let addresses = [
+            TransportAddr::Relay(RelayUrl::from_str("https://relay.example.com").unwrap()),
+            TransportAddr::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)), 4433)),
+            TransportAddr::Ip(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 11204))
+        ];
+        Ticket::new(EndpointAddr::from_parts(SecretKey::from_bytes(&[7; 32]).public(), addresses), Some(String::from("alice")), Some(1_700_000_000_000))
+    }
+
+    #[test]
+    fn round_trip() {
+        let ticket = ticket();
+        assert_eq!(Ticket::from_str(&ticket.to_string()).unwrap(), ticket);
+        assert_eq!(Ticket::from_str(&ticket.to_string().to_ascii_uppercase()).unwrap(), ticket);
+
+        let bare = Ticket::new(EndpointAddr::from_parts(ticket.id(), []), None, None);
+        assert_eq!(Ticket::from_str(&bare.to_string()).unwrap(), bare);
+    }
+
+    #[test]
+    fn long_names_are_truncated_on_a_character_boundary() {
+        let ticket = Ticket { name: Some("é".repeat(200)), ..ticket() };
+        let name = Ticket::from_str(&ticket.to_string()).unwrap().name.unwrap();
+        assert_eq!(name, "é".repeat(127));
+    }
+
+    #[test]
+    fn unknown_address_kinds_are_skipped() {
+        let mut bytes = Ticket { addr: EndpointAddr::from_parts(ticket().id(), []), ..ticket() }.to_bytes();
+        *bytes.last_mut().unwrap() = 1;
+        bytes.extend([9, 0, 3, 1, 2, 3]);
+        assert!(Ticket::from_bytes(&bytes).unwrap().addr.addrs.is_empty());
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = ticket().to_bytes();
+        for length in 0..bytes.len() {
+            assert!(Ticket::from_bytes(&bytes[..length]).is_err(), "accepted {length} of {} bytes", bytes.len());
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        let mut bytes = ticket().to_bytes();
+        bytes[0] = VERSION + 1;
+        assert!(Ticket::from_bytes(&bytes).is_err());
+
+        assert!(Ticket::from_str("").is_err());
+        assert!(Ticket::from_str("rift").is_err());
+        assert!(Ticket::from_str(&ticket().to_string().replacen("rift", "raft", 1)).is_err());
+        assert!(Ticket::from_str("rift0189").is_err());
+    }
+}
@@ -0,0 +1,340 @@
+use std::fs::File;
+use std::io::{Read, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use async_channel::Sender;
+use iroh::EndpointId;
+use iroh::endpoint::{RecvStream, SendStream};
+use tokio::fs::{OpenOptions, read, remove_file, rename};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::error::{ChannelError, Res, StorageError};
+use crate::networking::error::NetworkError;
+use crate::networking::packet::{MessageId, Packet};
+use crate::networking::window::ReceiveWindow;
+use crate::util::channel::send;
+use crate::util::storage::{create_private_dir, data_dir, write_sealed};
+use crate::util::vault;
+
+/// Peers advertising this capability accept PacketType::File.
+pub const CAPABILITY_FILE: u32 = 0b0000_0001;
+
+/// Peers advertising this capability accept PacketType::Resume.
+pub const CAPABILITY_RESUME: u32 = 0b0000_0010;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Progress is reported at most once per interval, so that large files do not flood the frontend.
+const PROGRESS_INTERVAL: u64 = 1024 * 1024;
+
+const DOWNLOAD_DIRECTORY: &str = "Rift";
+const PARTIAL_EXTENSION: &str = "part";
+const TRANSFER_DIRECTORY: &str = "transfers";
+
+// How long the sender waits for the receiver to reply with the offset to resume from.
+const OFFSET_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub enum TransferEvent {
+    Incoming(EndpointId, Packet),           // A peer has begun, or resumed, sending a file, which will be written to the packet's path.
+    Progress(MessageId, u64),               // The number of bytes transferred so far, in either direction.
+    Verified(EndpointId, MessageId),        // An incoming file was received in full and matched its hash.
+    Failed(EndpointId, MessageId)           // An incoming file was interrupted, to be resumed later, or did not match its hash and was deleted.
+}
+
+/// The metadata carried by a File packet. The contents themselves follow the frame on the same stream.
+#[derive(Debug, Clone)]
+pub struct FileHeader {
+    pub name: String,
+    pub size: u64,
+    pub mime: String,
+    pub hash: [u8; 32]
+}
+
+impl FileHeader {
+
+    /// Describe a file on disk. This hashes the whole file, so should not be called on the GUI thread.
+    pub fn from_path(path: &Path) -> Res<FileHeader> {
+        let mut file = File::open(path)?;
+        let mut hasher = blake3::Hasher::new();
+        let mut buffer = vec![0; CHUNK_SIZE];
+        let mut size = 0;
+
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 { break; }
+            hasher.update(&buffer[..read]);
+            size += read as u64;
+        }
+
+        Ok(FileHeader {
+            name: path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default(),
+            size,
+            mime: mime_guess::from_path(path).first_or_octet_stream().to_string(),
+            hash: *hasher.finalize().as_bytes()
+        })
+    }
+
+    /*
+        Encoding
+        [size: u64][hash: 32 bytes][name length: u16][name: utf8][mime: utf8]
+    */
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![
+            self.size.to_be_bytes().to_vec(),
+            self.hash.to_vec(),
+            (self.name.len() as u16).to_be_bytes().to_vec(),
+            self.name.as_bytes().to_vec(),
+            self.mime.as_bytes().to_vec()
+        ].into_iter().flatten().collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Res<FileHeader> {
+        let (size, rest) = bytes.split_first_chunk::<8>().ok_or(NetworkError::InvalidPacket)?;
+        let (hash, rest) = rest.split_first_chunk::<32>().ok_or(NetworkError::InvalidPacket)?;
+        let (length, rest) = rest.split_first_chunk::<2>().ok_or(NetworkError::InvalidPacket)?;
+        let length = u16::from_be_bytes(*length) as usize;
+        if rest.len() < length { return Err(NetworkError::InvalidPacket.into()); }
+        let (name, mime) = rest.split_at(length);
+
+        Ok(FileHeader {
+            name: String::from_utf8_lossy(name).to_string(),
+            size: u64::from_be_bytes(*size),
+            mime: String::from_utf8_lossy(mime).to_string(),
+            hash: *hash
+        })
+    }
+
+    /// The name to save the file under. The peer chooses the name, so anything resembling a path is stripped from it.
+    fn safe_name(&self) -> String {
+        match Path::new(&self.name).file_name().map(|name| name.to_string_lossy().to_string()) {
+            Some(name) if !name.starts_with('.') => name,
+            _ => String::from("download")
+        }
+    }
+}
+
+/// The directory received files are saved to, falling back to the home directory where the platform has no downloads folder.
+/// Received files are the user's to open, so they are never kept in the data directory, where they would be sealed with everything else.
+pub fn download_dir() -> Res<PathBuf> {
+    let directory = dirs::download_dir().or_else(dirs::home_dir).ok_or(StorageError::NoDownloadDirectory)?.join(DOWNLOAD_DIRECTORY);
+
+    create_private_dir(&directory)?;
+    Ok(directory)
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(format!(".{PARTIAL_EXTENSION}"));
+    PathBuf::from(partial)
+}
+
+/// Reserve a path in the download directory that no other file, or transfer in progress, is using.
+/// The reservation is the partial file itself, which the contents are written to until verified.
+async fn reserve(header: &FileHeader) -> Res<(PathBuf, tokio::fs::File)> {
+    let directory = download_dir()?;
+    let name = header.safe_name();
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem.to_string(), format!(".{extension}")),
+        _ => (name.clone(), String::new())
+    };
+
+    let mut attempt = 0;
+    loop {
+        let path = match attempt {
+            0 => directory.join(&name),
+            n => directory.join(format!("{stem} ({n}){extension}"))
+        };
+        attempt += 1;
+
+        if path.exists() { continue; }
+        match OpenOptions::new().write(true).create_new(true).open(partial_path(&path)).await {
+            Ok(file) => return Ok((path, file)),
+            Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(error) => return Err(error.into())
+        }
+    }
+}
+
+/*
+    Resumption
+    Every file stream begins with the receiver replying to the frame with the offset it already holds, as a u64.
+    The sender streams the contents from that offset onwards. An incoming file that is interrupted is kept as a partial file,
+    and its packet persisted under its sender and message id, so that a later stream from the same peer for the same id picks up where it left off,
+    even across restarts. Only a file that fails its hash is discarded.
+*/
+
+/// Read the receiver's offset, then stream a file's contents from it into a QUIC stream in chunks, reporting progress as it goes.
+pub async fn send_file(stream: &mut SendStream, reply: &mut RecvStream, path: &Path, id: MessageId, progress: &Sender<TransferEvent>) -> Res<()> {
+    let mut offset = [0; 8];
+    match tokio::time::timeout(OFFSET_TIMEOUT, reply.read_exact(&mut offset)).await {
+        Ok(read_result) => read_result?,
+        Err(_) => return Err(ChannelError::ChannelDead.into())
+    };
+
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut sent = u64::from_be_bytes(offset).min(file.metadata().await?.len());
+    file.seek(SeekFrom::Start(sent)).await?;
+    send(TransferEvent::Progress(id, sent), progress).await?;
+
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 { break; }
+        stream.write_all(&buffer[..read]).await?;
+
+        sent += read as u64;
+        if sent % PROGRESS_INTERVAL < read as u64 {
+            send(TransferEvent::Progress(id, sent), progress).await?;
+        }
+    }
+
+    send(TransferEvent::Progress(id, sent), progress).await?;
+    Ok(())
+}
+
+/// Receive the contents of a file following its frame, writing them straight to the download directory.
+/// A partial file from an earlier attempt with the same id is resumed rather than started again.
+/// The code is only echoed back once the contents have been hashed and found to match the header.
+/// A file that was already received in full is acknowledged again without any of its contents being sent.
+pub async fn receive_file(mut stream: RecvStream, mut reply: SendStream, mut packet: Packet, peer: EndpointId, progress: Sender<TransferEvent>, window: ReceiveWindow) -> Res<()> {
+    let header = FileHeader::from_bytes(&packet.data)?;
+
+    if window.contains(peer, packet.id) {
+        reply.write_all(&header.size.to_be_bytes()).await?;
+        reply.write_all(&packet.code.to_be_bytes()).await?;
+        let _ = reply.finish();
+        return Ok(());
+    }
+
+    let (path, file, offset) = match resumable(peer, &packet, &header).await {
+        Some(resumed) => resumed,
+        None => {
+            let (path, file) = reserve(&header).await?;
+            (path, file, 0)
+        }
+    };
+
+    // Persist the transfer before anything is written, so that it can be resumed if interrupted.
+    let partial = partial_path(&path);
+    packet.path = Some(path.clone());
+    write_sealed(&partial_record(peer, packet.id)?, packet.clone().to_stored_bytes())?;
+    send(TransferEvent::Incoming(peer, packet.clone()), &progress).await?;
+
+    let written = match reply.write_all(&offset.to_be_bytes()).await {
+        Ok(()) => write_file(&mut stream, file, &partial, &header, offset, packet.id, &progress).await,
+        Err(error) => Err(error.into())
+    };
+
+    match written {
+        Ok(true) => {
+            rename(&partial, &path).await?;
+            remove_file(partial_record(peer, packet.id)?).await?;
+            window.admit(peer, packet.id);
+            reply.write_all(&packet.code.to_be_bytes()).await?;
+            let _ = reply.finish();
+            Ok(send(TransferEvent::Verified(peer, packet.id), &progress).await?)
+        }
+
+        // The contents do not match the header, so there is nothing worth resuming.
+        Ok(false) => {
+            remove_file(&partial).await?;
+            remove_file(partial_record(peer, packet.id)?).await?;
+            let _ = reply.finish();
+            Ok(send(TransferEvent::Failed(peer, packet.id), &progress).await?)
+        }
+
+        // The stream was interrupted. The partial file is kept for when the sender tries again.
+        Err(_) => Ok(send(TransferEvent::Failed(peer, packet.id), &progress).await?)
+    }
+}
+
+/// Find the partial file of an earlier attempt at the same transfer, yielding its path, the file opened for appending, and how much of it is held.
+async fn resumable(peer: EndpointId, packet: &Packet, header: &FileHeader) -> Option<(PathBuf, tokio::fs::File, u64)> {
+    let bytes = read(partial_record(peer, packet.id).ok()?).await.ok()?;
+    let stored = Packet::from_stored_bytes(vault::key().unseal(bytes).ok()?).ok()?;
+    if stored.file_header()?.hash != header.hash { return None; }
+
+    let path = stored.path?;
+    let file = OpenOptions::new().append(true).open(partial_path(&path)).await.ok()?;
+    let offset = file.metadata().await.ok()?.len().min(header.size);
+    Some((path, file, offset))
+}
+
+/// Where the packet of an incomplete incoming file is persisted, by its sender and message id.
+/// Message ids are chosen by the sender, so one peer cannot resume or overwrite a transfer from another.
+fn partial_record(peer: EndpointId, id: MessageId) -> Res<PathBuf> {
+    let directory = data_dir()?.join(TRANSFER_DIRECTORY);
+    create_private_dir(&directory)?;
+    Ok(directory.join(format!("{peer}-{id:032x}.{PARTIAL_EXTENSION}")))
+}
+
+/// Write the stream to the file after the given offset until it ends, yielding whether the contents as a whole match the header.
+/// A stream that ends before the file is complete is an interruption rather than a mismatch.
+async fn write_file(stream: &mut RecvStream, mut file: tokio::fs::File, partial: &Path, header: &FileHeader, offset: u64, id: MessageId, progress: &Sender<TransferEvent>) -> Res<bool> {
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut received: u64 = 0;
+
+    // The hash covers the whole file, so whatever was received by earlier attempts is hashed first.
+    let mut existing = tokio::fs::File::open(partial).await?.take(offset);
+    loop {
+        let read = existing.read(&mut buffer).await?;
+        if read == 0 { break; }
+        hasher.update(&buffer[..read]);
+        received += read as u64;
+    }
+
+    send(TransferEvent::Progress(id, received), progress).await?;
+
+    while let Some(read) = stream.read(&mut buffer).await? {
+        if received + read as u64 > header.size { return Ok(false); }
+        hasher.update(&buffer[..read]);
+        file.write_all(&buffer[..read]).await?;
+
+        received += read as u64;
+        if received % PROGRESS_INTERVAL < read as u64 {
+            send(TransferEvent::Progress(id, received), progress).await?;
+        }
+    }
+
+    file.sync_all().await?;
+    send(TransferEvent::Progress(id, received), progress).await?;
+
+    if received < header.size { return Err(NetworkError::TransferInterrupted.into()); }
+    Ok(*hasher.finalize().as_bytes() == header.hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> FileHeader {
+        FileHeader { name: String::from("photo.png"), size: 1024, mime: String::from("image/png"), hash: [5; 32] }
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = header();
+        let decoded = FileHeader::from_bytes(&header.to_bytes()).unwrap();
+        assert_eq!((decoded.name, decoded.size, decoded.mime, decoded.hash), (header.name, header.size, header.mime, header.hash));
+    }
+
+    #[test]
+    fn header_truncated() {
+        // The mime type runs to the end, so only truncation before it is detectable.
+        let header = header();
+        for length in 0..8 + 32 + 2 + header.name.len() {
+            assert!(FileHeader::from_bytes(&header.to_bytes()[..length]).is_err(), "accepted {length} bytes");
+        }
+    }
+
+    #[test]
+    fn safe_name() {
+        for (name, safe) in [("photo.png", "photo.png"), ("../../.bashrc", "download"), ("/etc/passwd", "passwd"), ("", "download"), ("..", "download")] {
+            assert_eq!(FileHeader { name: String::from(name), ..header() }.safe_name(), safe);
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use iroh::EndpointId;
+
+use crate::networking::packet::MessageId;
+use crate::util::sync;
+
+// How many of the most recent message ids are remembered for each peer.
+const WINDOW_SIZE: usize = 1024;
+
+/// The message ids most recently received from each peer, shared by every connection so that it outlives reconnections.
+/// A sender that retries a packet it already delivered, because the acknowledgement was lost or late, reuses its message id,
+/// so a repeat can be acknowledged again without being delivered twice.
+#[derive(Debug, Clone, Default)]
+pub struct ReceiveWindow {
+    peers: Arc<Mutex<HashMap<EndpointId, Window>>>
+}
+
+#[derive(Debug, Default)]
+struct Window {
+    seen: HashSet<MessageId>,
+    order: VecDeque<MessageId>      // Oldest first, so that the oldest is forgotten once the window is full.
+}
+
+impl ReceiveWindow {
+
+    /// Note a message id received from a peer, returning whether it had not been seen before.
+    pub fn admit(&self, peer: EndpointId, id: MessageId) -> bool {
+        let mut peers = self.lock();
+        let window = peers.entry(peer).or_default();
+        if !window.seen.insert(id) { return false; }
+
+        window.order.push_back(id);
+        if window.order.len() > WINDOW_SIZE && let Some(oldest) = window.order.pop_front() {
+            window.seen.remove(&oldest);
+        }
+
+        true
+    }
+
+    /// Whether a message id has been received from a peer recently.
+    pub fn contains(&self, peer: EndpointId, id: MessageId) -> bool {
+        self.lock().get(&peer).is_some_and(|window| window.seen.contains(&id))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<EndpointId, Window>> {
+        sync::lock(&self.peers)
+    }
+}
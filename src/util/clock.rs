@@ -0,0 +1,43 @@
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use time::{Date, Duration, OffsetDateTime, UtcOffset};
+
+// The local offset can only be read safely while the process has a single thread, so it is read once at startup.
+static LOCAL_OFFSET: OnceLock<UtcOffset> = OnceLock::new();
+
+/// Read the local offset from UTC. Must be called before any other threads are spawned, or times are shown in UTC.
+pub fn init() {
+    LOCAL_OFFSET.get_or_init(|| UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC));
+}
+
+/// The current time in milliseconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_millis() as u64).unwrap_or_default()
+}
+
+fn local(millis: u64) -> OffsetDateTime {
+    let utc = OffsetDateTime::UNIX_EPOCH + Duration::milliseconds(millis as i64);
+    utc.to_offset(LOCAL_OFFSET.get().copied().unwrap_or(UtcOffset::UTC))
+}
+
+/// The local calendar day a time falls on.
+pub fn day(millis: u64) -> Date {
+    local(millis).date()
+}
+
+/// The local time of day, as hours and minutes.
+pub fn time_of_day(millis: u64) -> String {
+    let time = local(millis);
+    format!("{:02}:{:02}", time.hour(), time.minute())
+}
+
+/// A heading for a local calendar day, relative to today where it is recent.
+pub fn day_label(date: Date) -> String {
+    let today = day(now());
+    match today - date {
+        elapsed if elapsed.is_zero() => String::from("Today"),
+        elapsed if elapsed == Duration::days(1) => String::from("Yesterday"),
+        _ => format!("{} {} {} {}", date.weekday(), date.day(), date.month(), date.year())
+    }
+}
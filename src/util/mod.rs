@@ -1,2 +1,6 @@
 pub mod channel;
+pub mod clock;
 pub mod relay;
+pub mod storage;
+pub mod sync;
+pub mod vault;
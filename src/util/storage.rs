@@ -0,0 +1,71 @@
+use std::fs::{DirBuilder, File, OpenOptions, read, rename};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Res, StorageError};
+use crate::util::vault;
+
+const APPLICATION_DIRECTORY: &str = "rift";
+const TEMPORARY_EXTENSION: &str = "tmp";
+
+/// Resolve (and create, if required) the per-user directory in which rift keeps persistent state.
+pub fn data_dir() -> Res<PathBuf> {
+    let path = dirs::data_dir().ok_or(StorageError::NoDataDirectory)?.join(APPLICATION_DIRECTORY);
+    create_private_dir(&path)?;
+    Ok(path)
+}
+
+/// Create a directory (and its parents) that only the current user may access.
+pub fn create_private_dir(path: &Path) -> Res<()> {
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+
+    Ok(builder.create(path)?)
+}
+
+/// Overwrite a file with the given bytes, ensuring it is only readable and writable by the current user.
+/// The bytes are written to a temporary file beside it, which then replaces it, so that an interrupted write leaves the file as it was.
+pub fn write_private(path: &Path, bytes: &[u8]) -> Res<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(".{TEMPORARY_EXTENSION}"));
+    let temporary = PathBuf::from(temporary);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(&temporary)?;
+
+    // The mode above only applies on creation, so tighten permissions of a temporary file left behind by an interrupted write too.
+    #[cfg(unix)]
+    std::fs::set_permissions(&temporary, std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    rename(&temporary, path)?;
+
+    // The replacement only survives a crash once the directory holding it is synced.
+    #[cfg(unix)]
+    if let Some(directory) = path.parent().filter(|directory| !directory.as_os_str().is_empty()) {
+        File::open(directory)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Overwrite a file with the given bytes, sealed under the passphrase if one is set.
+pub fn write_sealed(path: &Path, bytes: Vec<u8>) -> Res<()> {
+    let key = vault::key();
+    write_private(path, &key.seal(bytes)?)
+}
+
+/// Read a file written by write_sealed.
+pub fn read_sealed(path: &Path) -> Res<Vec<u8>> {
+    let key = vault::key();
+    key.unseal(read(path)?)
+}
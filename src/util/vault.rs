@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::fs::{read, read_dir, remove_file};
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use rand::{Rng, rng};
+use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use ring::pbkdf2::{PBKDF2_HMAC_SHA256, derive};
+use zeroize::Zeroizing;
+
+use crate::backend::history::{FRAMED_EXTENSIONS, frame, frames};
+use crate::error::{Res, StorageError};
+use crate::util::storage::{data_dir, write_private};
+use crate::util::sync;
+
+const VAULT_FILE: &str = "vault";
+const VERSION: u8 = 1;
+
+// Prefixes everything sealed, so that whatever was stored before a passphrase was set is still read as it is.
+const MAGIC: &[u8; 8] = b"RIFTSEAL";
+
+// Deriving the key takes around a second at this count, so it is never done on the UI thread.
+const ITERATIONS: u32 = 600_000;
+const SALT_LENGTH: usize = 16;
+const KEY_LENGTH: usize = 32;
+
+// Sealed into the vault file, so that an incorrect passphrase is caught before anything is decrypted with it.
+const CHECK: &[u8] = b"rift vault check";
+
+static STATE: RwLock<State> = RwLock::new(State::Disabled);
+
+/// The key everything in the data directory is sealed with, held only in memory between entering the passphrase and locking.
+/// Files and history records are sealed with ChaCha20-Poly1305 under a key derived from the passphrase by PBKDF2-HMAC-SHA256.
+/// Files received from peers, and their partial downloads, are saved outside the data directory (see transfer::download_dir)
+/// and are left as they are. Images sent inline and the records of incomplete transfers are sealed like the rest.
+/// The key is cleared from memory when locked. A cipher is made from it for each seal or open, which ring does not clear,
+/// so that no copy outlives the operation that needed it.
+type Key = Zeroizing<[u8; KEY_LENGTH]>;
+
+enum State {
+    Disabled,               // No passphrase is set, and everything is stored unencrypted.
+    Locked,                 // A passphrase is set but has not been entered, so nothing stored can be read or written.
+    Unlocked(Key)
+}
+
+/// Each file in the data directory as it was read, and as it was resealed.
+type Resealed = HashMap<PathBuf, (Vec<u8>, Vec<u8>)>;
+
+/// Note whether a passphrase is set. Must be called before anything is read from storage.
+pub fn init() {
+    if data_dir().is_ok_and(|directory| directory.join(VAULT_FILE).exists()) {
+        *write_state() = State::Locked;
+    }
+}
+
+pub fn is_enabled() -> bool {
+    !matches!(*read_state(), State::Disabled)
+}
+
+pub fn is_locked() -> bool {
+    matches!(*read_state(), State::Locked)
+}
+
+/// Derive the key from the passphrase, failing if it does not open the vault file.
+/// Unlocking again once unlocked only checks the passphrase.
+pub fn unlock(passphrase: &str) -> Res<()> {
+    let key = VaultFile::load()?.open(passphrase)?;
+    *write_state() = State::Unlocked(key);
+    Ok(())
+}
+
+/// Clear the key from memory, after which nothing stored can be read or written until unlocked again.
+pub fn lock() {
+    let mut state = write_state();
+    if let State::Unlocked(_) = *state {
+        *state = State::Locked;
+    }
+}
+
+/// Set, change or remove the passphrase, resealing everything stored under the new key.
+/// The current passphrase is required whenever one is set, so that an unattended window cannot be used to remove it.
+pub fn change(current: Option<&str>, new: Option<&str>) -> Res<()> {
+    let path = data_dir()?.join(VAULT_FILE);
+    let old = match path.exists() {
+        true => Some(VaultFile::load()?.open(current.unwrap_or_default())?),
+        false => None
+    };
+    let new = new.map(VaultFile::create).transpose()?;
+    let new_key = new.as_ref().map(|(_, key)| key);
+
+    // Everything is resealed in memory first, so that an unreadable file leaves the data directory untouched.
+    // Readers and writers carry on meanwhile. Writers then wait on the key while whatever they wrote in the meantime is resealed
+    // again, which is all that is done under the lock, so that nothing is left sealed under the old key.
+    let resealed = reseal_all(old.as_ref(), new_key, &Resealed::new())?;
+    let mut state = write_state();
+    let resealed = reseal_all(old.as_ref(), new_key, &resealed)?;
+
+    // The vault file is written before the files it opens and removed after them, so that an interrupted change
+    // leaves every file either readable as it is or sealed under the key of the vault file.
+    match new {
+        Some((vault, key)) => {
+            vault.save()?;
+            *state = State::Unlocked(key);
+            resealed.into_iter().try_for_each(|(path, (_, bytes))| write_private(&path, &bytes))
+        }
+        None => {
+            resealed.into_iter().try_for_each(|(path, (_, bytes))| write_private(&path, &bytes))?;
+            remove_file(path)?;
+            *state = State::Disabled;
+            Ok(())
+        }
+    }
+}
+
+/// The current key, which cannot change until this is dropped.
+/// Anything sealed should be written before it is dropped, so that it is never written under a key that has since been replaced.
+pub struct CurrentKey(RwLockReadGuard<'static, State>);
+
+pub fn key() -> CurrentKey {
+    CurrentKey(read_state())
+}
+
+impl CurrentKey {
+
+    /// Seal bytes for storage. Bytes are stored as they are while no passphrase is set.
+    pub fn seal(&self, plaintext: Vec<u8>) -> Res<Vec<u8>> {
+        match &*self.0 {
+            State::Disabled => Ok(plaintext),
+            State::Locked => Err(StorageError::Locked.into()),
+            State::Unlocked(key) => seal(&cipher(key)?, plaintext)
+        }
+    }
+
+    /// Open sealed bytes. Bytes that were stored before a passphrase was set are yielded as they are.
+    pub fn unseal(&self, bytes: Vec<u8>) -> Res<Vec<u8>> {
+        if !bytes.starts_with(MAGIC) { return Ok(bytes); }
+        match &*self.0 {
+            State::Unlocked(key) => unseal(&cipher(key)?, bytes),
+            _ => Err(StorageError::Locked.into())
+        }
+    }
+}
+
+/*
+    Encoding
+    Sealed bytes are [magic: 8 bytes][nonce: 12 bytes][ciphertext][tag: 16 bytes], with a random nonce for every seal.
+*/
+
+fn seal(key: &LessSafeKey, mut plaintext: Vec<u8>) -> Res<Vec<u8>> {
+    let mut nonce = [0; NONCE_LEN];
+    rng().fill(&mut nonce);
+
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut plaintext).map_err(|_| StorageError::EncryptionFailed)?;
+    Ok([MAGIC.as_slice(), &nonce, &plaintext].concat())
+}
+
+fn unseal(key: &LessSafeKey, bytes: Vec<u8>) -> Res<Vec<u8>> {
+    let (nonce, ciphertext) = bytes[MAGIC.len()..].split_first_chunk::<NONCE_LEN>().ok_or(StorageError::Undecryptable)?;
+    let mut ciphertext = ciphertext.to_vec();
+
+    let length = key.open_in_place(Nonce::assume_unique_for_key(*nonce), Aad::empty(), &mut ciphertext).map_err(|_| StorageError::Undecryptable)?.len();
+    ciphertext.truncate(length);
+    Ok(ciphertext)
+}
+
+/// Move bytes from one cipher to another, where no cipher means they are stored unencrypted.
+fn reseal(old: Option<&LessSafeKey>, new: Option<&LessSafeKey>, bytes: Vec<u8>) -> Res<Vec<u8>> {
+    let plaintext = match old {
+        Some(key) if bytes.starts_with(MAGIC) => unseal(key, bytes)?,
+        _ => bytes
+    };
+
+    match new {
+        Some(key) => seal(key, plaintext),
+        None => Ok(plaintext)
+    }
+}
+
+/// Every file in the data directory resealed under the new key, by the path it is to be written back to.
+/// A file that has not changed since it was last resealed is not resealed again.
+/// History logs are sealed record by record, so that records can still be appended without rewriting the log.
+fn reseal_all(old: Option<&Key>, new: Option<&Key>, previous: &Resealed) -> Res<Resealed> {
+    let old = old.map(cipher).transpose()?;
+    let new = new.map(cipher).transpose()?;
+    let (old, new) = (old.as_ref(), new.as_ref());
+
+    let mut resealed = Resealed::new();
+    let mut directories = vec![data_dir()?];
+
+    while let Some(directory) = directories.pop() {
+        for entry in read_dir(&directory)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+
+            if path.is_dir() {
+                directories.push(path);
+                continue;
+            }
+            if name == VAULT_FILE { continue; }
+
+            let bytes = read(&path)?;
+            if let Some((original, sealed)) = previous.get(&path).filter(|(original, _)| *original == bytes) {
+                resealed.insert(path, (original.clone(), sealed.clone()));
+                continue;
+            }
+
+            let sealed = match path.extension().and_then(|extension| extension.to_str()).is_some_and(|extension| FRAMED_EXTENSIONS.contains(&extension)) {
+                true => frames(&bytes).into_iter()
+                    .map(|record| reseal(old, new, record.to_vec()).map(|record| frame(&record)))
+                    .collect::<Res<Vec<Vec<u8>>>>()?
+                    .concat(),
+                false => reseal(old, new, bytes.clone())?
+            };
+            resealed.insert(path, (bytes, sealed));
+        }
+    }
+
+    Ok(resealed)
+}
+
+/// The parameters a key is derived from a passphrase with, and a known plaintext sealed under that key.
+struct VaultFile {
+    iterations: u32,
+    salt: [u8; SALT_LENGTH],
+    check: Vec<u8>
+}
+
+impl VaultFile {
+
+    /// Derive a key from a passphrase under a fresh salt.
+    fn create(passphrase: &str) -> Res<(VaultFile, Key)> {
+        let mut salt = [0; SALT_LENGTH];
+        rng().fill(&mut salt);
+
+        let key = derive_key(passphrase, ITERATIONS, &salt)?;
+        let check = seal(&cipher(&key)?, CHECK.to_vec())?;
+        Ok((VaultFile { iterations: ITERATIONS, salt, check }, key))
+    }
+
+    /// Derive the key from a passphrase, failing if it is not the passphrase the vault was created with.
+    fn open(&self, passphrase: &str) -> Res<Key> {
+        let key = derive_key(passphrase, self.iterations, &self.salt)?;
+        match unseal(&cipher(&key)?, self.check.clone()) {
+            Ok(check) if check == CHECK => Ok(key),
+            _ => Err(StorageError::IncorrectPassphrase.into())
+        }
+    }
+
+    fn load() -> Res<VaultFile> {
+        Self::from_bytes(&read(data_dir()?.join(VAULT_FILE))?)
+    }
+
+    fn save(&self) -> Res<()> {
+        write_private(&data_dir()?.join(VAULT_FILE), &self.to_bytes())
+    }
+
+    /*
+        Encoding
+        [version: u8][iterations: u32][salt: 16 bytes][check: sealed bytes]
+    */
+
+    fn to_bytes(&self) -> Vec<u8> {
+        [vec![VERSION], self.iterations.to_be_bytes().to_vec(), self.salt.to_vec(), self.check.clone()].concat()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Res<VaultFile> {
+        match bytes.split_first() {
+            Some((&VERSION, rest)) => {
+                let (iterations, rest) = rest.split_first_chunk::<4>().ok_or(StorageError::CorruptVault)?;
+                let (salt, check) = rest.split_first_chunk::<SALT_LENGTH>().ok_or(StorageError::CorruptVault)?;
+                if !check.starts_with(MAGIC) { return Err(StorageError::CorruptVault.into()); }
+                Ok(VaultFile { iterations: u32::from_be_bytes(*iterations), salt: *salt, check: check.to_vec() })
+            }
+            _ => Err(StorageError::CorruptVault.into())
+        }
+    }
+}
+
+fn derive_key(passphrase: &str, iterations: u32, salt: &[u8]) -> Res<Key> {
+    let iterations = NonZeroU32::new(iterations).ok_or(StorageError::CorruptVault)?;
+    let mut key = Zeroizing::new([0; KEY_LENGTH]);
+    derive(PBKDF2_HMAC_SHA256, iterations, salt, passphrase.as_bytes(), key.as_mut_slice());
+    Ok(key)
+}
+
+fn cipher(key: &Key) -> Res<LessSafeKey> {
+    Ok(LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key.as_slice()).map_err(|_| StorageError::EncryptionFailed)?))
+}
+
+fn read_state() -> RwLockReadGuard<'static, State> {
+    sync::read(&STATE)
+}
+
+fn write_state() -> RwLockWriteGuard<'static, State> {
+    sync::write(&STATE)
+}